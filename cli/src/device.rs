@@ -0,0 +1,103 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use crate::protocol::PACKET_SIZE;
+
+pub const VENDOR_ID: u16 = 0x0A55;
+pub const PRODUCT_ID: u16 = 0x0A44;
+// Usage page item of descriptor::BufferReport, which is 0xFF69
+const BUFFER_REPORT_USAGE_PAGE: [u8; 3] = [0x06, 0x69, 0xFF];
+
+/// Sends one request packet and returns the response packet
+pub trait Transport {
+    fn transfer(&mut self, request: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn transfer(&mut self, request: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]> {
+        (**self).transfer(request)
+    }
+}
+
+/// Linux hidraw node of the BufferReport interface
+pub struct Hidraw {
+    file: File,
+}
+
+impl Hidraw {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Finds the hidraw node of the keyboard's BufferReport interface
+    pub fn find() -> io::Result<PathBuf> {
+        for entry in fs::read_dir("/sys/class/hidraw")? {
+            let entry = entry?;
+            let device = entry.path().join("device");
+            let uevent = fs::read_to_string(device.join("uevent")).unwrap_or_default();
+            let id = format!(":{:08X}:{:08X}", VENDOR_ID, PRODUCT_ID);
+            if !uevent
+                .lines()
+                .any(|line| line.starts_with("HID_ID=") && line.ends_with(&id))
+            {
+                continue;
+            }
+            let descriptor = fs::read(device.join("report_descriptor")).unwrap_or_default();
+            if descriptor
+                .windows(BUFFER_REPORT_USAGE_PAGE.len())
+                .any(|window| window == BUFFER_REPORT_USAGE_PAGE)
+            {
+                return Ok(Path::new("/dev").join(entry.file_name()));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no keyboard found, pass the hidraw node with --device",
+        ))
+    }
+}
+
+impl Transport for Hidraw {
+    fn transfer(&mut self, request: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]> {
+        // BufferReport has no report id, which hidraw expects as a leading 0
+        let mut report = [0u8; PACKET_SIZE + 1];
+        report[1..].copy_from_slice(request);
+        self.file.write_all(&report)?;
+        let mut response = [0u8; PACKET_SIZE];
+        let len = self.file.read(&mut response)?;
+        if len != PACKET_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short report of {} bytes", len),
+            ));
+        }
+        Ok(response)
+    }
+}
+
+/// Connection to a fake device served over a unix socket
+pub struct Socket {
+    stream: UnixStream,
+}
+
+impl Socket {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+}
+
+impl Transport for Socket {
+    fn transfer(&mut self, request: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]> {
+        self.stream.write_all(request)?;
+        let mut response = [0u8; PACKET_SIZE];
+        self.stream.read_exact(&mut response)?;
+        Ok(response)
+    }
+}
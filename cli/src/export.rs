@@ -0,0 +1,256 @@
+//! Exports a keymap to JSON and to keyboard-layout-editor (KLE) format for
+//! rendering keymap diagrams. Only keys with a physical position are exported,
+//! so an export fails if any other key has an action. Layers where every key
+//! is None are skipped.
+
+use std::fmt::Write;
+
+use crate::{
+    keycodes,
+    keymap::KeymapLayer,
+    layout::{KEYBOARD_NAME, KEY_POSITIONS},
+    protocol::KeyAction,
+};
+
+/// Escapes a string for use in JSON
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Short label of a code for keycap legends, such as A for KeyboardAa
+pub fn label(code: u8) -> String {
+    if code == 0 {
+        return String::new();
+    }
+    let name = keycodes::name(code);
+    let short = name.strip_prefix("Keyboard").unwrap_or(&name);
+    // Letter names repeat the letter in both cases
+    let mut chars = short.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(upper), Some(lower), None) if upper.to_ascii_lowercase() == lower => {
+            upper.to_string()
+        }
+        _ => short.to_string(),
+    }
+}
+
+/// Keycap legends of an action. The second legend is shown under the first
+fn legends(action: &KeyAction) -> (String, String) {
+    match *action {
+        KeyAction::Code(code) => (label(code), String::new()),
+        KeyAction::Toggle(code) => (label(code), "toggle".to_string()),
+        KeyAction::Double(code0, code1) => {
+            (format!("{}+{}", label(code1), label(code0)), String::new())
+        }
+        KeyAction::Triple(code0, code1, code2) => (
+            format!("{}+{}+{}", label(code1), label(code2), label(code0)),
+            String::new(),
+        ),
+        KeyAction::Combined {
+            other_index,
+            normal_code,
+            combined_code,
+        } => (
+            label(normal_code),
+            format!("{} with {}", label(combined_code), other_index),
+        ),
+        KeyAction::Interval { code, delay } => (label(code), format!("{} ms", delay)),
+        KeyAction::Config(id) => (format!("Layout {}", id), String::new()),
+    }
+}
+
+fn action_json(action: &KeyAction) -> String {
+    let name = |code: u8| quote(&keycodes::name(code));
+    match *action {
+        KeyAction::Code(code) => format!("{{\"type\": \"code\", \"code\": {}}}", name(code)),
+        KeyAction::Toggle(code) => format!("{{\"type\": \"toggle\", \"code\": {}}}", name(code)),
+        KeyAction::Double(code0, code1) => format!(
+            "{{\"type\": \"double\", \"codes\": [{}, {}]}}",
+            name(code0),
+            name(code1)
+        ),
+        KeyAction::Triple(code0, code1, code2) => format!(
+            "{{\"type\": \"triple\", \"codes\": [{}, {}, {}]}}",
+            name(code0),
+            name(code1),
+            name(code2)
+        ),
+        KeyAction::Combined {
+            other_index,
+            normal_code,
+            combined_code,
+        } => format!(
+            "{{\"type\": \"combined\", \"other_index\": {}, \"normal_code\": {}, \"combined_code\": {}}}",
+            other_index,
+            name(normal_code),
+            name(combined_code)
+        ),
+        KeyAction::Interval { code, delay } => format!(
+            "{{\"type\": \"interval\", \"code\": {}, \"delay_ms\": {}}}",
+            name(code),
+            delay
+        ),
+        KeyAction::Config(id) => format!("{{\"type\": \"config\", \"layout\": {}}}", id),
+    }
+}
+
+/// Fails if a key without a physical position has an action, which the export would drop
+fn check_positions(layer: &KeymapLayer) -> Result<(), String> {
+    let unplaced = layer
+        .actions
+        .iter()
+        .enumerate()
+        .skip(KEY_POSITIONS.len())
+        .find(|(_, action)| **action != KeyAction::Code(0));
+    match unplaced {
+        Some((index, _)) => Err(format!(
+            "layer {} key {} has an action but the {} has no key {}",
+            layer.layer, index, KEYBOARD_NAME, index
+        )),
+        None => Ok(()),
+    }
+}
+
+fn is_empty(layer: &KeymapLayer) -> bool {
+    layer
+        .actions
+        .iter()
+        .all(|action| *action == KeyAction::Code(0))
+}
+
+/// Exports every layer with the key positions and actions as JSON
+pub fn json(layers: &[KeymapLayer]) -> Result<String, String> {
+    for layer in layers {
+        check_positions(layer)?;
+    }
+    let mut text = String::new();
+    writeln!(text, "{{").unwrap();
+    writeln!(text, "  \"keyboard\": {},", quote(KEYBOARD_NAME)).unwrap();
+    writeln!(text, "  \"keys\": [").unwrap();
+    for (index, position) in KEY_POSITIONS.iter().enumerate() {
+        let comma = if index + 1 < KEY_POSITIONS.len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(
+            text,
+            "    {{\"index\": {}, \"x\": {:.2}, \"y\": {:.2}, \"rotation\": {}}}{}",
+            index, position.x, position.y, position.rotation, comma
+        )
+        .unwrap();
+    }
+    writeln!(text, "  ],").unwrap();
+    writeln!(text, "  \"layers\": [").unwrap();
+    let layers: Vec<_> = layers.iter().filter(|layer| !is_empty(layer)).collect();
+    for (i, layer) in layers.iter().enumerate() {
+        writeln!(text, "    {{").unwrap();
+        writeln!(text, "      \"layer\": {},", layer.layer).unwrap();
+        writeln!(text, "      \"actions\": [").unwrap();
+        let count = layer.actions.len().min(KEY_POSITIONS.len());
+        for (index, action) in layer.actions.iter().take(count).enumerate() {
+            let (legend, sublegend) = legends(action);
+            let comma = if index + 1 < count { "," } else { "" };
+            writeln!(
+                text,
+                "        {{\"index\": {}, \"label\": {}, \"sublabel\": {}, \"action\": {}}}{}",
+                index,
+                quote(&legend),
+                quote(&sublegend),
+                action_json(action),
+                comma
+            )
+            .unwrap();
+        }
+        writeln!(text, "      ]").unwrap();
+        let comma = if i + 1 < layers.len() { "," } else { "" };
+        writeln!(text, "    }}{}", comma).unwrap();
+    }
+    writeln!(text, "  ]").unwrap();
+    writeln!(text, "}}").unwrap();
+    Ok(text)
+}
+
+/// Exports a layer in KLE raw data format, which can be pasted into the editor.
+/// Every key gets its own row and is placed through its rotation center so the
+/// rotated thumb keys don't shift the keys after them
+pub fn kle(layer: &KeymapLayer) -> Result<String, String> {
+    check_positions(layer)?;
+    let mut text = String::new();
+    writeln!(text, "[").unwrap();
+    writeln!(
+        text,
+        "  {{\"name\": {}}},",
+        quote(&format!("{} layer {}", KEYBOARD_NAME, layer.layer))
+    )
+    .unwrap();
+    let count = layer.actions.len().min(KEY_POSITIONS.len());
+    for (index, action) in layer.actions.iter().take(count).enumerate() {
+        let position = KEY_POSITIONS[index];
+        let (legend, sublegend) = legends(action);
+        let legend = if sublegend.is_empty() {
+            legend
+        } else {
+            format!("{}\n{}", legend, sublegend)
+        };
+        let comma = if index + 1 < count { "," } else { "" };
+        writeln!(
+            text,
+            "  [{{\"r\": {}, \"rx\": {:.2}, \"ry\": {:.2}, \"x\": -0.5, \"y\": -0.5}}, {}]{}",
+            position.rotation,
+            position.x,
+            position.y,
+            quote(&legend),
+            comma
+        )
+        .unwrap();
+    }
+    writeln!(text, "]").unwrap();
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Layer of the firmware's 39 keys, where the keys past the physical ones are None
+    fn layer() -> KeymapLayer {
+        let mut actions = vec![KeyAction::Code(0); 39];
+        actions[0] = KeyAction::Code(0x14);
+        actions[35] = KeyAction::Toggle(0xE1);
+        KeymapLayer { layer: 0, actions }
+    }
+
+    #[test]
+    fn keys_without_a_position_are_skipped_when_none() {
+        let json = json(&[layer()]).unwrap();
+        assert_eq!(json.matches("\"label\"").count(), KEY_POSITIONS.len());
+        assert!(json.contains("\"index\": 35, \"label\""));
+        assert!(!json.contains("\"index\": 36"));
+
+        let kle = kle(&layer()).unwrap();
+        assert!(kle.contains("\"Q\""));
+        assert!(!kle.contains("\"36\""));
+    }
+
+    #[test]
+    fn keys_without_a_position_fail_when_set() {
+        let mut layer = layer();
+        layer.actions[37] = KeyAction::Code(0x04);
+        assert!(json(std::slice::from_ref(&layer))
+            .unwrap_err()
+            .contains("key 37"));
+        assert!(kle(&layer).unwrap_err().contains("key 37"));
+    }
+}
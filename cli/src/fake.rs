@@ -0,0 +1,189 @@
+//! In memory keyboard that answers the command protocol the same way the
+//! firmware's CommandHandler does. Used to try the CLI without a board.
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixListener,
+    path::Path,
+};
+
+use crate::{
+    device::Transport,
+    protocol::{CommandId, KeyAction, Status, ACTION_SIZE, BONDS_PER_PACKET, PACKET_SIZE},
+};
+
+pub const FAKE_KEYS: usize = 39;
+pub const FAKE_LAYERS: usize = 10;
+// Number of config::layouts in the firmware
+const FAKE_LAYOUTS: u8 = 2;
+const FAKE_BATTERY: u8 = 87;
+// Count, average, max and last latency in microseconds
+const FAKE_LATENCY: [u32; 4] = [1000, 850, 2400, 900];
+
+pub struct FakeDevice {
+    keymap: [[KeyAction; FAKE_KEYS]; FAKE_LAYERS],
+    bonds: Vec<(u8, u8, [u8; 6])>,
+    // Values of the settings, indexed by setting id
+    settings: [u16; 3],
+    layer: u8,
+}
+
+impl Default for FakeDevice {
+    fn default() -> Self {
+        let mut keymap = [[KeyAction::Code(0); FAKE_KEYS]; FAKE_LAYERS];
+        // Letters a to z on the first keys of the base layer
+        for (i, action) in keymap[0].iter_mut().take(26).enumerate() {
+            *action = KeyAction::Code(0x04 + i as u8);
+        }
+        Self {
+            keymap,
+            bonds: vec![
+                (0, 0x01, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]),
+                (1, 0x01, [0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0xF6]),
+            ],
+            settings: [1, 5, 0],
+            layer: 0,
+        }
+    }
+}
+
+impl FakeDevice {
+    /// Processes the request and returns the response
+    pub fn process(&mut self, request: &[u8; PACKET_SIZE]) -> [u8; PACKET_SIZE] {
+        let mut response = [0u8; PACKET_SIZE];
+        response[0] = request[0];
+        let mut data = Vec::new();
+        let status = match CommandId::from_raw(request[0]) {
+            Some(id) => self.run(id, &request[1..], &mut data),
+            None => Status::UnknownCommand,
+        };
+        response[1] = status as u8;
+        response[2..2 + data.len()].copy_from_slice(&data);
+        response
+    }
+
+    fn run(&mut self, id: CommandId, args: &[u8], data: &mut Vec<u8>) -> Status {
+        match id {
+            CommandId::Version => {
+                data.extend_from_slice(&[1, FAKE_KEYS as u8, FAKE_LAYERS as u8]);
+                Status::Ok
+            }
+            CommandId::GetKey => {
+                if !Self::is_key(args[0], args[1]) {
+                    return Status::InvalidArgument;
+                }
+                let action = self.keymap[args[0] as usize][args[1] as usize];
+                data.extend_from_slice(&args[..2]);
+                data.extend_from_slice(&action.to_bytes());
+                Status::Ok
+            }
+            CommandId::SetKey => {
+                if !Self::is_key(args[0], args[1]) {
+                    return Status::InvalidArgument;
+                }
+                match KeyAction::from_bytes(&args[2..2 + ACTION_SIZE]) {
+                    Some(action) if Self::is_valid(&action) => {
+                        self.keymap[args[0] as usize][args[1] as usize] = action;
+                        data.extend_from_slice(&args[..2]);
+                        data.extend_from_slice(&action.to_bytes());
+                        Status::Ok
+                    }
+                    _ => Status::InvalidArgument,
+                }
+            }
+            CommandId::GetLayer => {
+                data.push(self.layer);
+                Status::Ok
+            }
+            CommandId::GetBattery => {
+                data.push(FAKE_BATTERY);
+                Status::Ok
+            }
+            CommandId::GetBonds => {
+                data.push(self.bonds.len() as u8);
+                let start = args[0] as usize;
+                for (index, flags, addr) in self.bonds.iter().skip(start).take(BONDS_PER_PACKET) {
+                    data.push(*index);
+                    data.push(*flags);
+                    data.extend_from_slice(addr);
+                }
+                Status::Ok
+            }
+            CommandId::FactoryReset => {
+                *self = Self {
+                    bonds: Vec::new(),
+                    ..Self::default()
+                };
+                Status::Ok
+            }
+            CommandId::GetSetting => match self.settings.get(args[0] as usize) {
+                Some(value) => {
+                    data.push(args[0]);
+                    data.extend_from_slice(&value.to_le_bytes());
+                    Status::Ok
+                }
+                None => Status::InvalidArgument,
+            },
+            CommandId::SetSetting => {
+                let value = u16::from_le_bytes([args[1], args[2]]);
+                // Largest value of each setting, as checked by the firmware
+                let max = match args[0] {
+                    0 => 1,
+                    1 => 10_000,
+                    2 => 100,
+                    _ => return Status::InvalidArgument,
+                };
+                if value > max {
+                    return Status::InvalidArgument;
+                }
+                self.settings[args[0] as usize] = value;
+                data.extend_from_slice(&args[..3]);
+                Status::Ok
+            }
+            CommandId::GetLatency => {
+                for value in FAKE_LATENCY {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                Status::Ok
+            }
+            // The fake has no trackpad
+            CommandId::GetTrackPad | CommandId::SetTrackPad | CommandId::RecalibrateTrackPad => {
+                Status::Unsupported
+            }
+        }
+    }
+
+    fn is_key(layer: u8, index: u8) -> bool {
+        (layer as usize) < FAKE_LAYERS && (index as usize) < FAKE_KEYS
+    }
+
+    fn is_valid(action: &KeyAction) -> bool {
+        match *action {
+            KeyAction::Combined { other_index, .. } => (other_index as usize) < FAKE_KEYS,
+            KeyAction::Config(id) => id < FAKE_LAYOUTS,
+            _ => true,
+        }
+    }
+
+    /// Serves the device on a unix socket until the process is killed. The
+    /// state is kept between connections
+    pub fn serve(mut self, path: &Path) -> io::Result<()> {
+        let listener = UnixListener::bind(path)?;
+        println!("Fake device listening on {}", path.display());
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            let mut request = [0u8; PACKET_SIZE];
+            while stream.read_exact(&mut request).is_ok() {
+                let response = self.process(&request);
+                stream.write_all(&response)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for FakeDevice {
+    fn transfer(&mut self, request: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]> {
+        Ok(self.process(request))
+    }
+}
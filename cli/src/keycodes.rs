@@ -0,0 +1,54 @@
+//! KeyCodes names from the bruh78-codes crate, so the CLI uses the same
+//! names as the keymaps in the firmware's config.rs.
+
+use bruh78_codes::KeyCodes;
+
+// Code 0 isn't part of KeyCodes and means no key
+pub const NO_CODE: &str = "None";
+
+/// Returns the KeyCodes name of the raw code, or the code in hex if it has no name
+pub fn name(code: u8) -> String {
+    if code == 0 {
+        return NO_CODE.to_string();
+    }
+    match KeyCodes::from_raw(code) {
+        Some(code) => code.name().to_string(),
+        None => format!("0x{:02X}", code),
+    }
+}
+
+/// Parses a KeyCodes name or a number. The Keyboard prefix of the names is optional
+pub fn parse(token: &str) -> Option<u8> {
+    if token == NO_CODE {
+        return Some(0);
+    }
+    if let Some(hex) = token.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(code) = token.parse() {
+        return Some(code);
+    }
+    KeyCodes::from_name(token)
+        .or_else(|| KeyCodes::from_name(&format!("Keyboard{}", token)))
+        .map(|code| code as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(name(0x04), "KeyboardAa");
+        assert_eq!(parse("KeyboardAa"), Some(0x04));
+        assert_eq!(parse("Aa"), Some(0x04));
+        assert_eq!(parse(&name(KeyCodes::TrackpadScroll as u8)), Some(0xFF));
+    }
+
+    #[test]
+    fn codes_without_a_name_are_hex() {
+        assert_eq!(name(0xA5), "0xA5");
+        assert_eq!(parse("0xA5"), Some(0xA5));
+        assert_eq!(parse(NO_CODE), Some(0));
+    }
+}
@@ -0,0 +1,159 @@
+//! Text keymap format. A `layer <n>` line starts a layer and is followed by one
+//! action per key in key index order, separated by whitespace and over any number
+//! of lines. Everything after a `#` is a comment. Actions are written as:
+//!
+//! - `KeyboardAa`, `Aa`, `0x04` or `None`: a single code
+//! - `toggle(Layer1)`: a layer key that stays after release
+//! - `double(Aa,KeyboardLeftShift)` and `triple(Aa,KeyboardLeftShift,KeyboardLeftAlt)`
+//! - `combined(3,Aa,Bb)`: sends Bb instead of Aa while key 3 is held
+//! - `interval(MousePositiveX,20)`: repeats the code starting at a 20 ms delay
+//! - `config(0)`: loads config::layouts()[0]
+
+use std::fmt::Write;
+
+use crate::{keycodes, protocol::KeyAction};
+
+// Keys per line when formatting, which is one row of a half
+const KEYS_PER_LINE: usize = 5;
+
+pub struct KeymapLayer {
+    pub layer: u8,
+    pub actions: Vec<KeyAction>,
+}
+
+/// Formats the action as a keymap token
+pub fn format_action(action: &KeyAction) -> String {
+    match *action {
+        KeyAction::Code(code) => keycodes::name(code),
+        KeyAction::Toggle(code) => format!("toggle({})", keycodes::name(code)),
+        KeyAction::Double(code0, code1) => format!(
+            "double({},{})",
+            keycodes::name(code0),
+            keycodes::name(code1)
+        ),
+        KeyAction::Triple(code0, code1, code2) => format!(
+            "triple({},{},{})",
+            keycodes::name(code0),
+            keycodes::name(code1),
+            keycodes::name(code2)
+        ),
+        KeyAction::Combined {
+            other_index,
+            normal_code,
+            combined_code,
+        } => format!(
+            "combined({},{},{})",
+            other_index,
+            keycodes::name(normal_code),
+            keycodes::name(combined_code)
+        ),
+        KeyAction::Interval { code, delay } => {
+            format!("interval({},{})", keycodes::name(code), delay)
+        }
+        KeyAction::Config(id) => format!("config({})", id),
+    }
+}
+
+/// Parses a keymap token into an action
+pub fn parse_action(token: &str) -> Result<KeyAction, String> {
+    let (name, args) = match token.split_once('(') {
+        Some((name, rest)) => match rest.strip_suffix(')') {
+            Some(args) => (name, args.split(',').map(str::trim).collect::<Vec<_>>()),
+            None => return Err(format!("missing ) in {}", token)),
+        },
+        None => {
+            return keycodes::parse(token)
+                .map(KeyAction::Code)
+                .ok_or(format!("unknown keycode {}", token))
+        }
+    };
+    let code = |i: usize| -> Result<u8, String> {
+        let arg = args
+            .get(i)
+            .ok_or(format!("missing argument in {}", token))?;
+        keycodes::parse(arg).ok_or(format!("unknown keycode {}", arg))
+    };
+    let number = |i: usize| -> Result<u16, String> {
+        let arg = args
+            .get(i)
+            .ok_or(format!("missing argument in {}", token))?;
+        arg.parse().map_err(|_| format!("invalid number {}", arg))
+    };
+    let expected = match name {
+        "toggle" | "config" => 1,
+        "double" | "interval" => 2,
+        "triple" | "combined" => 3,
+        _ => return Err(format!("unknown action {}", name)),
+    };
+    if args.len() != expected {
+        return Err(format!("{} takes {} arguments", name, expected));
+    }
+    let small = |value: u16| u8::try_from(value).map_err(|_| format!("{} is too large", value));
+    match name {
+        "toggle" => Ok(KeyAction::Toggle(code(0)?)),
+        "double" => Ok(KeyAction::Double(code(0)?, code(1)?)),
+        "triple" => Ok(KeyAction::Triple(code(0)?, code(1)?, code(2)?)),
+        "combined" => Ok(KeyAction::Combined {
+            other_index: small(number(0)?)?,
+            normal_code: code(1)?,
+            combined_code: code(2)?,
+        }),
+        "interval" => Ok(KeyAction::Interval {
+            code: code(0)?,
+            delay: number(1)?,
+        }),
+        _ => Ok(KeyAction::Config(small(number(0)?)?)),
+    }
+}
+
+/// Parses a keymap file. Errors contain the line they happened on
+pub fn parse(text: &str) -> Result<Vec<KeymapLayer>, String> {
+    let mut layers: Vec<KeymapLayer> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace().peekable();
+        if tokens.peek() == Some(&"layer") {
+            tokens.next();
+            let layer = tokens
+                .next()
+                .and_then(|layer| layer.parse().ok())
+                .ok_or(format!("line {}: expected a layer number", number + 1))?;
+            layers.push(KeymapLayer {
+                layer,
+                actions: Vec::new(),
+            });
+            continue;
+        }
+        for token in tokens {
+            let current = layers.last_mut().ok_or(format!(
+                "line {}: action before the first layer",
+                number + 1
+            ))?;
+            let action = parse_action(token).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            current.actions.push(action);
+        }
+    }
+    Ok(layers)
+}
+
+/// Formats the layers as a keymap file
+pub fn format(layers: &[KeymapLayer]) -> String {
+    let mut text = String::new();
+    for layer in layers {
+        writeln!(text, "layer {}", layer.layer).unwrap();
+        for (row, actions) in layer.actions.chunks(KEYS_PER_LINE).enumerate() {
+            let tokens: Vec<_> = actions.iter().map(format_action).collect();
+            let start = row * KEYS_PER_LINE;
+            writeln!(
+                text,
+                "{}  # {}-{}",
+                tokens.join(" "),
+                start,
+                start + actions.len() - 1
+            )
+            .unwrap();
+        }
+        text.push('\n');
+    }
+    text
+}
@@ -0,0 +1,62 @@
+//! Physical layout of the 36 key choc board, taken from the switch footprints
+//! in pcb/left and pcb/right. Positions are key centers in units of 19.05 mm,
+//! with the left half's outer column at x 0 and the top of each half at y 0.
+//! Rotations are in degrees, clockwise.
+
+#[derive(Copy, Clone, Debug)]
+pub struct KeyPosition {
+    pub x: f32,
+    pub y: f32,
+    pub rotation: f32,
+}
+
+const fn pos(x: f32, y: f32, rotation: f32) -> KeyPosition {
+    KeyPosition { x, y, rotation }
+}
+
+pub const KEYBOARD_NAME: &str = "Choc 36";
+
+/// Position of each key index. Indices past the end have no physical key, so
+/// the export fails if they have an action
+pub const KEY_POSITIONS: [KeyPosition; 36] = [
+    // Left half
+    pos(0.00, 1.00, 0.0),
+    pos(1.00, 0.31, 0.0),
+    pos(2.00, 0.00, 0.0),
+    pos(3.00, 0.31, 0.0),
+    pos(4.00, 0.47, 0.0),
+    pos(0.00, 2.00, 0.0),
+    pos(1.00, 1.31, 0.0),
+    pos(2.00, 1.00, 0.0),
+    pos(3.00, 1.31, 0.0),
+    pos(4.00, 1.47, 0.0),
+    pos(0.00, 3.00, 0.0),
+    pos(1.00, 2.31, 0.0),
+    pos(2.00, 2.00, 0.0),
+    pos(3.00, 2.31, 0.0),
+    pos(4.00, 2.47, 0.0),
+    // Left thumbs
+    pos(2.50, 3.39, 0.0),
+    pos(3.61, 3.54, 15.0),
+    pos(4.65, 3.97, 30.0),
+    // Right half
+    pos(7.00, 0.47, 0.0),
+    pos(8.00, 0.31, 0.0),
+    pos(9.00, 0.00, 0.0),
+    pos(10.00, 0.31, 0.0),
+    pos(11.00, 1.00, 0.0),
+    pos(7.00, 1.47, 0.0),
+    pos(8.00, 1.31, 0.0),
+    pos(9.00, 1.00, 0.0),
+    pos(10.00, 1.31, 0.0),
+    pos(11.00, 2.00, 0.0),
+    pos(7.00, 2.47, 0.0),
+    pos(8.00, 2.31, 0.0),
+    pos(9.00, 2.00, 0.0),
+    pos(10.00, 2.31, 0.0),
+    pos(11.00, 3.00, 0.0),
+    // Right thumbs
+    pos(6.35, 3.97, -30.0),
+    pos(7.39, 3.54, -15.0),
+    pos(8.50, 3.39, 0.0),
+];
@@ -0,0 +1,285 @@
+//! Configures the keyboard over the BufferReport raw HID interface, or a fake
+//! device for trying things out without a board.
+
+mod device;
+mod export;
+mod fake;
+mod keycodes;
+mod keymap;
+mod layout;
+mod protocol;
+
+use std::{env, fs, path::Path, process::ExitCode};
+
+use device::{Hidraw, Socket, Transport};
+use fake::FakeDevice;
+use keymap::KeymapLayer;
+use protocol::{Client, Info};
+
+const USAGE: &str = "\
+Usage: bruh78 [--device <hidraw> | --socket <path> | --fake] <command>
+
+Commands:
+  info                      Show the protocol version, key and layer count
+  dump [file]               Write the keymap to a file, or stdout
+  upload <file>             Upload a keymap file
+  bonds                     List the bonded BLE peers
+  battery                   Read the battery level
+  layer                     Show the active layer
+  latency                   Show the scan to report latency
+  get-setting <id>          Read a setting
+  set-setting <id> <value>  Change a setting. Settings are 0 debounce (0 or
+                            1), 1 scan interval and 2 settle time, both in us
+  reset --yes               Factory reset the keymap, settings and bonds
+  recalibrate               Recalibrate the trackpad. Don't touch it meanwhile
+  export <json|kle> [--from <keymap>] [--layer <n>] [file]
+                            Export the keymap with the key positions. KLE
+                            exports one layer, 0 by default. --from exports
+                            a keymap file instead of the keyboard's keymap
+  serve-fake <socket>       Serve a fake device on a unix socket
+
+Without a device option the keyboard's hidraw node is looked up in sysfs.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (transport, args): (Option<Box<dyn Transport>>, &[String]) = match args {
+        [flag, path, rest @ ..] if flag == "--device" => (
+            Some(Box::new(
+                Hidraw::open(Path::new(path)).map_err(|e| format!("{}: {}", path, e))?,
+            )),
+            rest,
+        ),
+        [flag, path, rest @ ..] if flag == "--socket" => (
+            Some(Box::new(
+                Socket::connect(Path::new(path)).map_err(|e| format!("{}: {}", path, e))?,
+            )),
+            rest,
+        ),
+        [flag, rest @ ..] if flag == "--fake" => (Some(Box::new(FakeDevice::default())), rest),
+        _ => (None, args),
+    };
+
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => return Err(USAGE.to_string()),
+    };
+    if command == "serve-fake" {
+        let path = args.first().ok_or(USAGE)?;
+        return FakeDevice::default()
+            .serve(Path::new(path))
+            .map_err(|e| format!("{}: {}", path, e));
+    }
+    let export = match command {
+        "export" => Some(ExportArgs::parse(args)?),
+        _ => None,
+    };
+    // Keymap files are exported without a keyboard
+    if let Some(from) = export.as_ref().and_then(|export| export.from.clone()) {
+        let text = fs::read_to_string(&from).map_err(|e| format!("{}: {}", from, e))?;
+        let layers = keymap::parse(&text).map_err(|e| format!("{}: {}", from, e))?;
+        return export.unwrap().write(&layers);
+    }
+
+    let transport = match transport {
+        Some(transport) => transport,
+        None => {
+            let path = Hidraw::find().map_err(|e| e.to_string())?;
+            Box::new(Hidraw::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?)
+        }
+    };
+    let mut client = Client::new(transport);
+    let info = client.info().map_err(|e| e.to_string())?;
+
+    match (command, args) {
+        ("info", []) => {
+            println!("protocol version: {}", info.version);
+            println!("keys: {}", info.keys);
+            println!("layers: {}", info.layers);
+        }
+        ("dump", [] | [_]) => {
+            let layers = read_keymap(&mut client, &info)?;
+            let text = keymap::format(&layers);
+            match args.first() {
+                Some(path) => fs::write(path, text).map_err(|e| format!("{}: {}", path, e))?,
+                None => print!("{}", text),
+            }
+        }
+        ("upload", [path]) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            let layers = keymap::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+            // Check the whole file before changing anything on the keyboard
+            for layer in &layers {
+                if layer.layer as usize >= info.layers {
+                    return Err(format!(
+                        "layer {} doesn't exist, the keyboard has {} layers",
+                        layer.layer, info.layers
+                    ));
+                }
+                if layer.actions.len() != info.keys {
+                    return Err(format!(
+                        "layer {} has {} keys, the keyboard has {}",
+                        layer.layer,
+                        layer.actions.len(),
+                        info.keys
+                    ));
+                }
+            }
+            for layer in &layers {
+                for (index, action) in layer.actions.iter().enumerate() {
+                    client
+                        .set_key(layer.layer, index as u8, *action)
+                        .map_err(|e| format!("layer {} key {}: {}", layer.layer, index, e))?;
+                }
+                println!("Uploaded layer {}", layer.layer);
+            }
+        }
+        ("bonds", []) => {
+            let bonds = client.bonds().map_err(|e| e.to_string())?;
+            if bonds.is_empty() {
+                println!("No bonds");
+            }
+            for bond in bonds {
+                let addr: Vec<_> = bond
+                    .addr
+                    .iter()
+                    .rev()
+                    .map(|b| format!("{:02X}", b))
+                    .collect();
+                println!(
+                    "{}: {} (flags 0x{:02X})",
+                    bond.index,
+                    addr.join(":"),
+                    bond.flags
+                );
+            }
+        }
+        ("battery", []) => {
+            let battery = client.battery().map_err(|e| e.to_string())?;
+            println!("{}%", battery);
+        }
+        ("layer", []) => {
+            let layer = client.layer().map_err(|e| e.to_string())?;
+            println!("{}", layer);
+        }
+        ("latency", []) => {
+            let latency = client.latency().map_err(|e| e.to_string())?;
+            println!("reports: {}", latency.count);
+            println!("average: {} us", latency.average);
+            println!("max: {} us", latency.max);
+            println!("last: {} us", latency.last);
+        }
+        ("get-setting", [id]) => {
+            let id = id
+                .parse()
+                .map_err(|_| format!("invalid setting id {}", id))?;
+            let value = client.get_setting(id).map_err(|e| e.to_string())?;
+            println!("{}", value);
+        }
+        ("set-setting", [id, value]) => {
+            let id = id
+                .parse()
+                .map_err(|_| format!("invalid setting id {}", id))?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid setting value {}", value))?;
+            client.set_setting(id, value).map_err(|e| e.to_string())?;
+        }
+        ("reset", [yes]) if yes == "--yes" => {
+            client.factory_reset().map_err(|e| e.to_string())?;
+            println!("Keyboard reset, pair it again over BLE");
+        }
+        ("reset", _) => return Err("reset erases everything, pass --yes to confirm".to_string()),
+        ("recalibrate", []) => {
+            client.recalibrate_trackpad().map_err(|e| e.to_string())?;
+            println!("Trackpad recalibrated");
+        }
+        ("export", _) => {
+            let layers = read_keymap(&mut client, &info)?;
+            export.unwrap().write(&layers)?;
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
+/// Reads every layer of the keyboard's keymap
+fn read_keymap<T: Transport>(
+    client: &mut Client<T>,
+    info: &Info,
+) -> Result<Vec<KeymapLayer>, String> {
+    let mut layers = Vec::new();
+    for layer in 0..info.layers as u8 {
+        let mut actions = Vec::new();
+        for index in 0..info.keys as u8 {
+            actions.push(client.get_key(layer, index).map_err(|e| e.to_string())?);
+        }
+        layers.push(KeymapLayer { layer, actions });
+    }
+    Ok(layers)
+}
+
+struct ExportArgs {
+    kle: bool,
+    from: Option<String>,
+    layer: u8,
+    output: Option<String>,
+}
+
+impl ExportArgs {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut export = ExportArgs {
+            kle: match args.first().map(String::as_str) {
+                Some("json") => false,
+                Some("kle") => true,
+                _ => return Err(USAGE.to_string()),
+            },
+            from: None,
+            layer: 0,
+            output: None,
+        };
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--from" => export.from = Some(args.next().ok_or(USAGE)?.clone()),
+                "--layer" => {
+                    let layer = args.next().ok_or(USAGE)?;
+                    export.layer = layer
+                        .parse()
+                        .map_err(|_| format!("invalid layer {}", layer))?;
+                }
+                _ if export.output.is_none() => export.output = Some(arg.clone()),
+                _ => return Err(USAGE.to_string()),
+            }
+        }
+        Ok(export)
+    }
+
+    fn write(self, layers: &[KeymapLayer]) -> Result<(), String> {
+        let text = if self.kle {
+            let layer = layers
+                .iter()
+                .find(|layer| layer.layer == self.layer)
+                .ok_or(format!("keymap has no layer {}", self.layer))?;
+            export::kle(layer)?
+        } else {
+            export::json(layers)?
+        };
+        match self.output {
+            Some(path) => fs::write(&path, text).map_err(|e| format!("{}: {}", path, e)),
+            None => {
+                print!("{}", text);
+                Ok(())
+            }
+        }
+    }
+}
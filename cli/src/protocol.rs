@@ -0,0 +1,391 @@
+//! Host side of the command protocol in firmware/src/command.rs. Requests are
+//! [id, args..] and responses are [id, status, data..] in PACKET_SIZE reports.
+//! The values here have to be kept in sync with the firmware.
+
+use std::{fmt, io};
+
+use crate::device::Transport;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const PACKET_SIZE: usize = 32;
+pub const ACTION_SIZE: usize = 4;
+pub const BONDS_PER_PACKET: usize = 3;
+const BOND_ENTRY_SIZE: usize = 8;
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CommandId {
+    Version = 0x01,
+    GetKey = 0x02,
+    SetKey = 0x03,
+    GetLayer = 0x04,
+    GetBattery = 0x06,
+    GetBonds = 0x07,
+    FactoryReset = 0x08,
+    GetSetting = 0x09,
+    SetSetting = 0x0A,
+    GetLatency = 0x0B,
+    GetTrackPad = 0x0C,
+    SetTrackPad = 0x0D,
+    RecalibrateTrackPad = 0x0E,
+}
+
+impl CommandId {
+    pub fn from_raw(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(CommandId::Version),
+            0x02 => Some(CommandId::GetKey),
+            0x03 => Some(CommandId::SetKey),
+            0x04 => Some(CommandId::GetLayer),
+            0x06 => Some(CommandId::GetBattery),
+            0x07 => Some(CommandId::GetBonds),
+            0x08 => Some(CommandId::FactoryReset),
+            0x09 => Some(CommandId::GetSetting),
+            0x0A => Some(CommandId::SetSetting),
+            0x0B => Some(CommandId::GetLatency),
+            0x0C => Some(CommandId::GetTrackPad),
+            0x0D => Some(CommandId::SetTrackPad),
+            0x0E => Some(CommandId::RecalibrateTrackPad),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    InvalidArgument = 2,
+    Unsupported = 3,
+}
+
+impl Status {
+    pub fn from_raw(status: u8) -> Option<Self> {
+        match status {
+            0 => Some(Status::Ok),
+            1 => Some(Status::UnknownCommand),
+            2 => Some(Status::InvalidArgument),
+            3 => Some(Status::Unsupported),
+            _ => None,
+        }
+    }
+}
+
+/// Mirror of the firmware's keymap::KeyAction. Codes are raw KeyCodes values
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyAction {
+    Code(u8),
+    Toggle(u8),
+    Double(u8, u8),
+    Triple(u8, u8, u8),
+    Combined {
+        other_index: u8,
+        normal_code: u8,
+        combined_code: u8,
+    },
+    Interval {
+        code: u8,
+        delay: u16,
+    },
+    Config(u8),
+}
+
+impl KeyAction {
+    pub fn to_bytes(self) -> [u8; ACTION_SIZE] {
+        match self {
+            KeyAction::Code(code) => [0, code, 0, 0],
+            KeyAction::Toggle(code) => [1, code, 0, 0],
+            KeyAction::Double(code0, code1) => [2, code0, code1, 0],
+            KeyAction::Triple(code0, code1, code2) => [3, code0, code1, code2],
+            KeyAction::Combined {
+                other_index,
+                normal_code,
+                combined_code,
+            } => [4, other_index, normal_code, combined_code],
+            KeyAction::Interval { code, delay } => {
+                let delay = delay.to_le_bytes();
+                [5, code, delay[0], delay[1]]
+            }
+            KeyAction::Config(id) => [6, id, 0, 0],
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < ACTION_SIZE {
+            return None;
+        }
+        match buf[0] {
+            0 => Some(KeyAction::Code(buf[1])),
+            1 => Some(KeyAction::Toggle(buf[1])),
+            2 => Some(KeyAction::Double(buf[1], buf[2])),
+            3 => Some(KeyAction::Triple(buf[1], buf[2], buf[3])),
+            4 => Some(KeyAction::Combined {
+                other_index: buf[1],
+                normal_code: buf[2],
+                combined_code: buf[3],
+            }),
+            5 => Some(KeyAction::Interval {
+                code: buf[1],
+                delay: u16::from_le_bytes([buf[2], buf[3]]),
+            }),
+            6 => Some(KeyAction::Config(buf[1])),
+            _ => None,
+        }
+    }
+}
+
+/// Errors returned by the Client
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Status(CommandId, Status),
+    InvalidResponse(CommandId),
+    Version(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "device io failed: {}", e),
+            Error::Status(id, status) => write!(f, "{:?} failed with {:?}", id, status),
+            Error::InvalidResponse(id) => write!(f, "invalid response to {:?}", id),
+            Error::Version(version) => write!(
+                f,
+                "device speaks protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Keyboard info returned by the Version command
+#[derive(Copy, Clone, Debug)]
+pub struct Info {
+    pub version: u8,
+    pub keys: usize,
+    pub layers: usize,
+}
+
+/// Scan to report latency counters, in microseconds
+#[derive(Copy, Clone, Debug)]
+pub struct Latency {
+    pub count: u32,
+    pub average: u32,
+    pub max: u32,
+    pub last: u32,
+}
+
+/// A bonded BLE peer
+#[derive(Copy, Clone, Debug)]
+pub struct Bond {
+    pub index: u8,
+    pub flags: u8,
+    pub addr: [u8; 6],
+}
+
+/// Sends commands to a keyboard over the passed in transport
+pub struct Client<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    /// Sends a command and returns the data of the response
+    fn request(&mut self, id: CommandId, args: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut request = [0u8; PACKET_SIZE];
+        request[0] = id as u8;
+        request[1..1 + args.len()].copy_from_slice(args);
+        let response = self.transport.transfer(&request)?;
+        if response[0] != id as u8 {
+            return Err(Error::InvalidResponse(id));
+        }
+        match Status::from_raw(response[1]) {
+            Some(Status::Ok) => Ok(response[2..].to_vec()),
+            Some(status) => Err(Error::Status(id, status)),
+            None => Err(Error::InvalidResponse(id)),
+        }
+    }
+
+    /// Returns the keyboard info. Fails if the keyboard uses another protocol version
+    pub fn info(&mut self) -> Result<Info, Error> {
+        let data = self.request(CommandId::Version, &[])?;
+        if data[0] != PROTOCOL_VERSION {
+            return Err(Error::Version(data[0]));
+        }
+        Ok(Info {
+            version: data[0],
+            keys: data[1] as usize,
+            layers: data[2] as usize,
+        })
+    }
+
+    pub fn get_key(&mut self, layer: u8, index: u8) -> Result<KeyAction, Error> {
+        let data = self.request(CommandId::GetKey, &[layer, index])?;
+        KeyAction::from_bytes(&data[2..]).ok_or(Error::InvalidResponse(CommandId::GetKey))
+    }
+
+    pub fn set_key(&mut self, layer: u8, index: u8, action: KeyAction) -> Result<(), Error> {
+        let mut args = [0u8; 2 + ACTION_SIZE];
+        args[0] = layer;
+        args[1] = index;
+        args[2..].copy_from_slice(&action.to_bytes());
+        self.request(CommandId::SetKey, &args).map(|_| ())
+    }
+
+    pub fn layer(&mut self) -> Result<u8, Error> {
+        Ok(self.request(CommandId::GetLayer, &[])?[0])
+    }
+
+    pub fn battery(&mut self) -> Result<u8, Error> {
+        Ok(self.request(CommandId::GetBattery, &[])?[0])
+    }
+
+    /// Returns every bonded peer. Bonds are paged BONDS_PER_PACKET at a time
+    pub fn bonds(&mut self) -> Result<Vec<Bond>, Error> {
+        let mut bonds = Vec::new();
+        loop {
+            let data = self.request(CommandId::GetBonds, &[bonds.len() as u8])?;
+            let count = data[0] as usize;
+            let page = (count - bonds.len().min(count)).min(BONDS_PER_PACKET);
+            for entry in data[1..].chunks(BOND_ENTRY_SIZE).take(page) {
+                bonds.push(Bond {
+                    index: entry[0],
+                    flags: entry[1],
+                    addr: entry[2..].try_into().unwrap(),
+                });
+            }
+            if page == 0 || bonds.len() >= count {
+                return Ok(bonds);
+            }
+        }
+    }
+
+    pub fn factory_reset(&mut self) -> Result<(), Error> {
+        self.request(CommandId::FactoryReset, &[]).map(|_| ())
+    }
+
+    pub fn get_setting(&mut self, id: u8) -> Result<u16, Error> {
+        let data = self.request(CommandId::GetSetting, &[id])?;
+        Ok(u16::from_le_bytes([data[1], data[2]]))
+    }
+
+    pub fn set_setting(&mut self, id: u8, value: u16) -> Result<(), Error> {
+        let value = value.to_le_bytes();
+        self.request(CommandId::SetSetting, &[id, value[0], value[1]])
+            .map(|_| ())
+    }
+
+    pub fn latency(&mut self) -> Result<Latency, Error> {
+        let data = self.request(CommandId::GetLatency, &[])?;
+        let word = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+        Ok(Latency {
+            count: word(0),
+            average: word(1),
+            max: word(2),
+            last: word(3),
+        })
+    }
+
+    /// Recalibrates the trackpad. Fails with Unsupported on boards without one
+    pub fn recalibrate_trackpad(&mut self) -> Result<(), Error> {
+        self.request(CommandId::RecalibrateTrackPad, &[])
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{FakeDevice, FAKE_KEYS, FAKE_LAYERS};
+
+    fn client() -> Client<FakeDevice> {
+        Client::new(FakeDevice::default())
+    }
+
+    #[test]
+    fn info_reports_the_keys_and_layers() {
+        let info = client().info().unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION);
+        assert_eq!(info.keys, FAKE_KEYS);
+        assert_eq!(info.layers, FAKE_LAYERS);
+    }
+
+    #[test]
+    fn set_key_is_read_back() {
+        let mut client = client();
+        assert_eq!(client.get_key(0, 0).unwrap(), KeyAction::Code(0x04));
+        let action = KeyAction::Combined {
+            other_index: 3,
+            normal_code: 0x05,
+            combined_code: 0x06,
+        };
+        client.set_key(2, 7, action).unwrap();
+        assert_eq!(client.get_key(2, 7).unwrap(), action);
+    }
+
+    #[test]
+    fn keys_out_of_range_are_rejected() {
+        let mut client = client();
+        assert!(matches!(
+            client.get_key(FAKE_LAYERS as u8, 0),
+            Err(Error::Status(CommandId::GetKey, Status::InvalidArgument))
+        ));
+        assert!(matches!(
+            client.set_key(0, FAKE_KEYS as u8, KeyAction::Code(0x04)),
+            Err(Error::Status(CommandId::SetKey, Status::InvalidArgument))
+        ));
+    }
+
+    #[test]
+    fn layer_is_read() {
+        assert_eq!(client().layer().unwrap(), 0);
+    }
+
+    #[test]
+    fn settings_are_checked_and_read_back() {
+        let mut client = client();
+        client.set_setting(1, 250).unwrap();
+        assert_eq!(client.get_setting(1).unwrap(), 250);
+        assert!(matches!(
+            client.set_setting(0, 2),
+            Err(Error::Status(
+                CommandId::SetSetting,
+                Status::InvalidArgument
+            ))
+        ));
+        assert_eq!(client.get_setting(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn factory_reset_restores_the_defaults() {
+        let mut client = client();
+        client.set_key(0, 0, KeyAction::Code(0x29)).unwrap();
+        client.set_setting(2, 50).unwrap();
+        assert_eq!(client.bonds().unwrap().len(), 2);
+        client.factory_reset().unwrap();
+        assert_eq!(client.get_key(0, 0).unwrap(), KeyAction::Code(0x04));
+        assert_eq!(client.get_setting(2).unwrap(), 0);
+        assert!(client.bonds().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recalibrating_without_a_trackpad_is_unsupported() {
+        assert!(matches!(
+            client().recalibrate_trackpad(),
+            Err(Error::Status(
+                CommandId::RecalibrateTrackPad,
+                Status::Unsupported
+            ))
+        ));
+    }
+}
@@ -0,0 +1,80 @@
+//! Prints a compiled-in layout of config::layouts as a keymap file for the
+//! CLI, so it can be exported without a keyboard:
+//!
+//! cargo run --example layouts --no-default-features --target x86_64-unknown-linux-gnu -- 0 > callum.keymap
+//! bruh78 export kle --from callum.keymap
+
+use std::{env, process::ExitCode};
+
+use bruh78::{
+    codes::KeyCodes,
+    config::layouts,
+    keymap::KeyAction,
+    keys::{Keys, NUM_LAYERS},
+};
+
+const KEYS: usize = 39;
+// Keys per line, which is one row of a half
+const KEYS_PER_LINE: usize = 5;
+
+/// Formats a raw code the way the CLI's keymap parser reads it
+fn name(code: u8) -> String {
+    match (code, KeyCodes::from_raw(code)) {
+        (0, _) => "None".to_string(),
+        (_, Some(code)) => code.name().to_string(),
+        (_, None) => format!("0x{:02X}", code),
+    }
+}
+
+fn token(action: KeyAction) -> String {
+    match action {
+        KeyAction::Code(code) => name(code),
+        KeyAction::Toggle(code) => format!("toggle({})", name(code)),
+        KeyAction::Double(code0, code1) => format!("double({},{})", name(code0), name(code1)),
+        KeyAction::Triple(code0, code1, code2) => {
+            format!("triple({},{},{})", name(code0), name(code1), name(code2))
+        }
+        KeyAction::Combined {
+            other_index,
+            normal_code,
+            combined_code,
+        } => format!(
+            "combined({},{},{})",
+            other_index,
+            name(normal_code),
+            name(combined_code)
+        ),
+        KeyAction::Interval { code, delay } => format!("interval({},{})", name(code), delay),
+        KeyAction::Config(id) => format!("config({})", id),
+    }
+}
+
+fn main() -> ExitCode {
+    let layouts = layouts::<KEYS>();
+    let layout = match env::args().nth(1).and_then(|id| id.parse::<usize>().ok()) {
+        Some(id) if id < layouts.len() => layouts[id],
+        _ => {
+            eprintln!("usage: layouts <0-{}>", layouts.len() - 1);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut keys = Keys::<KEYS>::default();
+    layout(&mut keys);
+    for layer in 0..NUM_LAYERS {
+        println!("layer {}", layer);
+        let actions: Vec<_> = (0..KEYS)
+            .map(|i| token(keys.get_action(i, layer)))
+            .collect();
+        for (row, tokens) in actions.chunks(KEYS_PER_LINE).enumerate() {
+            let start = row * KEYS_PER_LINE;
+            println!(
+                "{}  # {}-{}",
+                tokens.join(" "),
+                start,
+                start + tokens.len() - 1
+            );
+        }
+        println!();
+    }
+    ExitCode::SUCCESS
+}
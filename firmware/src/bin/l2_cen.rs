@@ -1,15 +1,20 @@
 #![no_std]
 #![no_main]
 
-use core::{mem, slice};
+use core::{cell::Cell, mem, slice};
 
 use bruh78::battery::BatteryVoltage;
+use bruh78::bitmap::KeyBitmap;
 use bruh78::cirque::TrackPad;
 use bruh78::config::load_callum;
-use bruh78::descriptor::{CombinedReport, KeyboardReportNKRO};
+use bruh78::descriptor::{KeyboardReportNKRO, MouseReport, MouseReport16};
+use bruh78::gesture::{ScrollConfig, ScrollMode};
 use bruh78::keys::Keys;
-use bruh78::matrix::Matrix;
+use bruh78::matrix::{Matrix, LEFT_TRANSFORM};
 use bruh78::report::Report;
+use bruh78::scanner::KeyScanner;
+use bruh78::split::central::HidService;
+use bruh78::split::HALF_KEYS;
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_futures::join::join;
@@ -17,7 +22,7 @@ use embassy_futures::select::{select, select3, Either};
 use embassy_futures::select::{select4, select_array};
 use embassy_nrf::config::HfclkSource;
 use embassy_nrf::gpio::Pin;
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::gpio::{Level, Output, OutputDrive};
 use embassy_nrf::gpiote::Channel;
 use embassy_nrf::gpiote::InputChannel;
 use embassy_nrf::gpiote::InputChannelPolarity;
@@ -64,12 +69,6 @@ const SOFTWARE_REVISION: Uuid = Uuid::new_16(0x2a28);
 const MANUFACTURER_NAME: Uuid = Uuid::new_16(0x2a29);
 const PNP_ID: Uuid = Uuid::new_16(0x2a50);
 
-const HID_INFO: Uuid = Uuid::new_16(0x2a4a);
-const REPORT_MAP: Uuid = Uuid::new_16(0x2a4b);
-const HID_CONTROL_POINT: Uuid = Uuid::new_16(0x2a4c);
-const HID_REPORT: Uuid = Uuid::new_16(0x2a4d);
-const PROTOCOL_MODE: Uuid = Uuid::new_16(0x2a4e);
-
 // Main items
 pub const HIDINPUT: u8 = 0x80;
 pub const HIDOUTPUT: u8 = 0x90;
@@ -103,8 +102,6 @@ pub const STRING_MINIMUM: u8 = 0x88;
 pub const STRING_MAXIMUM: u8 = 0x98;
 pub const DELIMITER: u8 = 0xa8;
 
-const KEYBOARD_ID: u8 = 0x01;
-
 #[nrf_softdevice::gatt_client(uuid = "9e7312e0-2354-11eb-9f10-fbc30a62cf38")]
 struct KeyClient {
     #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf38", read, write, notify)]
@@ -257,144 +254,6 @@ impl BatteryService {
     }
 }
 
-#[allow(dead_code)]
-pub struct HidService {
-    hid_info: u16,
-    report_map: u16,
-    hid_control: u16,
-    protocol_mode: u16,
-    input_keyboard: u16,
-    input_keyboard_cccd: u16,
-    input_keyboard_descriptor: u16,
-    output_keyboard: u16,
-    output_keyboard_descriptor: u16,
-    input_mouse: u16,
-    input_mouse_cccd: u16,
-    intput_mouse_descriptor: u16,
-}
-
-impl HidService {
-    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
-        let mut service_builder = ServiceBuilder::new(sd, Uuid::new_16(0x1812))?;
-
-        let hid_info = service_builder.add_characteristic(
-            HID_INFO,
-            Attribute::new([0x11u8, 0x1u8, 0x00u8, 0x01u8]).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().read()),
-        )?;
-        let hid_info_handle = hid_info.build();
-
-        let report_map = service_builder.add_characteristic(
-            REPORT_MAP,
-            Attribute::new(CombinedReport::desc()).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().read()),
-        )?;
-        let report_map_handle = report_map.build();
-
-        let hid_control = service_builder.add_characteristic(
-            HID_CONTROL_POINT,
-            Attribute::new([0u8]).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().write_without_response()),
-        )?;
-        let hid_control_handle = hid_control.build();
-
-        let protocol_mode = service_builder.add_characteristic(
-            PROTOCOL_MODE,
-            Attribute::new([1u8]).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().read().write_without_response()),
-        )?;
-        let protocol_mode_handle = protocol_mode.build();
-
-        let mut input_keyboard = service_builder.add_characteristic(
-            HID_REPORT,
-            Attribute::new([0u8; 8]).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().read().notify()),
-        )?;
-        let input_keyboard_desc = input_keyboard.add_descriptor(
-            Uuid::new_16(0x2908),
-            Attribute::new([1u8, 1u8]).security(SecurityMode::JustWorks),
-        )?; // First is ID (e.g. 1 for keyboard 2 for media keys), second is in/out
-        let input_keyboard_handle = input_keyboard.build();
-
-        let mut output_keyboard = service_builder.add_characteristic(
-            HID_REPORT,
-            Attribute::new([0u8; 8]).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().read().write().write_without_response()),
-        )?;
-        let output_keyboard_desc = output_keyboard.add_descriptor(
-            Uuid::new_16(0x2908),
-            Attribute::new([1u8, 2u8]).security(SecurityMode::JustWorks),
-        )?; // First is ID (e.g. 1 for keyboard 2 for media keys)
-        let output_keyboard_desc = output_keyboard
-            .add_descriptor(Uuid::new_16(0x2908), Attribute::new([KEYBOARD_ID, 2u8]))?; // First is ID (e.g. 1 for keyboard 2 for media keys)
-        let output_keyboard_handle = output_keyboard.build();
-
-        let mut input_mouse = service_builder.add_characteristic(
-            HID_REPORT,
-            Attribute::new([0u8; 5]).security(SecurityMode::JustWorks),
-            Metadata::new(Properties::new().read().notify()),
-        )?;
-
-        let input_mouse_desc = input_mouse.add_descriptor(
-            Uuid::new_16(0x2908),
-            Attribute::new([2, 1u8]).security(SecurityMode::JustWorks),
-        )?;
-
-        let input_mouse_handle = input_mouse.build();
-
-        let _service_handle = service_builder.build();
-
-        Ok(HidService {
-            hid_info: hid_info_handle.value_handle,
-            report_map: report_map_handle.value_handle,
-            hid_control: hid_control_handle.value_handle,
-            protocol_mode: protocol_mode_handle.value_handle,
-            input_keyboard: input_keyboard_handle.value_handle,
-            input_keyboard_cccd: input_keyboard_handle.cccd_handle,
-            input_keyboard_descriptor: input_keyboard_desc.handle(),
-            output_keyboard: output_keyboard_handle.value_handle,
-            output_keyboard_descriptor: output_keyboard_desc.handle(),
-            input_mouse: input_mouse_handle.value_handle,
-            input_mouse_cccd: input_mouse_handle.cccd_handle,
-            intput_mouse_descriptor: input_mouse_desc.handle(),
-        })
-    }
-
-    pub fn on_write(&self, conn: &Connection, handle: u16, data: &[u8]) {
-        let val = &[
-            0, // Modifiers (Shift, Ctrl, Alt, GUI, etc.)
-            0, // Reserved
-            0x0E, 0, 0, 0, 0, 0, // Key code array - 0x04 is 'a' and 0x1d is 'z' - for example
-        ];
-        // gatt_server::notify_value(conn, self.input_keyboard_cccd, val).unwrap();
-        // gatt_server::notify_value(conn, self.input_keyboard_descriptor, val).unwrap();
-        if handle == self.input_keyboard_cccd {
-            info!("HID input keyboard notify: {:?}", data);
-        }
-    }
-
-    pub fn mouse_notify(&self, conn: &Connection, data: &[u8]) {
-        match gatt_server::notify_value(&conn, self.input_mouse, data) {
-            Ok(_) => {
-                info!("Report Sent!");
-            }
-            Err(e) => {
-                error!("{:?}", e);
-            }
-        }
-    }
-    pub fn keyboard_notify(&self, conn: &Connection, data: &[u8]) {
-        match gatt_server::notify_value(&conn, self.input_keyboard, data) {
-            Ok(_) => {
-                info!("Report Sent!");
-            }
-            Err(e) => {
-                error!("{:?}", e);
-            }
-        };
-    }
-}
-
 struct Server {
     _dis: DeviceInformationService,
     bas: BatteryService,
@@ -550,18 +409,18 @@ async fn main(spawner: Spawner) {
     static SEC: HidSecurityHandler = HidSecurityHandler {};
 
     let mut columns = [
-        Output::new(p.P1_00.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_11.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_04.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_06.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_09.degrade(), Level::Low, OutputDrive::Standard),
+        p.P1_00.degrade(),
+        p.P0_11.degrade(),
+        p.P1_04.degrade(),
+        p.P1_06.degrade(),
+        p.P0_09.degrade(),
     ];
 
     let mut rows = [
-        Input::new(p.P0_02.degrade(), Pull::Down),
-        Input::new(p.P1_15.degrade(), Pull::Down),
-        Input::new(p.P1_11.degrade(), Pull::Down),
-        Input::new(p.P0_10.degrade(), Pull::Down),
+        p.P0_02.degrade(),
+        p.P1_15.degrade(),
+        p.P1_11.degrade(),
+        p.P0_10.degrade(),
     ];
 
     let mut keys = Keys::<39>::default();
@@ -572,11 +431,15 @@ async fn main(spawner: Spawner) {
     let mut saadc = Saadc::new(p.SAADC, Irqs, saadc::Config::default(), [battery_channel]);
     let mut battery = BatteryVoltage::new(&mut saadc, 0).await;
 
-    let channel = SyncChannel::<CriticalSectionRawMutex, u32, 10>::new();
+    let channel = SyncChannel::<CriticalSectionRawMutex, KeyBitmap<HALF_KEYS>, 10>::new();
     let tx = channel.sender();
     let rx = channel.receiver();
     let mut matrix = Matrix::new(columns, rows);
+    matrix.set_transform(LEFT_TRANSFORM);
     let mut report = Report::default();
+    // Set by the main loop while trackpad movement should scroll
+    let scrolling = Cell::new(false);
+    let mut scroll = ScrollMode::new(ScrollConfig::default());
     loop {
         info!("start loop");
         let pair_addr = Address::new(
@@ -627,22 +490,31 @@ async fn main(spawner: Spawner) {
         //     }
         // }
 
+        server.hid.reset(sd);
         // Run the GATT server on the connection. This returns when the connection gets disconnected.
         let e = gatt_server::run(&conn, &server, |_| {});
 
         key_client.state_cccd_write(true).await.unwrap();
         key_client.mouse_state_cccd_write(true).await.unwrap();
         let e2 = gatt_client::run(&peer_conn, &key_client, |event| match event {
-            KeyClientEvent::StateNotification(val) => match tx.try_send(val) {
-                Ok(_) => {}
-                Err(_) => {}
-            },
+            KeyClientEvent::StateNotification(val) => {
+                match tx.try_send(KeyBitmap::from_bits(val as u64)) {
+                    Ok(_) => {}
+                    Err(_) => {}
+                }
+            }
             KeyClientEvent::MouseStateNotification(val) => {
-                let x = ((val & 0xFF00) >> 8) as u8;
-                let y = (val & 0xFF) as u8;
-
-                let buf = [0u8, x, y, 0, 0];
-                server.hid.mouse_notify(&conn, &buf);
+                let mut rep = MouseReport {
+                    x: ((val & 0xFF00) >> 8) as i8,
+                    y: (val & 0xFF) as i8,
+                    ..MouseReport::default()
+                };
+                scroll.set_resolution(server.hid.resolution());
+                match scrolling.get() {
+                    true => scroll.update(&mut rep),
+                    false => scroll.reset(),
+                }
+                server.hid.mouse_notify(&conn, &MouseReport16::from(&rep));
             }
         });
 
@@ -661,36 +533,19 @@ async fn main(spawner: Spawner) {
         let main_loop = async {
             Timer::after_secs(2).await;
             loop {
-                let mut states = [[false; 5]; 4];
-                match select(matrix.scan(&mut states), rx.receive()).await {
-                    Either::First(_) => {
-                        states[3][0] = states[3][2];
-                        states[3][1] = states[3][3];
-                        states[3][2] = states[3][4];
-                        let iter = states.iter().flatten().into_iter();
-                        let mut index = 0;
-                        for state in iter {
-                            keys.update_buf_central(index, *state);
-                            index += 1;
-                        }
+                match select(matrix.scan_keys(), rx.receive()).await {
+                    Either::First(bitmap) => {
+                        keys.update_bitmap::<HALF_KEYS>(0, bitmap);
                         match rx.try_receive() {
-                            Ok(val) => {
-                                for i in 0..18 {
-                                    let res = (val >> i) & 1 == 1;
-                                    keys.update_buf(18 + i, res);
-                                }
-                            }
+                            Ok(bitmap) => keys.update_bitmap(HALF_KEYS, bitmap),
                             Err(_) => {}
                         };
                     }
-                    Either::Second(val) => {
-                        for i in 0..18 {
-                            let res = (val >> i) & 1 == 1;
-                            keys.update_buf(18 + i, res);
-                        }
-                    }
+                    Either::Second(bitmap) => keys.update_bitmap(HALF_KEYS, bitmap),
                 }
+                report.set_resolution(server.hid.resolution());
                 let (key, mouse) = report.generate_report(&mut keys);
+                scrolling.set(report.scroll_active());
                 match key {
                     Some(rep) => {
                         let val = [
@@ -709,14 +564,7 @@ async fn main(spawner: Spawner) {
                 };
                 match mouse {
                     Some(rep) => {
-                        let buf = [
-                            rep.buttons,
-                            rep.x as u8,
-                            rep.y as u8,
-                            rep.wheel as u8,
-                            rep.pan as u8,
-                        ];
-                        server.hid.mouse_notify(&conn, &buf);
+                        server.hid.mouse_notify(&conn, rep);
                     }
                     None => {}
                 }
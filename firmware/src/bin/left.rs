@@ -1,23 +1,29 @@
 #![no_std]
 #![no_main]
 
-use core::mem;
+use core::{cell::Cell, mem};
 
 use bruh78::battery::BatteryVoltage;
+use bruh78::bitmap::KeyBitmap;
 use bruh78::bond::Bonder;
+use bruh78::command::{BoardState, CommandHandler, PACKET_SIZE};
 use bruh78::config::load_colemak;
+use bruh78::keymap::{load_keymap, save_keymap};
 use bruh78::keys::Keys;
-use bruh78::matrix::Matrix;
+use bruh78::latency::Latency;
+use bruh78::matrix::{Matrix, LEFT_TRANSFORM};
+use bruh78::power::{system_off, PowerManager, PowerState, PowerTimeouts};
 use bruh78::report::Report;
+use bruh78::scanner::KeyScanner;
 use bruh78::split::central::{BleCentral, Server};
 use bruh78::split::link::Link;
+use bruh78::split::HALF_KEYS;
 use bruh78::storage::{Storage, NRF_FLASH_RANGE};
 use defmt::{info, *};
 use embassy_executor::Spawner;
-use embassy_futures::select::select4;
-use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select, select3, select4, Either, Either3};
 use embassy_nrf::gpio::Pin;
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::gpio::{Level, Output, OutputDrive};
 use embassy_nrf::interrupt::{self, InterruptExt, Priority};
 use embassy_nrf::saadc::{Gain, Saadc};
 use embassy_nrf::{bind_interrupts, saadc};
@@ -25,7 +31,7 @@ use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel as SyncChannel;
 use embassy_sync::mutex::Mutex;
 // time driver
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
 use nrf_softdevice::ble::{set_address, Address, AddressType};
 use nrf_softdevice::{raw, Flash, Softdevice};
 
@@ -41,10 +47,26 @@ async fn softdevice_task(sd: &'static Softdevice) -> ! {
     sd.run().await
 }
 
+#[embassy_executor::task]
+async fn storage_task(storage: &'static Storage<Flash, u32>) {
+    storage.run_storage().await
+}
+
 bind_interrupts!(struct Irqs {
     SAADC => embassy_nrf::saadc::InterruptHandler;
 });
 
+/// Disconnects and enters System OFF until a key is pressed
+async fn power_down(central: &mut BleCentral<'_>, matrix: &mut Matrix<'_, 4, 5>) -> ! {
+    info!("Inactive, powering down");
+    central.disconnect().await;
+    // Dropping the link disconnected the right half. Give the SoftDevice time
+    // to send the terminates
+    Timer::after_millis(100).await;
+    matrix.prepare_wakeup();
+    system_off()
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     info!("Hello World!");
@@ -108,34 +130,36 @@ async fn main(spawner: Spawner) {
     unwrap!(spawner.spawn(softdevice_task(sd)));
     let storage: &'static Storage<Flash, u32> =
         STORAGE.init(Storage::init(Flash::take(&sd), NRF_FLASH_RANGE).await);
+    unwrap!(spawner.spawn(storage_task(storage)));
 
     let mut columns = [
-        Output::new(p.P1_00.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_11.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_04.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_06.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_09.degrade(), Level::Low, OutputDrive::Standard),
+        p.P1_00.degrade(),
+        p.P0_11.degrade(),
+        p.P1_04.degrade(),
+        p.P1_06.degrade(),
+        p.P0_09.degrade(),
     ];
 
     let mut rows = [
-        Input::new(p.P0_02.degrade(), Pull::Down),
-        Input::new(p.P1_15.degrade(), Pull::Down),
-        Input::new(p.P1_11.degrade(), Pull::Down),
-        Input::new(p.P0_10.degrade(), Pull::Down),
+        p.P0_02.degrade(),
+        p.P1_15.degrade(),
+        p.P1_11.degrade(),
+        p.P0_10.degrade(),
     ];
 
     let mut keys = Keys::<39>::default();
-    load_colemak(&mut keys);
+    load_keymap(storage, &mut keys, load_colemak).await;
 
     let mut battery_channel = saadc::ChannelConfig::single_ended(p.P0_31);
     battery_channel.gain = Gain::GAIN1;
     let mut saadc = Saadc::new(p.SAADC, Irqs, saadc::Config::default(), [battery_channel]);
     let mut battery = BatteryVoltage::new(&mut saadc, 0).await;
 
-    let channel = SyncChannel::<CriticalSectionRawMutex, u32, 10>::new();
+    let channel = SyncChannel::<CriticalSectionRawMutex, KeyBitmap<HALF_KEYS>, 10>::new();
     let tx = channel.sender();
     let rx = channel.receiver();
     let mut matrix = Matrix::new(columns, rows);
+    matrix.set_transform(LEFT_TRANSFORM);
     let mut report = Report::default();
 
     let sd_lock: Mutex<CriticalSectionRawMutex, _> = Mutex::new(&*sd);
@@ -143,14 +167,24 @@ async fn main(spawner: Spawner) {
 
     let mut link = Link::new(tx, &sd_lock);
     let bonder: &'static Bonder<_> = BONDER.init(Bonder::init(storage).await);
+    let mut commands = CommandHandler::new(storage, Some(bonder), load_colemak).await;
+    commands.settings().apply(&mut keys);
+    matrix.set_settle_time(commands.settings().settle_time());
+    let battery_level = Cell::new(None);
+    let power = PowerManager::new(PowerTimeouts::default());
 
     loop {
         info!("start loop");
-        central.advertise(bonder).await;
+        let advertised = select(central.advertise(bonder), power.wait_for_off()).await;
+        match advertised {
+            Either::First(_) => {}
+            Either::Second(_) => power_down(&mut central, &mut matrix).await,
+        }
         let battery_loop = async {
             loop {
                 match battery.update_reading().await {
                     Some(percentage) => {
+                        battery_level.set(Some(percentage));
                         central.battery_notify(percentage).await;
                     }
                     None => {}
@@ -160,61 +194,98 @@ async fn main(spawner: Spawner) {
         };
 
         let main_loop = async {
+            let mut latency = Latency::new();
             Timer::after_secs(2).await;
             loop {
-                let mut states = [[false; 5]; 4];
-                match select(matrix.scan(&mut states), rx.receive()).await {
-                    Either::First(_) => {
-                        states[3][0] = states[3][2];
-                        states[3][1] = states[3][3];
-                        states[3][2] = states[3][4];
-                        let iter = states.iter().flatten().into_iter();
-                        let mut index = 0;
-                        for state in iter {
-                            keys.update_buf_central(index, *state);
-                            index += 1;
+                let result =
+                    select3(matrix.scan_keys(), rx.receive(), central.config_receive()).await;
+                let scanned = Instant::now();
+                match result {
+                    Either3::First(bitmap) => {
+                        if !bitmap.is_empty() {
+                            power.touch();
                         }
+                        keys.update_bitmap::<HALF_KEYS>(0, bitmap);
                         match rx.try_receive() {
-                            Ok(val) => {
-                                for i in 0..18 {
-                                    let res = (val >> i) & 1 == 1;
-                                    keys.update_buf(18 + i, res);
-                                }
-                            }
+                            Ok(bitmap) => keys.update_bitmap(HALF_KEYS, bitmap),
                             Err(_) => {}
                         };
                     }
-                    Either::Second(val) => {
-                        for i in 0..18 {
-                            let res = (val >> i) & 1 == 1;
-                            keys.update_buf(18 + i, res);
+                    Either3::Second(bitmap) => {
+                        if !bitmap.is_empty() {
+                            power.touch();
                         }
+                        keys.update_bitmap(HALF_KEYS, bitmap)
+                    }
+                    Either3::Third(event) => {
+                        power.touch();
+                        let state = BoardState {
+                            layer: report.layer(),
+                            battery: battery_level.get(),
+                            latency,
+                        };
+                        let mut response = [0u8; PACKET_SIZE];
+                        let len = commands
+                            .process(&mut keys, state, &event.request, &mut response)
+                            .await;
+                        central.config_respond(event.target, &response[..len]).await;
+                        matrix.set_settle_time(commands.settings().settle_time());
+                        continue;
                     }
                 }
+                let layer = report.layer();
+                report.set_resolution(central.resolution());
                 let (key, mouse) = report.generate_report(&mut keys);
+                if keys.take_changed() {
+                    save_keymap(storage, &keys).await;
+                }
                 match key {
                     Some(rep) => {
                         central.keyboard_notify(rep).await;
+                        power.touch();
+                        latency.record(scanned);
                     }
                     _ => {}
                 };
                 match mouse {
                     Some(rep) => {
                         central.mouse_notify(rep).await;
+                        power.touch();
                     }
                     None => {}
                 }
-                Timer::after_micros(5).await;
+                if report.layer() != layer {
+                    central.layer_notify(report.layer() as u8).await;
+                }
+                Timer::after(power.scan_interval(commands.settings().scan_interval())).await;
             }
         };
 
-        let cen_server = central.connect();
+        let cen_server = central.connect(bonder);
         let pair_addr = Address::new(
             AddressType::RandomStatic,
             [0x66u8, 0x66u8, 0x66u8, 0x66u8, 0x66u8, 0b11111111u8],
         );
 
         let link_server = link.link(pair_addr);
-        let _res = select4(cen_server, link_server, main_loop, battery_loop).await;
+        // Follows the power state with the connection parameters and returns once
+        // the board should power down
+        let power_loop = async {
+            let mut state = PowerState::Active;
+            loop {
+                state = power.changed(state).await;
+                if state == PowerState::Off {
+                    return;
+                }
+                central.set_power_state(state);
+            }
+        };
+        let halves = select4(cen_server, link_server, main_loop, battery_loop);
+        // Bound first so the futures and their borrows are dropped before powering down
+        let result = select(halves, power_loop).await;
+        match result {
+            Either::First(_) => {}
+            Either::Second(_) => power_down(&mut central, &mut matrix).await,
+        }
     }
 }
@@ -5,22 +5,32 @@
 #![no_std]
 #![no_main]
 
+use core::cell::Cell;
 use core::sync::atomic::{AtomicBool, Ordering};
 
+use bruh78::bitmap::KeyBitmap;
 use bruh78::codes::KeyCodes;
+use bruh78::command::{BoardState, CommandHandler, PACKET_SIZE};
 use bruh78::config::load_callum;
-use bruh78::descriptor::{BufferReport, KeyboardReportNKRO};
+use bruh78::descriptor::{BufferReport, KeyboardReportNKRO, ViaReport};
+use bruh78::keymap::load_keymap;
 use bruh78::keys::Keys;
-use bruh78::matrix::Matrix;
+use bruh78::latency::Latency;
+use bruh78::matrix::{Matrix, LEFT_TRANSFORM};
 use bruh78::report::Report;
+use bruh78::scanner::KeyScanner;
+use bruh78::split::HALF_KEYS;
+use bruh78::storage::{BlockingFlash, Storage, NRF_FLASH_RANGE};
+use bruh78::via::Via;
 use cortex_m::delay::Delay;
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_futures::join::{self, join, join3, join4};
 use embassy_futures::yield_now;
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pin, Pull};
+use embassy_nrf::gpio::{Level, Output, OutputDrive, Pin};
 use embassy_nrf::gpiote::{Channel, InputChannel, InputChannelPolarity};
 use embassy_nrf::interrupt::{self, InterruptExt, Priority};
+use embassy_nrf::nvmc::Nvmc;
 use embassy_nrf::peripherals::USBD;
 use embassy_nrf::usb::vbus_detect::{HardwareVbusDetect, VbusDetect};
 use embassy_nrf::{bind_interrupts, peripherals, usb};
@@ -76,6 +86,7 @@ async fn main(_spawner: Spawner) {
 
     let mut key_state = State::new();
     let mut slave_state = State::new();
+    let mut via_state = State::new();
 
     let mut builder = Builder::new(
         driver,
@@ -98,63 +109,75 @@ async fn main(_spawner: Spawner) {
         poll_ms: 1,
         max_packet_size: 64,
     };
+    let via_config = embassy_usb::class::hid::Config {
+        report_descriptor: ViaReport::desc(),
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: 32,
+    };
 
     let mut key_writer = HidWriter::<_, 29>::new(&mut builder, &mut key_state, key_config);
-    let s_hid = HidReaderWriter::<_, 4, 1>::new(&mut builder, &mut slave_state, slave_config);
+    let s_hid = HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut slave_state, slave_config);
 
-    let (mut s_reader, _) = s_hid.split();
+    let (mut s_reader, mut s_writer) = s_hid.split();
+    let via_hid = HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut via_state, via_config);
+    let (mut via_reader, mut via_writer) = via_hid.split();
 
     builder.handler(&mut device_handler);
 
     let mut usb = builder.build();
     let usb_fut = usb.run();
     let mut columns = [
-        Output::new(p.P1_00.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_11.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_04.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_06.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_09.degrade(), Level::Low, OutputDrive::Standard),
+        p.P1_00.degrade(),
+        p.P0_11.degrade(),
+        p.P1_04.degrade(),
+        p.P1_06.degrade(),
+        p.P0_09.degrade(),
     ];
 
     let mut rows = [
-        Input::new(p.P0_02.degrade(), Pull::Down),
-        Input::new(p.P1_15.degrade(), Pull::Down),
-        Input::new(p.P1_11.degrade(), Pull::Down),
-        Input::new(p.P0_10.degrade(), Pull::Down),
+        p.P0_02.degrade(),
+        p.P1_15.degrade(),
+        p.P1_11.degrade(),
+        p.P0_10.degrade(),
     ];
 
+    let storage = Storage::init(BlockingFlash(Nvmc::new(p.NVMC)), NRF_FLASH_RANGE).await;
     let mut keys = Keys::<39>::default();
-    load_callum(&mut keys);
+    load_keymap(&storage, &mut keys, load_callum).await;
+    let mut commands = CommandHandler::new(&storage, None, load_callum).await;
+    commands.settings().apply(&mut keys);
+    let keys = Mutex::<CriticalSectionRawMutex, _>::new(keys);
+    let mut via = Via::new(&storage, load_callum).await;
     let mut report = Report::default();
+    let layer = Cell::new(0);
+    let latency = Cell::new(Latency::new());
 
     let mut matrix = Matrix::new(columns, rows);
+    matrix.set_transform(LEFT_TRANSFORM);
+    matrix.set_settle_time(commands.settings().settle_time());
     let main_loop = async {
         loop {
-            let mut states = [[false; 5]; 4];
-            matrix.scan(&mut states).await;
-            states[3][0] = states[3][2];
-            states[3][1] = states[3][3];
-            states[3][2] = states[3][4];
-            let iter = states.iter().flatten().into_iter();
-            let mut index = 0;
-            for state in iter {
-                keys.update_buf_central(index, *state);
-                index += 1;
-            }
-            let mut slave_buf = [0u8; 3];
+            let bitmap: KeyBitmap<HALF_KEYS> = matrix.scan_keys().await;
+            let scanned = Instant::now();
             let slave_keys = MUX.lock().await;
-            slave_buf = *slave_keys;
+            let slave_bitmap = KeyBitmap::<21>::from_le_bytes(&*slave_keys);
             drop(slave_keys);
-            for i in 0..21 {
-                let a_idx = (i / 8) as usize;
-                let b_idx = i % 8;
-                let val = (slave_buf[a_idx] >> b_idx) & 1;
-                keys.update_buf(i + 18, val != 0);
-            }
-            match report.generate_report(&mut keys) {
-                (Some(rep), _) => key_writer.write_serialize(rep).await.unwrap(),
+
+            let mut keys = keys.lock().await;
+            keys.update_bitmap(0, bitmap);
+            keys.update_bitmap(HALF_KEYS, slave_bitmap);
+            match report.generate_report(&mut *keys) {
+                (Some(rep), _) => {
+                    key_writer.write_serialize(rep).await.unwrap();
+                    let mut counters = latency.get();
+                    counters.record(scanned);
+                    latency.set(counters);
+                }
                 _ => {}
             }
+            layer.set(report.layer());
+            drop(keys);
 
             yield_now().await;
         }
@@ -170,11 +193,42 @@ async fn main(_spawner: Spawner) {
                 (*keys)[1] = buf[2];
                 (*keys)[2] = buf[3];
                 drop(keys);
+            } else {
+                // Every other report is a config command from the host
+                let state = BoardState {
+                    layer: layer.get(),
+                    battery: None,
+                    latency: latency.get(),
+                };
+                let mut response = [0u8; PACKET_SIZE];
+                commands
+                    .process(&mut *keys.lock().await, state, &buf, &mut response)
+                    .await;
+                if let Err(e) = s_writer.write(&response).await {
+                    warn!("Failed to send command response: {:?}", e);
+                }
             }
             yield_now().await;
         }
     };
 
+    let via_loop = async {
+        loop {
+            let mut buf = [0u8; 32];
+            match via_reader.read(&mut buf).await {
+                Ok(_) => {
+                    via.process(&mut *keys.lock().await, &mut buf).await;
+                    if let Err(e) = via_writer.write(&buf).await {
+                        warn!("Failed to send VIA response: {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to read VIA report: {:?}", e);
+                }
+            }
+        }
+    };
+
     let led_loop = async {
         let mut state = true;
         loop {
@@ -183,7 +237,13 @@ async fn main(_spawner: Spawner) {
             Timer::after_millis(2000).await;
         }
     };
-    join4(usb_fut, main_loop, led_loop, slave_loop).await;
+    join4(
+        usb_fut,
+        main_loop,
+        led_loop,
+        join3(slave_loop, via_loop, storage.run_storage()),
+    )
+    .await;
     // join(main_loop, led_loop).await;
 }
 
@@ -1,27 +1,47 @@
 #![no_std]
 #![no_main]
 
-use bruh78::cirque::{AbsoluteDataPacket, TrackPad};
-use bruh78::keys::{Keys, DEBOUNCE_TIME};
-use bruh78::matrix::Matrix;
+use bruh78::bitmap::KeyBitmap;
+use bruh78::cirque::{
+    AbsoluteDataPacket, Gesture, GestureConfig, Gestures, I2cBus, Rotation, TrackPad,
+    TrackPadConfig, TrackPadMode,
+};
+use bruh78::command::{BoardState, CommandHandler, TrackPadRequest, PACKET_SIZE};
+use bruh78::config::{trackpad_corners, TRACKPAD_CURVE, TRACKPAD_MODE};
+use bruh78::descriptor::{
+    BufferReport, MouseReport, MouseReport16, ResolutionMultiplier, MOUSE_DESCRIPTOR,
+};
+use bruh78::gesture::{Recognizer, RecognizerConfig, ScrollConfig, ScrollMode};
+use bruh78::keys::Keys;
+use bruh78::matrix::{Matrix, RIGHT_TRANSFORM};
+use bruh78::pointer::Accelerator;
+use bruh78::power::{system_off, PowerManager, PowerState, PowerTimeouts};
+use bruh78::report::Report;
+use bruh78::scanner::KeyScanner;
+use bruh78::settings::Settings;
+use bruh78::split::HALF_KEYS;
+use bruh78::storage::{BlockingFlash, Storage, NRF_FLASH_RANGE};
+use core::cell::Cell;
 use core::mem;
 use core::ptr::NonNull;
 use core::sync::atomic::{AtomicBool, Ordering};
 use defmt_rtt as _;
-use embassy_futures::join::{join, join3};
-use embassy_futures::select::select;
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pin, Pull};
+use embassy_futures::join::{join, join5};
+use embassy_futures::select::{select, Either};
+use embassy_nrf::gpio::{Level, Output, OutputDrive, Pin};
 use embassy_nrf::gpiote::{Channel, InputChannel, InputChannelPolarity};
 use embassy_nrf::interrupt::{self, InterruptExt, Priority};
+use embassy_nrf::nvmc::Nvmc;
 use embassy_nrf::twim::{Config as I2cConfig, Twim};
 use embassy_nrf::usb::vbus_detect::HardwareVbusDetect;
 use embassy_nrf::usb::{self, Driver};
 use embassy_nrf::{bind_interrupts, peripherals, twim};
-use embassy_time::{self, Duration, Instant, Timer};
-use embassy_usb::class::hid::{HidWriter, State};
+use embassy_time::{self, Delay, Duration, Instant, Timer};
+use embassy_usb::class::hid::{HidReaderWriter, HidWriter, ReportId, RequestHandler, State};
+use embassy_usb::control::OutResponse;
 use embassy_usb::{Builder, Handler};
 use nrf_softdevice::ble::gatt_server;
-use usbd_hid::descriptor::{MouseReport, SerializedDescriptor};
+use usbd_hid::descriptor::SerializedDescriptor;
 // time driver
 use panic_probe as _;
 
@@ -71,30 +91,68 @@ async fn main(spawner: Spawner) {
     let p = embassy_nrf::init(nrf_config);
     let mut led = Output::new(p.P0_15, Level::Low, OutputDrive::Standard);
 
-    let mut power = Output::new(p.P0_13, Level::High, OutputDrive::Standard);
+    // Powers the trackpad
+    let mut trackpad_power = Output::new(p.P0_13, Level::High, OutputDrive::Standard);
     Timer::after_millis(5).await;
     let mut iConfig = I2cConfig::default();
     iConfig.sda_pullup = true;
     iConfig.scl_pullup = true;
     let mut i2c = Twim::new(p.TWISPI0, Irqs, p.P0_24, p.P0_22, iConfig);
-    let mut trackpad = TrackPad::new(&mut i2c).await;
-    trackpad.sleep(true).await;
+    let mut trackpad = TrackPad::new(I2cBus::new(&mut i2c), Delay).await.ok();
+    if trackpad.is_none() {
+        error!("Trackpad setup failed, power cycling it");
+        trackpad_power.set_low();
+        Timer::after_millis(50).await;
+        trackpad_power.set_high();
+        Timer::after_millis(5).await;
+        trackpad = TrackPad::new(I2cBus::new(&mut i2c), Delay).await.ok();
+    }
+    let storage = Storage::init(BlockingFlash(Nvmc::new(p.NVMC)), NRF_FLASH_RANGE).await;
+    let settings = Settings::load(&storage).await;
+    // The trackpad is mounted rotated on this board
+    let trackpad_config = TrackPadConfig::load(&storage)
+        .await
+        .unwrap_or(TrackPadConfig {
+            rotation: Rotation::Deg90,
+            invert_x: true,
+            ..TrackPadConfig::default()
+        });
+    match trackpad.as_mut() {
+        Some(trackpad) => {
+            if trackpad.set_config(trackpad_config).await.is_err() {
+                error!("Failed to set the trackpad config");
+            }
+            if trackpad.sleep(true).await.is_err() {
+                error!("Trackpad sleep failed");
+            }
+        }
+        None => error!("Trackpad setup failed again, only the keys are scanned"),
+    }
+    // This half has no keymap, the commands only change the settings and the trackpad
+    let mut commands = CommandHandler::new(&storage, None, |_: &mut Keys<0>| {}).await;
+    if trackpad.is_some() {
+        commands.set_trackpad(trackpad_config);
+    }
+    // Passes the trackpad changes of the commands to the mouse loop
+    let trackpad_request = Cell::new(None);
     let mut columns = [
-        Output::new(p.P0_09.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_10.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_11.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_15.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_02.degrade(), Level::Low, OutputDrive::Standard),
+        p.P0_09.degrade(),
+        p.P0_10.degrade(),
+        p.P1_11.degrade(),
+        p.P1_15.degrade(),
+        p.P0_02.degrade(),
     ];
 
     let rows = [
-        Input::new(p.P1_00.degrade(), Pull::Down),
-        Input::new(p.P0_11.degrade(), Pull::Down),
-        Input::new(p.P1_04.degrade(), Pull::Down),
-        Input::new(p.P1_06.degrade(), Pull::Down),
+        p.P1_00.degrade(),
+        p.P0_11.degrade(),
+        p.P1_04.degrade(),
+        p.P1_06.degrade(),
     ];
 
     let mut matrix = Matrix::new(columns, rows);
+    matrix.set_transform(RIGHT_TRANSFORM);
+    matrix.set_settle_time(settings.settle_time());
 
     let driver = Driver::new(p.USBD, Irqs, HardwareVbusDetect::new(Irqs));
 
@@ -114,6 +172,11 @@ async fn main(spawner: Spawner) {
     let mut device_handler = MyDeviceHandler::new();
 
     let mut mouse_state = State::new();
+    let mut command_state = State::new();
+    let resolution = Cell::new(ResolutionMultiplier::default());
+    let mut mouse_handler = MouseRequestHandler {
+        resolution: &resolution,
+    };
 
     let mut builder = Builder::new(
         driver,
@@ -128,70 +191,189 @@ async fn main(spawner: Spawner) {
 
     // Create classes on the builder.
     let mouse_config = embassy_usb::class::hid::Config {
-        report_descriptor: MouseReport::desc(),
-        request_handler: None,
+        report_descriptor: &MOUSE_DESCRIPTOR,
+        request_handler: Some(&mut mouse_handler),
         poll_ms: 1,
-        max_packet_size: 5,
+        max_packet_size: 7,
     };
 
-    let mut mouse_writer = HidWriter::<_, 5>::new(&mut builder, &mut mouse_state, mouse_config);
+    let mut mouse_writer = HidWriter::<_, 7>::new(&mut builder, &mut mouse_state, mouse_config);
+    let command_config = embassy_usb::class::hid::Config {
+        report_descriptor: BufferReport::desc(),
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: 32,
+    };
+    let command_hid =
+        HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut command_state, command_config);
+    let (mut command_reader, mut command_writer) = command_hid.split();
 
     // Build the builder.
     let mut usb = builder.build();
     let usb_fut = usb.run();
+    let power = PowerManager::new(PowerTimeouts::default());
 
+    // This half's keys aren't sent anywhere, a press only keeps it awake
     let main_loop = async {
-        let mut key_state = 0u64;
-        let mut key_states = [Debouncer::default(); 18];
         Timer::after_secs(1).await;
         loop {
-            let mut current_state = 0u64;
-            let mut states = [[false; 5]; 4];
-            matrix.scan(&mut states).await;
-            let iter = states.iter().flatten().into_iter();
-            let mut index = 0;
-            for state in iter {
-                if index < 18 {
-                    key_states[index].update_buf(*state);
-                    index += 1;
-                }
+            let bitmap: KeyBitmap<HALF_KEYS> = matrix.scan_keys().await;
+            if !bitmap.is_empty() {
+                power.touch();
             }
-            for i in 0..key_states.len() {
-                if key_states[i].is_pressed() {
-                    current_state |= 1 << i;
-                } else {
-                    current_state &= !(1 << i);
-                }
-            }
-            if key_state != current_state {
-                key_state = current_state;
-            }
-            Timer::after_micros(5).await;
+            Timer::after(power.scan_interval(settings.scan_interval())).await;
         }
     };
     let mouse_loop = async {
+        let trackpad = match trackpad.as_mut() {
+            Some(trackpad) => trackpad,
+            None => return,
+        };
         Timer::after_secs(1).await;
+        let mut recognizer = Recognizer::new(RecognizerConfig::default());
+        let mut pointer = Accelerator::new(TRACKPAD_CURVE);
+        let gestures = *recognizer.config();
+        let features = trackpad
+            .set_relative_features(
+                gestures.tap_to_click || gestures.tap_drag,
+                gestures.two_finger_right_click,
+                gestures.two_finger_scroll,
+            )
+            .await;
+        if features.is_err() {
+            error!("Failed to set the trackpad gestures");
+        }
+        let mut absolute = Gestures::new(GestureConfig {
+            corners: trackpad_corners(),
+            ..GestureConfig::default()
+        });
+        // Presses the tapped corner codes. This half has no keys or layers of its
+        // own, so scrolling is only latched by tapping a TrackpadScroll corner
+        let mut taps = Report::default();
+        let mut scroll = ScrollMode::new(ScrollConfig::default());
+        let mut no_keys = Keys::<0>::default();
+        if TRACKPAD_MODE == TrackPadMode::Absolute && trackpad.set_absolute(true).await.is_err() {
+            error!("Failed to set the trackpad to absolute mode");
+        }
         let mut time = Instant::now();
         let def = 10;
         let mut sleep_time = def;
+        let mut asleep = false;
         loop {
-            let data = trackpad.get_relative().await;
-            match data {
-                Some((x, y, buttons)) => {
-                    log::info!("x: {}, y: {}", x, y);
-                    let rep = MouseReport {
-                        buttons,
-                        x: y,
-                        y: x,
-                        wheel: 0,
-                        pan: 0,
+            // Shuts the trackpad down in the Sleep state. Only key presses wake it
+            if power.state() >= PowerState::Sleep {
+                if !asleep {
+                    if trackpad.shutdown(true).await.is_err() {
+                        error!("Trackpad shutdown failed");
+                    }
+                    asleep = true;
+                }
+                Timer::after_millis(1000).await;
+                continue;
+            }
+            if asleep {
+                if trackpad.shutdown(false).await.is_err() {
+                    error!("Trackpad wake failed");
+                }
+                asleep = false;
+                time = Instant::now();
+                sleep_time = def;
+            }
+            match trackpad_request.take() {
+                Some(TrackPadRequest::SetConfig(config)) => {
+                    if trackpad.set_config(config).await.is_err() {
+                        error!("Failed to set the trackpad config");
+                    }
+                }
+                Some(TrackPadRequest::Recalibrate) => {
+                    if trackpad.recalibrate().await.is_err() {
+                        error!("Trackpad recalibration failed");
+                    }
+                }
+                None => {}
+            }
+            let now = Instant::now();
+            scroll.set_resolution(resolution.get());
+            let report = match TRACKPAD_MODE {
+                TrackPadMode::Relative => {
+                    let packet = match trackpad.get_relative_packet().await {
+                        Ok(packet) => packet,
+                        Err(_) => {
+                            error!("Trackpad read failed");
+                            None
+                        }
                     };
-                    let data = ((((y as u16) << 8) | x as u16) as u64) << 32;
-                    // match server.key_client.state_notify(&conn, &data) {
-                    //     Ok(_) => info!("report sent!"),
-                    //     Err(e) => error!("{:?}", e),
-                    // }
-                    mouse_writer.write_serialize(&rep).await;
+                    recognizer.set_resolution(resolution.get());
+                    recognizer.update(packet, now).map(|mut gesture| {
+                        match taps.scroll_active() {
+                            true => scroll.update(&mut gesture),
+                            false => scroll.reset(),
+                        }
+                        let (x, y) = pointer.apply(gesture.x as i16, gesture.y as i16, now);
+                        MouseReport16 {
+                            buttons: gesture.buttons,
+                            x,
+                            y,
+                            wheel: gesture.wheel,
+                            pan: gesture.pan,
+                        }
+                    })
+                }
+                TrackPadMode::Absolute => {
+                    let gesture = match trackpad.get_absolute().await {
+                        Ok(Some(packet)) => absolute.update(&packet, now),
+                        Ok(None) => Gesture::None,
+                        Err(_) => {
+                            error!("Trackpad read failed");
+                            Gesture::None
+                        }
+                    };
+                    if let Gesture::Tap(code) = gesture {
+                        taps.tap(code);
+                    }
+                    let (key, tapped) = taps.generate_report(&mut no_keys);
+                    if key.is_some() {
+                        warn!("Only mouse codes can be tapped on this half");
+                    }
+                    let tapped = tapped.copied();
+                    match gesture {
+                        Gesture::Move(x, y) => {
+                            let mut moved = MouseReport {
+                                x,
+                                y,
+                                ..MouseReport::default()
+                            };
+                            match taps.scroll_active() {
+                                true => scroll.update(&mut moved),
+                                false => scroll.reset(),
+                            }
+                            let (x, y) = pointer.apply(moved.x as i16, moved.y as i16, now);
+                            Some(MouseReport16 {
+                                x,
+                                y,
+                                wheel: moved.wheel,
+                                pan: moved.pan,
+                                ..tapped.unwrap_or_default()
+                            })
+                        }
+                        Gesture::Scroll(steps) => {
+                            // A step is one detent, which high resolution scrolling spreads out
+                            let wheel = steps as i16 * resolution.get().wheel as i16;
+                            Some(MouseReport16 {
+                                wheel: wheel.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+                                ..tapped.unwrap_or_default()
+                            })
+                        }
+                        _ => tapped,
+                    }
+                }
+            };
+            match report {
+                Some(rep) => {
+                    if mouse_writer.write(&rep.to_bytes()).await.is_err() {
+                        error!("Failed to send the mouse report");
+                    }
+                    power.touch();
                     time = Instant::now();
                     sleep_time = def;
                 }
@@ -205,43 +387,81 @@ async fn main(spawner: Spawner) {
             Timer::after_millis(sleep_time).await;
         }
     };
-    join(usb_fut, mouse_loop).await;
-    // mouse_loop.await;
+    let command_loop = async {
+        let mut keys = Keys::<0>::default();
+        loop {
+            let mut buf = [0u8; PACKET_SIZE];
+            match command_reader.read(&mut buf).await {
+                Ok(_) => {
+                    let mut response = [0u8; PACKET_SIZE];
+                    commands
+                        .process(&mut keys, BoardState::default(), &buf, &mut response)
+                        .await;
+                    if let Some(request) = commands.take_trackpad_request() {
+                        trackpad_request.set(Some(request));
+                    }
+                    if let Err(e) = command_writer.write(&response).await {
+                        warn!("Failed to send command response: {:?}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to read command: {:?}", e),
+            }
+        }
+    };
+    let result = select(
+        join5(
+            usb_fut,
+            main_loop,
+            mouse_loop,
+            command_loop,
+            storage.run_storage(),
+        ),
+        power.wait_for_off(),
+    )
+    .await;
+    match result {
+        Either::First(_) => {}
+        Either::Second(_) => {
+            info!("Inactive, powering down");
+            if let Some(trackpad) = trackpad.as_mut() {
+                if trackpad.shutdown(true).await.is_err() {
+                    error!("Trackpad shutdown failed");
+                }
+            }
+            // Pin levels are kept in System OFF, so the trackpad stays unpowered
+            // until the reset that follows the wakeup drives the pin high again
+            trackpad_power.set_low();
+            matrix.prepare_wakeup();
+            system_off();
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Debouncer {
-    state: bool,
-    debounced: Option<Instant>,
+/// Handles the Resolution Multiplier feature report of the mouse
+struct MouseRequestHandler<'a> {
+    resolution: &'a Cell<ResolutionMultiplier>,
 }
 
-impl Debouncer {
-    const fn default() -> Debouncer {
-        Self {
-            state: false,
-            debounced: None,
+impl RequestHandler for MouseRequestHandler<'_> {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::Feature(_) => {
+                buf[0] = self.resolution.get().to_feature();
+                Some(1)
+            }
+            _ => None,
         }
     }
-    /// Returns the pressed status of the position
-    fn is_pressed(&self) -> bool {
-        self.state
-    }
 
-    /// Updates the buf of the key. Updating the buf will also update
-    /// the value returned from the is_pressed function
-    fn update_buf(&mut self, buf: bool) {
-        match self.debounced {
-            Some(time) => {
-                if time.elapsed() > Duration::from_millis(DEBOUNCE_TIME) {
-                    self.debounced = None;
-                }
-            }
-            None => {
-                if buf != self.state {
-                    self.debounced = Some(Instant::now());
-                    self.state = buf;
-                }
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data.first()) {
+            (ReportId::Feature(_), Some(feature)) => {
+                info!("Resolution multiplier set: {}", feature);
+                self.resolution
+                    .set(ResolutionMultiplier::from_feature(*feature));
+                OutResponse::Accepted
             }
+            _ => OutResponse::Rejected,
         }
     }
 }
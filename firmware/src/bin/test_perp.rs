@@ -1,21 +1,26 @@
 #![no_std]
 #![no_main]
 
+use bruh78::bitmap::KeyBitmap;
 use bruh78::bond::Bonder;
-use bruh78::keys::{Keys, DEBOUNCE_TIME};
-use bruh78::matrix::Matrix;
+use bruh78::debounce::Debouncer;
+use bruh78::keys::Keys;
+use bruh78::matrix::{Matrix, RIGHT_TRANSFORM};
+use bruh78::scanner::KeyScanner;
+use bruh78::settings::Settings;
+use bruh78::split::HALF_KEYS;
 use bruh78::storage::{Storage, NRF_FLASH_RANGE};
 use core::mem;
 use core::ptr::NonNull;
 use defmt_rtt as _;
 use embassy_futures::join::join;
 use embassy_futures::select::{select, select3};
-use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pin, Pull};
+use embassy_nrf::gpio::{Level, Output, OutputDrive, Pin};
 use embassy_nrf::gpiote::{Channel, InputChannel, InputChannelPolarity};
 use embassy_nrf::interrupt::Priority;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_time::{self, Duration, Instant, Timer};
+use embassy_time::{self, Instant, Timer};
 use nrf_softdevice::ble::gatt_server;
 use static_cell::StaticCell;
 // time driver
@@ -131,21 +136,22 @@ async fn main(spawner: Spawner) {
     let mut led = Output::new(p.P0_15, Level::Low, OutputDrive::Standard);
 
     let mut columns = [
-        Output::new(p.P0_09.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_10.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_11.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P1_15.degrade(), Level::Low, OutputDrive::Standard),
-        Output::new(p.P0_02.degrade(), Level::Low, OutputDrive::Standard),
+        p.P0_09.degrade(),
+        p.P0_10.degrade(),
+        p.P1_11.degrade(),
+        p.P1_15.degrade(),
+        p.P0_02.degrade(),
     ];
 
     let rows = [
-        Input::new(p.P1_00.degrade(), Pull::Down),
-        Input::new(p.P0_11.degrade(), Pull::Down),
-        Input::new(p.P1_04.degrade(), Pull::Down),
-        Input::new(p.P1_06.degrade(), Pull::Down),
+        p.P1_00.degrade(),
+        p.P0_11.degrade(),
+        p.P1_04.degrade(),
+        p.P1_06.degrade(),
     ];
 
     let mut matrix = Matrix::new(columns, rows);
+    matrix.set_transform(RIGHT_TRANSFORM);
     let bonder: &'static Bonder<_> = BONDER.init(Bonder::init(&storage).await);
     loop {
         let config = peripheral::Config::default();
@@ -168,37 +174,25 @@ async fn main(spawner: Spawner) {
                 led.set_high();
             }
         }
-        let mut key_state = 0u32;
-        let mut key_states = [Debouncer::default(); 18];
+        let mut key_state = KeyBitmap::<HALF_KEYS>::new();
+        let mut debouncer = Debouncer::<HALF_KEYS>::new();
         let main_loop = async {
             Timer::after_secs(1).await;
             loop {
-                let mut current_state = 0u32;
-                let mut states = [[false; 5]; 4];
-                matrix.scan(&mut states).await;
-                let iter = states.iter().flatten().into_iter();
-                let mut index = 0;
-                for state in iter {
-                    if index < 18 {
-                        key_states[index].update_buf(*state);
-                        index += 1;
-                    }
-                }
-                for i in 0..key_states.len() {
-                    if key_states[i].is_pressed() {
-                        current_state |= 1 << i;
-                    } else {
-                        current_state &= !(1 << i);
-                    }
-                }
+                let bitmap: KeyBitmap<HALF_KEYS> = matrix.scan_keys().await;
+                debouncer.update_bitmap(0, bitmap, Instant::now());
+                let current_state = debouncer.pressed(0);
                 if key_state != current_state {
                     key_state = current_state;
-                    match server.key_client.state_notify(&conn, &key_state) {
+                    match server
+                        .key_client
+                        .state_notify(&conn, &(key_state.bits() as u32))
+                    {
                         Ok(_) => info!("report sent"),
                         Err(e) => error!("{:?}", e),
                     }
                 }
-                Timer::after_micros(5).await;
+                Timer::after(Settings::default().scan_interval()).await;
             }
         };
         let e = gatt_server::run(&conn, &server, |_| {});
@@ -206,40 +200,3 @@ async fn main(spawner: Spawner) {
         select3(e, main_loop, storage.run_storage()).await;
     }
 }
-
-#[derive(Copy, Clone, Debug)]
-struct Debouncer {
-    state: bool,
-    debounced: Option<Instant>,
-}
-
-impl Debouncer {
-    const fn default() -> Debouncer {
-        Self {
-            state: false,
-            debounced: None,
-        }
-    }
-    /// Returns the pressed status of the position
-    fn is_pressed(&self) -> bool {
-        self.state
-    }
-
-    /// Updates the buf of the key. Updating the buf will also update
-    /// the value returned from the is_pressed function
-    fn update_buf(&mut self, buf: bool) {
-        match self.debounced {
-            Some(time) => {
-                if time.elapsed() > Duration::from_millis(DEBOUNCE_TIME) {
-                    self.debounced = None;
-                }
-            }
-            None => {
-                if buf != self.state {
-                    self.debounced = Some(Instant::now());
-                    self.state = buf;
-                }
-            }
-        }
-    }
-}
@@ -0,0 +1,145 @@
+/// Pressed state of N keys, one bit per key with bit i for key i. Up to 64 keys
+/// are supported
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct KeyBitmap<const N: usize> {
+    bits: u64,
+}
+
+impl<const N: usize> KeyBitmap<N> {
+    // Keys past the 64th would be dropped without a bit to go in
+    const N_FITS: () = assert!(N <= 64, "KeyBitmap supports up to 64 keys");
+    const MASK: u64 = if N >= 64 { u64::MAX } else { (1 << N) - 1 };
+
+    /// Returns a bitmap with no keys pressed
+    pub const fn new() -> Self {
+        let () = Self::N_FITS;
+        Self { bits: 0 }
+    }
+
+    /// Returns a bitmap from raw bits. Bits past N are dropped
+    pub const fn from_bits(bits: u64) -> Self {
+        let () = Self::N_FITS;
+        Self {
+            bits: bits & Self::MASK,
+        }
+    }
+
+    /// Returns a bitmap from little endian bytes, such as the ones sent over the split link
+    pub fn from_le_bytes(bytes: &[u8]) -> Self {
+        let mut bits = 0;
+        for (i, byte) in bytes.iter().take(8).enumerate() {
+            bits |= (*byte as u64) << (i * 8);
+        }
+        Self::from_bits(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < N && (self.bits >> index) & 1 == 1
+    }
+
+    /// Sets the state of the indexed key. Indexes past N are ignored
+    pub fn set(&mut self, index: usize, pressed: bool) {
+        if index >= N {
+            return;
+        }
+        if pressed {
+            self.bits |= 1 << index;
+        } else {
+            self.bits &= !(1 << index);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Returns the keys whose state differs from the previous bitmap
+    pub fn changed(&self, previous: &Self) -> Self {
+        Self::from_bits(self.bits ^ previous.bits)
+    }
+
+    /// Returns the M keys starting at offset
+    pub fn slice<const M: usize>(&self, offset: usize) -> KeyBitmap<M> {
+        KeyBitmap::from_bits(self.bits.checked_shr(offset as u32).unwrap_or(0))
+    }
+
+    /// Replaces the keys starting at offset with the bitmap
+    pub fn set_slice<const M: usize>(&mut self, offset: usize, bitmap: KeyBitmap<M>) {
+        let mask = KeyBitmap::<M>::MASK.checked_shl(offset as u32).unwrap_or(0);
+        let bits = bitmap.bits.checked_shl(offset as u32).unwrap_or(0);
+        self.bits = ((self.bits & !mask) | bits) & Self::MASK;
+    }
+
+    /// Returns an iterator over the indexes of the pressed keys
+    pub fn iter(&self) -> Indexes {
+        Indexes { bits: self.bits }
+    }
+}
+
+/// Iterator over the set bits of a KeyBitmap, from the lowest index
+pub struct Indexes {
+    bits: u64,
+}
+
+impl Iterator for Indexes {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+        let index = self.bits.trailing_zeros() as usize;
+        self.bits &= self.bits - 1;
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_past_n_are_ignored() {
+        let mut bitmap = KeyBitmap::<3>::new();
+        bitmap.set(1, true);
+        bitmap.set(3, true);
+        assert!(bitmap.get(1));
+        assert!(!bitmap.get(3));
+        assert_eq!(bitmap.bits(), 0b10);
+        assert_eq!(KeyBitmap::<3>::from_bits(0b1110).bits(), 0b110);
+        bitmap.set(1, false);
+        assert!(bitmap.is_empty());
+    }
+
+    #[test]
+    fn changed_keys_are_the_difference() {
+        let previous = KeyBitmap::<8>::from_bits(0b0110);
+        let current = KeyBitmap::<8>::from_bits(0b1100);
+        let changed = current.changed(&previous);
+        assert_eq!(changed.iter().collect::<heapless::Vec<_, 8>>(), [1, 3]);
+        assert!(current.changed(&current).is_empty());
+    }
+
+    #[test]
+    fn halves_are_sliced_and_joined() {
+        let mut keys = KeyBitmap::<36>::new();
+        keys.set_slice(18, KeyBitmap::<18>::from_le_bytes(&[0x01, 0x00, 0x02]));
+        keys.set(0, true);
+        assert_eq!(keys.iter().collect::<heapless::Vec<_, 36>>(), [0, 18, 35]);
+        assert_eq!(keys.slice::<18>(18).bits(), 1 << 17 | 1);
+        assert_eq!(keys.slice::<18>(0).bits(), 1);
+    }
+
+    #[test]
+    fn all_64_keys_fit() {
+        let mut keys = KeyBitmap::<64>::new();
+        keys.set(63, true);
+        assert!(keys.get(63));
+        assert_eq!(keys.slice::<1>(63).bits(), 1);
+        assert_eq!(keys.slice::<1>(64).bits(), 0);
+    }
+}
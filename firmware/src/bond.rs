@@ -14,7 +14,7 @@ use nrf_softdevice::ble::{
 };
 use sequential_storage::map::Value;
 
-use crate::storage::{Storage, StorageItem};
+use crate::storage::{Storage, StorageItem, ITEM_BUFFER_SIZE};
 
 const PEER_SIZE: usize = mem::size_of::<Peer>();
 const MAX_NUM_BONDS: usize = 8;
@@ -171,7 +171,7 @@ impl<'a, S: NorFlash> Bonder<'a, S> {
             storage,
         };
 
-        let mut buffer = [0u8; 128];
+        let mut buffer = [0u8; ITEM_BUFFER_SIZE];
         let mut bonds = bonder.bonds.borrow_mut();
 
         for i in 0..(MAX_NUM_BONDS as u8) {
@@ -187,6 +187,29 @@ impl<'a, S: NorFlash> Bonder<'a, S> {
         drop(bonds);
         bonder
     }
+
+    /// Returns true if the peer of the passed in connection is bonded
+    pub fn is_bonded(&self, conn: &Connection) -> bool {
+        self.bonds
+            .borrow()
+            .values()
+            .any(|peer| peer.peer_id.is_match(conn.peer_address()))
+    }
+
+    /// Returns the storage index and address of every bonded peer
+    pub fn bonds(&self) -> Vec<(u8, Address), MAX_NUM_BONDS> {
+        let mut vec = Vec::new();
+        for (index, peer) in self.bonds.borrow().iter() {
+            vec.push((*index, peer.peer_id.addr)).unwrap();
+        }
+        vec
+    }
+
+    /// Forgets every bonded peer. The bonds still need to be removed from
+    /// the storage separately
+    pub fn clear(&self) {
+        self.bonds.borrow_mut().clear();
+    }
 }
 
 impl<'a, S: NorFlash> SecurityHandler for Bonder<'a, S> {
@@ -229,7 +252,8 @@ impl<'a, S: NorFlash> SecurityHandler for Bonder<'a, S> {
         };
 
         bonds.insert(index, val.clone()).unwrap();
-        self.storage.send_item(&bi(index), &StorageItem::Peer(val));
+        self.storage
+            .send_priority_item(&bi(index), &StorageItem::Peer(val));
     }
 
     fn get_key(&self, conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
@@ -255,7 +279,7 @@ impl<'a, S: NorFlash> SecurityHandler for Bonder<'a, S> {
 
     fn save_sys_attrs(&self, conn: &Connection) {
         info!("Save Sys Attrs");
-        let mut buffer = [0u8; 128];
+        let mut buffer = [0u8; ITEM_BUFFER_SIZE];
         let mut bonds = self.bonds.borrow_mut();
         let res = bonds.iter_mut().find_map(|(key, peer)| {
             if peer.peer_id.is_match(conn.peer_address()) {
@@ -267,7 +291,7 @@ impl<'a, S: NorFlash> SecurityHandler for Bonder<'a, S> {
                             peer.sys_attrs.clear();
                             peer.sys_attrs.extend_from_slice(&buffer[..len]).unwrap();
                             self.storage
-                                .send_item(&bi(*key), &StorageItem::Peer(peer.clone()));
+                                .send_priority_item(&bi(*key), &StorageItem::Peer(peer.clone()));
                         }
                     }
                     Err(err) => {
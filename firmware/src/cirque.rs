@@ -1,5 +1,19 @@
-use embassy_nrf::twim::{self, Instance, Twim};
-use embassy_time::Timer;
+use core::fmt::Debug;
+
+use defmt::{error, warn};
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::{
+    delay::DelayNs,
+    i2c::I2c,
+    spi::{Operation, SpiDevice},
+};
+use embedded_storage_async::nor_flash::NorFlash;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    keys::ScanCode,
+    storage::{Storage, StorageItem, ITEM_BUFFER_SIZE},
+};
 
 const SLAVE_ADDR: u8 = 0x2A;
 
@@ -7,105 +21,1200 @@ const WRITE_MASK: u8 = 0x80;
 const READ_MASK: u8 = 0xA0;
 
 const SYS_CONFIG1_ADDR: u8 = 0x03;
+// Stops sensing until the bit is cleared again
+const SYS_CONFIG1_SHUTDOWN: u8 = 1 << 1;
+// Lowers the sample rate after a while without touches
+const SYS_CONFIG1_SLEEP: u8 = 1 << 2;
 
 const FEED_CONFIG1_ADDR: u8 = 0x04;
+const FEED_CONFIG1_FEED_ENABLE: u8 = 1 << 0;
 const FEED_CONFIG1_RELATIVE: u8 = 0b11000001;
+const FEED_CONFIG1_ABSOLUTE: u8 = 0b00000011;
 
 const FEED_CONFIG2_ADDR: u8 = 0x05;
+// Adds the scroll wheel byte to relative packets
+const FEED_CONFIG2_INTELLIMOUSE: u8 = 1 << 0;
+const FEED_CONFIG2_TAPS_DISABLE: u8 = 1 << 1;
+const FEED_CONFIG2_SECONDARY_TAP_DISABLE: u8 = 1 << 2;
+const FEED_CONFIG2_SCROLL_DISABLE: u8 = 1 << 3;
+
+const CAL_CONFIG1_ADDR: u8 = 0x07;
+const CAL_CONFIG1_CALIBRATE: u8 = 1 << 0;
+
+const Z_IDLE_ADDR: u8 = 0x0A;
 
 const FLAGS: u8 = 0x02;
+const PACKET_BYTE_0: u8 = 0x12;
 
-#[derive(Debug, Default)]
+// Extended registers are accessed through these
+const ERA_VALUE_ADDR: u8 = 0x1B;
+const ERA_HIGH_BYTE_ADDR: u8 = 0x1C;
+const ERA_LOW_BYTE_ADDR: u8 = 0x1D;
+const ERA_CONTROL_ADDR: u8 = 0x1E;
+const ERA_CONTROL_READ: u8 = 0x01;
+const ERA_CONTROL_WRITE: u8 = 0x02;
+// Extended register with the ADC attenuation in its top two bits
+const TRACKPAD_ADC_CONFIG: u16 = 0x0187;
+const ADC_ATTENUATION_MASK: u8 = 0xC0;
+
+// Range of the absolute coordinates reported by the sensor
+pub const X_MIN: u16 = 128;
+pub const X_MAX: u16 = 1920;
+pub const Y_MIN: u16 = 64;
+pub const Y_MAX: u16 = 1472;
+
+// Fingers with a lower Z are hovering above the sensor instead of touching it
+const HOVER_Z: u16 = 8;
+
+#[derive(Copy, Clone, Debug, Default)]
 pub struct AbsoluteDataPacket {
     pub x: u16,
     pub y: u16,
-    z: u16,
-    button_flags: u8,
-    touch_down: bool,
-    hovering: bool,
+    pub z: u16,
+    pub button_flags: u8,
+    /// A finger is on or above the sensor
+    pub touch_down: bool,
+    /// The finger is above the sensor without touching it
+    pub hovering: bool,
+}
+
+impl AbsoluteDataPacket {
+    /// Decodes the 6 bytes read from PACKET_BYTE_0 in absolute mode
+    pub fn from_bytes(buf: &[u8; 6]) -> Self {
+        let x = buf[2] as u16 | ((buf[4] as u16 & 0x0F) << 8);
+        let y = buf[3] as u16 | ((buf[4] as u16 & 0xF0) << 4);
+        let z = buf[5] as u16 & 0x3F;
+        let touch_down = x != 0;
+        Self {
+            x,
+            y,
+            z,
+            button_flags: buf[0] & 0x3F,
+            touch_down,
+            hovering: touch_down && z < HOVER_Z,
+        }
+    }
+
+    /// Returns true if a finger is touching the sensor
+    pub fn is_touching(&self) -> bool {
+        self.touch_down && !self.hovering
+    }
+}
+
+/// Relative mode packet. The sensor reports taps as primary button presses,
+/// two finger taps as secondary button presses and two finger scrolling as
+/// wheel counts
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelativeDataPacket {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+}
+
+impl RelativeDataPacket {
+    /// Decodes the 4 bytes read from PACKET_BYTE_0 in relative mode
+    pub fn from_bytes(buf: &[u8; 4]) -> Self {
+        Self {
+            buttons: buf[0] & 0b111,
+            x: buf[1] as i8,
+            y: buf[2] as i8,
+            wheel: buf[3] as i8,
+        }
+    }
+
+    pub fn primary(&self) -> bool {
+        self.buttons & 0b001 != 0
+    }
+
+    pub fn secondary(&self) -> bool {
+        self.buttons & 0b010 != 0
+    }
+}
+
+/// Register access to the sensor over the bus it's wired to
+#[allow(async_fn_in_trait)]
+pub trait Bus {
+    type Error: Debug;
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), Self::Error>;
+
+    /// Reads buf.len() registers starting at register
+    async fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Sensor wired for I2C
+pub struct I2cBus<I> {
+    i2c: I,
+}
+
+impl<I: I2c> I2cBus<I> {
+    pub fn new(i2c: I) -> Self {
+        Self { i2c }
+    }
+}
+
+impl<I: I2c> Bus for I2cBus<I> {
+    type Error = I::Error;
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), Self::Error> {
+        self.i2c
+            .write(SLAVE_ADDR, &[WRITE_MASK | register, value])
+            .await
+    }
+
+    async fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.i2c
+            .write_read(SLAVE_ADDR, &[READ_MASK | register], buf)
+            .await
+    }
+}
+
+// Clocked out after a read command and while reading over SPI
+const SPI_FILLER: u8 = 0xFC;
+
+/// Sensor wired for SPI. The device has to use SPI mode 1
+pub struct SpiBus<S> {
+    spi: S,
+}
+
+impl<S: SpiDevice> SpiBus<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+}
+
+impl<S: SpiDevice> Bus for SpiBus<S> {
+    type Error = S::Error;
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), Self::Error> {
+        self.spi.write(&[WRITE_MASK | register, value]).await
+    }
+
+    async fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Self::Error> {
+        // The read command is followed by two filler bytes before the data
+        let command = [READ_MASK | register, SPI_FILLER, SPI_FILLER];
+        buf.fill(SPI_FILLER);
+        self.spi
+            .transaction(&mut [Operation::Write(&command), Operation::TransferInPlace(buf)])
+            .await
+    }
+}
+
+/// Bus errors in a row after which the sensor is reset
+const MAX_BUS_ERRORS: u8 = 3;
+/// Polls of a busy register before giving up
+const MAX_POLLS: u16 = 1000;
+
+/// Errors returned by the TrackPad
+#[derive(Debug)]
+pub enum TrackPadError<E> {
+    /// A bus transfer failed. After MAX_BUS_ERRORS of them in a row the sensor
+    /// is reset and set up again
+    Bus(E),
+    /// The sensor didn't finish an extended register access or a calibration
+    Timeout,
+}
+
+/// Rotation of the reported movement, clockwise
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    Deg0 = 0,
+    Deg90 = 1,
+    Deg180 = 2,
+    Deg270 = 3,
+}
+
+impl Rotation {
+    pub fn from_raw(rotation: u8) -> Option<Self> {
+        match rotation {
+            0 => Some(Rotation::Deg0),
+            1 => Some(Rotation::Deg90),
+            2 => Some(Rotation::Deg180),
+            3 => Some(Rotation::Deg270),
+            _ => None,
+        }
+    }
+}
+
+/// ADC attenuation of the sensor. Higher attenuation makes it less sensitive,
+/// for thicker overlays use a lower one
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Attenuation {
+    X1 = 0x00,
+    X2 = 0x40,
+    X3 = 0x80,
+    X4 = 0xC0,
+}
+
+impl Attenuation {
+    pub fn from_raw(attenuation: u8) -> Option<Self> {
+        match attenuation {
+            0x00 => Some(Attenuation::X1),
+            0x40 => Some(Attenuation::X2),
+            0x80 => Some(Attenuation::X3),
+            0xC0 => Some(Attenuation::X4),
+            _ => None,
+        }
+    }
+}
+
+pub const TRACKPAD_CONFIG_KEY: u32 = 0x400;
+const TRACKPAD_CONFIG_VERSION: u8 = 1;
+pub const TRACKPAD_CONFIG_SIZE: usize = 7;
+// Scale positions are rotated in
+const POSITION_SCALE: u32 = 1 << 12;
+
+/// Mounting and tuning of the trackpad. Stored in flash so it survives reflashing
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TrackPadConfig {
+    pub rotation: Rotation,
+    /// Inverts the axes after rotating
+    pub invert_x: bool,
+    pub invert_y: bool,
+    pub attenuation: Attenuation,
+    /// Pointer speed in percent
+    pub speed: u8,
+    /// Touches with a Z at or above this are palms and are dropped in
+    /// absolute mode. 0 turns palm rejection off
+    pub palm_z: u8,
+    /// Number of empty packets sent after a finger lifts. None keeps the
+    /// sensor default
+    pub z_idle: Option<u8>,
 }
 
-pub struct TrackPad<'a, 'b, T: Instance> {
-    i2c: &'a mut Twim<'b, T>,
+impl TrackPadConfig {
+    pub const fn default() -> Self {
+        Self {
+            rotation: Rotation::Deg0,
+            invert_x: false,
+            invert_y: false,
+            attenuation: Attenuation::X4,
+            speed: 100,
+            palm_z: 0,
+            z_idle: None,
+        }
+    }
+
+    /// Loads the config stored in flash, if there is one
+    pub async fn load<F: NorFlash>(storage: &Storage<F, u32>) -> Option<Self> {
+        let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+        storage
+            .get_item::<TrackPadConfig>(TRACKPAD_CONFIG_KEY, &mut buffer)
+            .await
+    }
+
+    /// Sends the config to the storage channel
+    pub async fn save<F: NorFlash>(&self, storage: &Storage<F, u32>) {
+        storage
+            .send_item(&TRACKPAD_CONFIG_KEY, &StorageItem::TrackPad(*self))
+            .await;
+    }
+
+    /// Rotates, inverts and scales relative movement
+    pub fn transform_delta(&self, x: i8, y: i8) -> (i8, i8) {
+        let (x, y) = (x as i16, y as i16);
+        let (mut x, mut y) = match self.rotation {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (-y, x),
+            Rotation::Deg180 => (-x, -y),
+            Rotation::Deg270 => (y, -x),
+        };
+        if self.invert_x {
+            x = -x;
+        }
+        if self.invert_y {
+            y = -y;
+        }
+        let scale =
+            |v: i16| (v * self.speed as i16 / 100).clamp(i8::MIN as i16, i8::MAX as i16) as i8;
+        (scale(x), scale(y))
+    }
+
+    /// Rotates and inverts an absolute position, keeping it in the sensor range
+    pub fn transform_position(&self, x: u16, y: u16) -> (u16, u16) {
+        let normalize = |v: u16, min: u16, max: u16| {
+            (v.clamp(min, max) - min) as u32 * POSITION_SCALE / (max - min) as u32
+        };
+        let (x, y) = (normalize(x, X_MIN, X_MAX), normalize(y, Y_MIN, Y_MAX));
+        let (mut x, mut y) = match self.rotation {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (POSITION_SCALE - y, x),
+            Rotation::Deg180 => (POSITION_SCALE - x, POSITION_SCALE - y),
+            Rotation::Deg270 => (y, POSITION_SCALE - x),
+        };
+        if self.invert_x {
+            x = POSITION_SCALE - x;
+        }
+        if self.invert_y {
+            y = POSITION_SCALE - y;
+        }
+        let restore =
+            |v: u32, min: u16, max: u16| min + (v * (max - min) as u32 / POSITION_SCALE) as u16;
+        (restore(x, X_MIN, X_MAX), restore(y, Y_MIN, Y_MAX))
+    }
+
+    /// Applies the palm rejection and the rotation to an absolute packet
+    pub fn transform_packet(&self, packet: &mut AbsoluteDataPacket) {
+        if self.palm_z != 0 && packet.z >= self.palm_z as u16 {
+            packet.touch_down = false;
+            packet.hovering = false;
+        }
+        if packet.touch_down {
+            (packet.x, packet.y) = self.transform_position(packet.x, packet.y);
+        }
+    }
+}
+
+impl<'a> Value<'a> for TrackPadConfig {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < TRACKPAD_CONFIG_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = TRACKPAD_CONFIG_VERSION;
+        buffer[1] = self.rotation as u8;
+        buffer[2] =
+            self.invert_x as u8 | (self.invert_y as u8) << 1 | (self.z_idle.is_some() as u8) << 2;
+        buffer[3] = self.attenuation as u8;
+        buffer[4] = self.speed;
+        buffer[5] = self.palm_z;
+        buffer[6] = self.z_idle.unwrap_or(0);
+        Ok(TRACKPAD_CONFIG_SIZE)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < TRACKPAD_CONFIG_SIZE || buffer[0] != TRACKPAD_CONFIG_VERSION {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let rotation = Rotation::from_raw(buffer[1]).ok_or(SerializationError::InvalidFormat)?;
+        let attenuation =
+            Attenuation::from_raw(buffer[3]).ok_or(SerializationError::InvalidFormat)?;
+        Ok(Self {
+            rotation,
+            invert_x: buffer[2] & 0b001 != 0,
+            invert_y: buffer[2] & 0b010 != 0,
+            attenuation,
+            speed: buffer[4],
+            palm_z: buffer[5],
+            z_idle: match buffer[2] & 0b100 != 0 {
+                true => Some(buffer[6]),
+                false => None,
+            },
+        })
+    }
+}
+
+pub struct TrackPad<B: Bus, D: DelayNs> {
+    bus: B,
+    delay: D,
     pub data: AbsoluteDataPacket,
+    config: TrackPadConfig,
+    // Config restored after a reset
+    feed_config1: u8,
+    feed_config2: u8,
+    sleeping: bool,
+    shut_down: bool,
+    errors: u8,
 }
 
-impl<'a, 'b, T: Instance> TrackPad<'a, 'b, T> {
-    pub async fn new(i2c: &'a mut Twim<'b, T>) -> Self {
+impl<B: Bus, D: DelayNs> TrackPad<B, D> {
+    /// Resets the sensor and sets it up in relative mode with the default config
+    pub async fn new(bus: B, delay: D) -> Result<Self, TrackPadError<B::Error>> {
         let mut dev = Self {
-            i2c,
+            bus,
+            delay,
             data: AbsoluteDataPacket::default(),
+            config: TrackPadConfig::default(),
+            feed_config1: FEED_CONFIG1_RELATIVE,
+            feed_config2: 0x0,
+            sleeping: false,
+            shut_down: false,
+            errors: 0,
         };
+        dev.init().await?;
+        Ok(dev)
+    }
 
-        let register = WRITE_MASK | SYS_CONFIG1_ADDR;
-        dev.i2c.write(SLAVE_ADDR, &[register, 0b001]).await.unwrap();
+    /// Resets the sensor and writes the current config
+    async fn init(&mut self) -> Result<(), TrackPadError<B::Error>> {
+        self.write_raw(SYS_CONFIG1_ADDR, 0b001).await?;
 
-        Timer::after_millis(50).await;
+        self.delay.delay_ms(50).await;
+
+        self.write_raw(SYS_CONFIG1_ADDR, 0b000).await?;
+        self.write_raw(FEED_CONFIG1_ADDR, self.feed_config1).await?;
+        self.write_raw(FEED_CONFIG2_ADDR, self.feed_config2).await?;
+        self.write_config().await?;
+        if self.sys_config1() != 0 {
+            self.write_raw(SYS_CONFIG1_ADDR, self.sys_config1()).await?;
+        }
+        Ok(())
+    }
 
-        let register = WRITE_MASK | SYS_CONFIG1_ADDR;
-        dev.i2c.write(SLAVE_ADDR, &[register, 0b000]).await.unwrap();
+    /// SYS_CONFIG1 value for the sleep and shutdown state
+    fn sys_config1(&self) -> u8 {
+        let mut config = 0;
+        if self.sleeping {
+            config |= SYS_CONFIG1_SLEEP;
+        }
+        if self.shut_down {
+            config |= SYS_CONFIG1_SHUTDOWN;
+        }
+        config
+    }
 
-        let register = WRITE_MASK | FEED_CONFIG1_ADDR;
-        dev.i2c
-            .write(SLAVE_ADDR, &[register, FEED_CONFIG1_RELATIVE])
+    /// Counts bus errors in a row and resets the sensor once there are
+    /// MAX_BUS_ERRORS of them
+    async fn check<T>(
+        &mut self,
+        result: Result<T, TrackPadError<B::Error>>,
+    ) -> Result<T, TrackPadError<B::Error>> {
+        match result {
+            Ok(value) => {
+                self.errors = 0;
+                Ok(value)
+            }
+            Err(e) => {
+                self.errors = self.errors.saturating_add(1);
+                if self.errors >= MAX_BUS_ERRORS {
+                    warn!(
+                        "Trackpad bus failed {} times in a row, resetting",
+                        self.errors
+                    );
+                    match self.init().await {
+                        Ok(_) => self.errors = 0,
+                        Err(_) => error!("Trackpad reset failed"),
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn write_raw(&mut self, register: u8, value: u8) -> Result<(), TrackPadError<B::Error>> {
+        self.bus
+            .write_register(register, value)
             .await
-            .unwrap();
+            .map_err(TrackPadError::Bus)
+    }
 
-        let register = WRITE_MASK | FEED_CONFIG2_ADDR;
-        dev.i2c.write(SLAVE_ADDR, &[register, 0x0]).await.unwrap();
+    async fn read_raw(
+        &mut self,
+        register: u8,
+        buf: &mut [u8],
+    ) -> Result<(), TrackPadError<B::Error>> {
+        self.bus
+            .read_registers(register, buf)
+            .await
+            .map_err(TrackPadError::Bus)
+    }
 
-        dev
+    async fn write(&mut self, register: u8, value: u8) -> Result<(), TrackPadError<B::Error>> {
+        let result = self.write_raw(register, value).await;
+        self.check(result).await
     }
 
-    pub async fn sleep(&mut self, input: bool) {
-        if input {
-            let register = WRITE_MASK | SYS_CONFIG1_ADDR;
-            self.i2c.write(SLAVE_ADDR, &[register, 0b100]).await;
-            // .unwrap();
-        } else {
-            let register = WRITE_MASK | SYS_CONFIG1_ADDR;
-            self.i2c.write(SLAVE_ADDR, &[register, 0b000]).await;
-            // .unwrap();
+    async fn read(&mut self, register: u8, buf: &mut [u8]) -> Result<(), TrackPadError<B::Error>> {
+        let result = self.read_raw(register, buf).await;
+        self.check(result).await
+    }
+
+    /// Waits until the register reads 0
+    async fn wait_cleared(
+        &mut self,
+        register: u8,
+        mask: u8,
+    ) -> Result<(), TrackPadError<B::Error>> {
+        for _ in 0..MAX_POLLS {
+            let mut buf = [0u8];
+            self.read_raw(register, &mut buf).await?;
+            if buf[0] & mask == 0 {
+                return Ok(());
+            }
+            self.delay.delay_us(100).await;
         }
+        Err(TrackPadError::Timeout)
     }
 
-    async fn clear_flags(&mut self) {
-        let register = WRITE_MASK | FLAGS;
-        self.i2c
-            .write(SLAVE_ADDR, &[register, 0x000])
+    /// Reads an extended register. The feed has to be disabled
+    async fn era_read(&mut self, address: u16) -> Result<u8, TrackPadError<B::Error>> {
+        let [high, low] = address.to_be_bytes();
+        self.write_raw(ERA_HIGH_BYTE_ADDR, high).await?;
+        self.write_raw(ERA_LOW_BYTE_ADDR, low).await?;
+        self.write_raw(ERA_CONTROL_ADDR, ERA_CONTROL_READ).await?;
+        self.wait_cleared(ERA_CONTROL_ADDR, 0xFF).await?;
+        let mut buf = [0u8];
+        self.read_raw(ERA_VALUE_ADDR, &mut buf).await?;
+        Ok(buf[0])
+    }
+
+    /// Writes an extended register. The feed has to be disabled
+    async fn era_write(&mut self, address: u16, value: u8) -> Result<(), TrackPadError<B::Error>> {
+        let [high, low] = address.to_be_bytes();
+        self.write_raw(ERA_VALUE_ADDR, value).await?;
+        self.write_raw(ERA_HIGH_BYTE_ADDR, high).await?;
+        self.write_raw(ERA_LOW_BYTE_ADDR, low).await?;
+        self.write_raw(ERA_CONTROL_ADDR, ERA_CONTROL_WRITE).await?;
+        self.wait_cleared(ERA_CONTROL_ADDR, 0xFF).await
+    }
+
+    /// Writes the sensor side of the config: the Z idle count and the ADC
+    /// attenuation, followed by a calibration for the new attenuation
+    async fn write_config(&mut self) -> Result<(), TrackPadError<B::Error>> {
+        match self.config.z_idle {
+            Some(count) => self.write_raw(Z_IDLE_ADDR, count).await?,
+            None => {}
+        }
+        self.write_raw(
+            FEED_CONFIG1_ADDR,
+            self.feed_config1 & !FEED_CONFIG1_FEED_ENABLE,
+        )
+        .await?;
+        let adc_config = self.era_read(TRACKPAD_ADC_CONFIG).await?;
+        let adc_config = (adc_config & !ADC_ATTENUATION_MASK) | self.config.attenuation as u8;
+        self.era_write(TRACKPAD_ADC_CONFIG, adc_config).await?;
+        self.calibrate().await?;
+        self.write_raw(FEED_CONFIG1_ADDR, self.feed_config1).await?;
+        self.write_raw(FLAGS, 0x000).await
+    }
+
+    /// Forces a calibration and waits for it to finish
+    async fn calibrate(&mut self) -> Result<(), TrackPadError<B::Error>> {
+        let mut buf = [0u8];
+        self.read_raw(CAL_CONFIG1_ADDR, &mut buf).await?;
+        self.write_raw(CAL_CONFIG1_ADDR, buf[0] | CAL_CONFIG1_CALIBRATE)
+            .await?;
+        self.wait_cleared(CAL_CONFIG1_ADDR, CAL_CONFIG1_CALIBRATE)
             .await
-            .unwrap();
-        Timer::after_micros(50).await;
     }
 
-    async fn data_ready(&mut self) -> bool {
-        let register = READ_MASK | FLAGS;
+    pub fn config(&self) -> &TrackPadConfig {
+        &self.config
+    }
+
+    /// Applies the config. Movement and positions are transformed in software,
+    /// the attenuation and Z idle count are written to the sensor
+    pub async fn set_config(
+        &mut self,
+        config: TrackPadConfig,
+    ) -> Result<(), TrackPadError<B::Error>> {
+        self.config = config;
+        let result = self.write_config().await;
+        self.check(result).await
+    }
+
+    /// Recalibrates the sensor, such as after the overlay or the
+    /// surroundings changed. Nothing may touch the sensor while it runs
+    pub async fn recalibrate(&mut self) -> Result<(), TrackPadError<B::Error>> {
+        let result = self.calibrate().await;
+        self.check(result).await?;
+        self.clear_flags().await
+    }
+
+    /// Switches between absolute and relative mode. The sensor starts in relative mode
+    pub async fn set_absolute(&mut self, absolute: bool) -> Result<(), TrackPadError<B::Error>> {
+        self.feed_config1 = match absolute {
+            true => FEED_CONFIG1_ABSOLUTE,
+            false => FEED_CONFIG1_RELATIVE,
+        };
+        self.write(FEED_CONFIG1_ADDR, self.feed_config1).await?;
+        self.clear_flags().await
+    }
+
+    /// Sets which gestures the sensor detects in relative mode
+    pub async fn set_relative_features(
+        &mut self,
+        taps: bool,
+        secondary_tap: bool,
+        scroll: bool,
+    ) -> Result<(), TrackPadError<B::Error>> {
+        let mut config = FEED_CONFIG2_INTELLIMOUSE;
+        if !taps {
+            config |= FEED_CONFIG2_TAPS_DISABLE;
+        }
+        if !secondary_tap {
+            config |= FEED_CONFIG2_SECONDARY_TAP_DISABLE;
+        }
+        if !scroll {
+            config |= FEED_CONFIG2_SCROLL_DISABLE;
+        }
+        self.feed_config2 = config;
+        self.write(FEED_CONFIG2_ADDR, config).await
+    }
+
+    /// Enables the sensor's automatic sleep, which lowers the sample rate after
+    /// a while without touches. The sensor still reports touches while asleep
+    pub async fn sleep(&mut self, input: bool) -> Result<(), TrackPadError<B::Error>> {
+        self.sleeping = input;
+        self.write(SYS_CONFIG1_ADDR, self.sys_config1()).await
+    }
+
+    /// Shuts the sensor down, so it stops sensing until it's woken with
+    /// shutdown(false). Waking keeps the automatic sleep as set by sleep
+    pub async fn shutdown(&mut self, input: bool) -> Result<(), TrackPadError<B::Error>> {
+        self.shut_down = input;
+        self.write(SYS_CONFIG1_ADDR, self.sys_config1()).await
+    }
+
+    async fn clear_flags(&mut self) -> Result<(), TrackPadError<B::Error>> {
+        self.write(FLAGS, 0x000).await?;
+        self.delay.delay_us(50).await;
+        Ok(())
+    }
+
+    async fn data_ready(&mut self) -> Result<bool, TrackPadError<B::Error>> {
         let mut buf = [0u8];
-        self.i2c.write_read(SLAVE_ADDR, &[register], &mut buf).await;
-        // .unwrap();
+        self.read(FLAGS, &mut buf).await?;
+        Ok(buf[0] & 0b1100 != 0)
+    }
 
-        if buf[0] & 0b1100 != 0 {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub async fn get_relative(&mut self) -> Option<((i8, i8, u8))> {
-        if self.data_ready().await {
-            let mut buf = [0u8; 4];
-            let register = READ_MASK | 0x12;
-            self.i2c
-                .write_read(SLAVE_ADDR, &[register], &mut buf)
-                .await
-                .unwrap();
-            let mut x = buf[1] as i8;
-            let mut y = buf[2] as i8;
-            let button = buf[0] & 1;
-            self.clear_flags().await;
-            Some((x, y, button))
+    /// Reads the N packet bytes if there's new data, and clears the data flags
+    async fn read_packet<const N: usize>(
+        &mut self,
+    ) -> Result<Option<[u8; N]>, TrackPadError<B::Error>> {
+        if !self.data_ready().await? {
+            return Ok(None);
+        }
+        let mut buf = [0u8; N];
+        self.read(PACKET_BYTE_0, &mut buf).await?;
+        self.clear_flags().await?;
+        Ok(Some(buf))
+    }
+
+    pub async fn get_relative(&mut self) -> Result<Option<(i8, i8, u8)>, TrackPadError<B::Error>> {
+        let packet = self.read_packet::<4>().await?;
+        Ok(packet.map(|buf| {
+            let (x, y) = self.config.transform_delta(buf[1] as i8, buf[2] as i8);
+            (x, y, buf[0] & 1)
+        }))
+    }
+
+    /// Returns the latest packet, in relative mode
+    pub async fn get_relative_packet(
+        &mut self,
+    ) -> Result<Option<RelativeDataPacket>, TrackPadError<B::Error>> {
+        let packet = self.read_packet::<4>().await?;
+        Ok(packet.map(|buf| {
+            let mut packet = RelativeDataPacket::from_bytes(&buf);
+            (packet.x, packet.y) = self.config.transform_delta(packet.x, packet.y);
+            packet
+        }))
+    }
+
+    /// Returns the latest packet, in absolute mode. The packet is also kept in data
+    pub async fn get_absolute(
+        &mut self,
+    ) -> Result<Option<AbsoluteDataPacket>, TrackPadError<B::Error>> {
+        match self.read_packet::<6>().await? {
+            Some(buf) => {
+                self.data = AbsoluteDataPacket::from_bytes(&buf);
+                self.config.transform_packet(&mut self.data);
+                Ok(Some(self.data))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// How the sensor's touches are read
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrackPadMode {
+    /// Movement with the taps and scrolling detected by the sensor. See gesture::Recognizer
+    Relative,
+    /// Positions, turned into movement, edge scrolling and corner taps by Gestures
+    Absolute,
+}
+
+/// What a touch on the sensor turned into
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    None,
+    /// Pointer movement
+    Move(i8, i8),
+    /// Scroll steps from dragging along the right edge. Positive scrolls up
+    Scroll(i8),
+    /// A short touch in a corner. The code is pressed and released once
+    Tap(ScanCode),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GestureConfig {
+    /// Width of the right edge that scrolls, in sensor units
+    pub edge_width: u16,
+    /// Distance dragged along the edge for each scroll step
+    pub scroll_step: u16,
+    /// Width and height of the corners that can be tapped
+    pub corner_size: u16,
+    /// Codes of the top left, top right, bottom left and bottom right corners.
+    /// ScanCode::None disables the corner
+    pub corners: [ScanCode; 4],
+    /// Longest touch that counts as a tap
+    pub tap_time: Duration,
+    /// Furthest a tap can move before it becomes a drag
+    pub tap_distance: u16,
+    /// Sensor units per pointer unit
+    pub divisor: i16,
+}
+
+impl GestureConfig {
+    pub const fn default() -> Self {
+        Self {
+            edge_width: 160,
+            scroll_step: 48,
+            corner_size: 240,
+            corners: [ScanCode::None; 4],
+            tap_time: Duration::from_millis(150),
+            tap_distance: 40,
+            divisor: 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Touch {
+    start: Instant,
+    start_x: u16,
+    start_y: u16,
+    x: u16,
+    y: u16,
+    scrolling: bool,
+    // Distance dragged along the edge that hasn't made a full scroll step yet
+    scroll_rest: i16,
+    moved: bool,
+}
+
+/// Turns absolute packets into pointer movement, edge scrolling and corner taps
+pub struct Gestures {
+    config: GestureConfig,
+    touch: Option<Touch>,
+}
+
+impl Gestures {
+    pub const fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            touch: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: GestureConfig) {
+        self.config = config;
+    }
+
+    /// Returns the corner index of the position, in the order of GestureConfig::corners
+    fn corner(&self, x: u16, y: u16) -> Option<usize> {
+        let size = self.config.corner_size;
+        let left = x <= X_MIN + size;
+        let right = x >= X_MAX - size;
+        let top = y <= Y_MIN + size;
+        let bottom = y >= Y_MAX - size;
+        match (top, bottom, left, right) {
+            (true, _, true, _) => Some(0),
+            (true, _, _, true) => Some(1),
+            (_, true, true, _) => Some(2),
+            (_, true, _, true) => Some(3),
+            _ => None,
+        }
+    }
+
+    /// Updates the touch with a packet read at the passed in time
+    pub fn update(&mut self, packet: &AbsoluteDataPacket, now: Instant) -> Gesture {
+        if !packet.is_touching() {
+            return match self.touch.take() {
+                Some(touch) => self.release(&touch, now),
+                None => Gesture::None,
+            };
+        }
+        let x = packet.x.clamp(X_MIN, X_MAX);
+        let y = packet.y.clamp(Y_MIN, Y_MAX);
+        let mut touch = match self.touch {
+            Some(touch) => touch,
+            None => {
+                self.touch = Some(Touch {
+                    start: now,
+                    start_x: x,
+                    start_y: y,
+                    x,
+                    y,
+                    scrolling: x >= X_MAX - self.config.edge_width,
+                    scroll_rest: 0,
+                    moved: false,
+                });
+                return Gesture::None;
+            }
+        };
+        let dx = x as i16 - touch.x as i16;
+        let dy = y as i16 - touch.y as i16;
+        touch.moved = touch.moved
+            || x.abs_diff(touch.start_x) > self.config.tap_distance
+            || y.abs_diff(touch.start_y) > self.config.tap_distance;
+        let gesture = if touch.scrolling {
+            // Dragging up, towards Y_MIN, scrolls up
+            touch.scroll_rest -= dy;
+            let step = self.config.scroll_step.max(1) as i16;
+            let steps = touch.scroll_rest / step;
+            touch.scroll_rest -= steps * step;
+            touch.x = x;
+            touch.y = y;
+            match steps {
+                0 => Gesture::None,
+                _ => Gesture::Scroll(steps.clamp(i8::MIN as i16, i8::MAX as i16) as i8),
+            }
         } else {
-            None
+            let divisor = self.config.divisor.max(1);
+            let mx = dx / divisor;
+            let my = dy / divisor;
+            // Keep the remainder for the next packet so slow movement isn't lost
+            touch.x = (x as i16 - dx % divisor) as u16;
+            touch.y = (y as i16 - dy % divisor) as u16;
+            match (mx, my) {
+                (0, 0) => Gesture::None,
+                _ => Gesture::Move(
+                    mx.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+                    my.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+                ),
+            }
+        };
+        self.touch = Some(touch);
+        gesture
+    }
+
+    fn release(&self, touch: &Touch, now: Instant) -> Gesture {
+        if touch.moved || now - touch.start > self.config.tap_time {
+            return Gesture::None;
+        }
+        match self.corner(touch.start_x, touch.start_y) {
+            Some(corner) => match self.config.corners[corner] {
+                ScanCode::None => Gesture::None,
+                code => Gesture::Tap(code),
+            },
+            None => Gesture::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        cell::{Cell, RefCell},
+        convert::Infallible,
+    };
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embedded_hal_async::{
+        delay::DelayNs,
+        spi::{ErrorType, Operation, SpiDevice},
+    };
+
+    use super::*;
+
+    /// Records register writes and serves reads from a register file. Shared
+    /// by reference so tests can change it while a TrackPad uses it
+    struct MockBus {
+        writes: RefCell<Vec<(u8, u8)>>,
+        registers: RefCell<[u8; 0x20]>,
+        // Number of reads that fail before they start working
+        failing_reads: Cell<usize>,
+    }
+
+    impl MockBus {
+        fn new() -> Self {
+            Self {
+                writes: RefCell::new(Vec::new()),
+                registers: RefCell::new([0; 0x20]),
+                failing_reads: Cell::new(0),
+            }
+        }
+
+        fn writes(&self) -> Vec<(u8, u8)> {
+            self.writes.borrow().clone()
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct MockError;
+
+    impl Bus for &MockBus {
+        type Error = MockError;
+
+        async fn write_register(&mut self, register: u8, value: u8) -> Result<(), MockError> {
+            self.writes.borrow_mut().push((register, value));
+            Ok(())
+        }
+
+        async fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), MockError> {
+            if self.failing_reads.get() > 0 {
+                self.failing_reads.set(self.failing_reads.get() - 1);
+                return Err(MockError);
+            }
+            let start = register as usize;
+            buf.copy_from_slice(&self.registers.borrow()[start..start + buf.len()]);
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        async fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    /// Records the bytes clocked out and clocks in 0x42
+    struct MockSpi {
+        sent: Vec<u8>,
+    }
+
+    impl ErrorType for MockSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for MockSpi {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Infallible> {
+            for operation in operations {
+                match operation {
+                    Operation::Write(buf) => self.sent.extend_from_slice(buf),
+                    Operation::TransferInPlace(buf) => {
+                        self.sent.extend_from_slice(buf);
+                        buf.fill(0x42);
+                    }
+                    _ => panic!("unexpected operation"),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Writes made by the init sequence with the default config in relative mode
+    const INIT_WRITES: [(u8, u8); 13] = [
+        (SYS_CONFIG1_ADDR, 0b001),
+        (SYS_CONFIG1_ADDR, 0b000),
+        (FEED_CONFIG1_ADDR, FEED_CONFIG1_RELATIVE),
+        (FEED_CONFIG2_ADDR, 0x0),
+        // The feed is disabled while the attenuation is set
+        (
+            FEED_CONFIG1_ADDR,
+            FEED_CONFIG1_RELATIVE & !FEED_CONFIG1_FEED_ENABLE,
+        ),
+        (ERA_HIGH_BYTE_ADDR, 0x01),
+        (ERA_LOW_BYTE_ADDR, 0x87),
+        (ERA_CONTROL_ADDR, ERA_CONTROL_READ),
+        (ERA_VALUE_ADDR, Attenuation::X4 as u8),
+        (ERA_HIGH_BYTE_ADDR, 0x01),
+        (ERA_LOW_BYTE_ADDR, 0x87),
+        (ERA_CONTROL_ADDR, ERA_CONTROL_WRITE),
+        (CAL_CONFIG1_ADDR, CAL_CONFIG1_CALIBRATE),
+    ];
+
+    #[test]
+    fn new_resets_and_sets_relative_mode() {
+        let bus = MockBus::new();
+        block_on(TrackPad::new(&bus, NoDelay)).unwrap();
+        let writes = bus.writes();
+        assert_eq!(writes[..INIT_WRITES.len()], INIT_WRITES);
+        assert_eq!(
+            writes[INIT_WRITES.len()..],
+            [(FEED_CONFIG1_ADDR, FEED_CONFIG1_RELATIVE), (FLAGS, 0)]
+        );
+    }
+
+    #[test]
+    fn failed_setup_is_returned() {
+        let bus = MockBus::new();
+        bus.failing_reads.set(1);
+        assert!(matches!(
+            block_on(TrackPad::new(&bus, NoDelay)),
+            Err(TrackPadError::Bus(MockError))
+        ));
+    }
+
+    #[test]
+    fn relative_features_set_the_disable_bits() {
+        let bus = MockBus::new();
+        let mut trackpad = block_on(TrackPad::new(&bus, NoDelay)).unwrap();
+        block_on(trackpad.set_relative_features(true, false, true)).unwrap();
+        assert_eq!(bus.writes().last(), Some(&(FEED_CONFIG2_ADDR, 0b0101)));
+    }
+
+    #[test]
+    fn waking_from_shutdown_keeps_the_automatic_sleep() {
+        let bus = MockBus::new();
+        let mut trackpad = block_on(TrackPad::new(&bus, NoDelay)).unwrap();
+        block_on(trackpad.sleep(true)).unwrap();
+        block_on(trackpad.shutdown(true)).unwrap();
+        block_on(trackpad.shutdown(false)).unwrap();
+        let writes = bus.writes();
+        assert_eq!(
+            writes[writes.len() - 3..],
+            [
+                (SYS_CONFIG1_ADDR, SYS_CONFIG1_SLEEP),
+                (SYS_CONFIG1_ADDR, SYS_CONFIG1_SLEEP | SYS_CONFIG1_SHUTDOWN),
+                (SYS_CONFIG1_ADDR, SYS_CONFIG1_SLEEP),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_packet_without_data_ready() {
+        let bus = MockBus::new();
+        let mut trackpad = block_on(TrackPad::new(&bus, NoDelay)).unwrap();
+        assert_eq!(block_on(trackpad.get_relative_packet()).unwrap(), None);
+    }
+
+    #[test]
+    fn relative_packet_is_decoded_and_flags_cleared() {
+        let bus = MockBus::new();
+        {
+            let mut registers = bus.registers.borrow_mut();
+            registers[FLAGS as usize] = 0b1100;
+            registers[PACKET_BYTE_0 as usize..PACKET_BYTE_0 as usize + 4]
+                .copy_from_slice(&[0b1011, 5, 0xFE, 0xFF]);
+        }
+        let mut trackpad = block_on(TrackPad::new(&bus, NoDelay)).unwrap();
+        let packet = block_on(trackpad.get_relative_packet()).unwrap().unwrap();
+        assert_eq!(
+            packet,
+            RelativeDataPacket {
+                buttons: 0b011,
+                x: 5,
+                y: -2,
+                wheel: -1,
+            }
+        );
+        assert!(packet.primary());
+        assert!(packet.secondary());
+        assert_eq!(bus.writes().last(), Some(&(FLAGS, 0)));
+    }
+
+    #[test]
+    fn repeated_bus_errors_reset_the_sensor() {
+        let bus = MockBus::new();
+        let mut trackpad = block_on(TrackPad::new(&bus, NoDelay)).unwrap();
+        block_on(trackpad.set_absolute(true)).unwrap();
+        let before = bus.writes().len();
+        bus.failing_reads.set(MAX_BUS_ERRORS as usize);
+        for _ in 0..MAX_BUS_ERRORS {
+            assert!(matches!(
+                block_on(trackpad.get_absolute()),
+                Err(TrackPadError::Bus(MockError))
+            ));
         }
+        assert!(block_on(trackpad.get_absolute()).unwrap().is_none());
+        // The reset restores absolute mode
+        let writes = bus.writes();
+        assert_eq!(
+            writes[before..before + 4],
+            [
+                (SYS_CONFIG1_ADDR, 0b001),
+                (SYS_CONFIG1_ADDR, 0b000),
+                (FEED_CONFIG1_ADDR, FEED_CONFIG1_ABSOLUTE),
+                (FEED_CONFIG2_ADDR, 0x0),
+            ]
+        );
+    }
+
+    #[test]
+    fn absolute_packet_is_decoded() {
+        // x = 0x3E8, y = 0x2BC, z = 0x25 with the high bits of z ignored
+        let packet = AbsoluteDataPacket::from_bytes(&[0x01, 0, 0xE8, 0xBC, 0x23, 0xE5]);
+        assert_eq!(packet.x, 1000);
+        assert_eq!(packet.y, 700);
+        assert_eq!(packet.z, 0x25);
+        assert_eq!(packet.button_flags, 0x01);
+        assert!(packet.touch_down);
+        assert!(!packet.hovering);
+        assert!(packet.is_touching());
+    }
+
+    #[test]
+    fn absolute_packet_with_low_z_is_hovering() {
+        let packet = AbsoluteDataPacket::from_bytes(&[0, 0, 0xE8, 0xBC, 0x23, 0x02]);
+        assert!(packet.hovering);
+        assert!(!packet.is_touching());
+        let packet = AbsoluteDataPacket::from_bytes(&[0; 6]);
+        assert!(!packet.touch_down);
+        assert!(!packet.hovering);
+    }
+
+    #[test]
+    fn spi_writes_and_reads_use_the_rap_framing() {
+        let mut bus = SpiBus::new(MockSpi { sent: Vec::new() });
+        block_on(bus.write_register(FEED_CONFIG1_ADDR, 0x03)).unwrap();
+        let mut buf = [0u8; 2];
+        block_on(bus.read_registers(PACKET_BYTE_0, &mut buf)).unwrap();
+        assert_eq!(buf, [0x42, 0x42]);
+        assert_eq!(
+            bus.spi.sent,
+            [
+                WRITE_MASK | FEED_CONFIG1_ADDR,
+                0x03,
+                READ_MASK | PACKET_BYTE_0,
+                SPI_FILLER,
+                SPI_FILLER,
+                SPI_FILLER,
+                SPI_FILLER,
+            ]
+        );
+    }
+
+    #[test]
+    fn rotation_and_inversion_transform_movement() {
+        let mut config = TrackPadConfig::default();
+        assert_eq!(config.transform_delta(3, -1), (3, -1));
+        config.rotation = Rotation::Deg90;
+        assert_eq!(config.transform_delta(3, -1), (1, 3));
+        // Rotating by 90 and inverting x swaps the axes
+        config.invert_x = true;
+        assert_eq!(config.transform_delta(3, -1), (-1, 3));
+        config.rotation = Rotation::Deg180;
+        config.invert_x = false;
+        config.speed = 200;
+        assert_eq!(config.transform_delta(100, -1), (-128, 2));
+    }
+
+    #[test]
+    fn rotated_positions_stay_in_range() {
+        let mut config = TrackPadConfig::default();
+        config.rotation = Rotation::Deg90;
+        assert_eq!(config.transform_position(X_MIN, Y_MIN), (X_MAX, Y_MIN));
+        assert_eq!(config.transform_position(X_MAX, Y_MAX), (X_MIN, Y_MAX));
+        config.rotation = Rotation::Deg180;
+        assert_eq!(config.transform_position(X_MIN, Y_MAX), (X_MAX, Y_MIN));
+    }
+
+    #[test]
+    fn palm_touches_are_dropped() {
+        let mut config = TrackPadConfig::default();
+        config.palm_z = 0x20;
+        let mut packet = AbsoluteDataPacket::from_bytes(&[0, 0, 0xE8, 0xBC, 0x23, 0x30]);
+        config.transform_packet(&mut packet);
+        assert!(!packet.touch_down);
+    }
+
+    #[test]
+    fn config_round_trips_through_flash() {
+        let config = TrackPadConfig {
+            rotation: Rotation::Deg270,
+            invert_x: false,
+            invert_y: true,
+            attenuation: Attenuation::X2,
+            speed: 150,
+            palm_z: 40,
+            z_idle: Some(5),
+        };
+        let mut buffer = [0u8; TRACKPAD_CONFIG_SIZE];
+        let len = config.serialize_into(&mut buffer).unwrap();
+        assert_eq!(
+            TrackPadConfig::deserialize_from(&buffer[..len]).unwrap(),
+            config
+        );
     }
 }
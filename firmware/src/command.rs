@@ -0,0 +1,315 @@
+//! Configuration command protocol shared by the GATT config service and the
+//! BufferReport HID interface. Requests are [id, args..] and responses are
+//! [id, status, data..], both at most PACKET_SIZE bytes. Id 0x05 is skipped
+//! since the wired builds use it on BufferReport to bridge the other half.
+//! Trackpad configs are sent in the format they're stored in flash.
+
+use defmt::{info, warn};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+use sequential_storage::map::Value;
+
+use crate::{
+    bond::Bonder,
+    cirque::{TrackPadConfig, TRACKPAD_CONFIG_SIZE},
+    keymap::{save_layer, KeyAction, ACTION_SIZE},
+    keys::{Keys, NUM_LAYERS},
+    latency::Latency,
+    settings::{SettingId, Settings},
+    storage::Storage,
+};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const PACKET_SIZE: usize = 32;
+// Space left after the id and status of a response
+const DATA_SIZE: usize = PACKET_SIZE - 2;
+// Number of bonds sent in one GetBonds response
+pub const BONDS_PER_PACKET: usize = 3;
+const BOND_ENTRY_SIZE: usize = 8;
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CommandId {
+    Version = 0x01,
+    GetKey = 0x02,
+    SetKey = 0x03,
+    GetLayer = 0x04,
+    GetBattery = 0x06,
+    GetBonds = 0x07,
+    FactoryReset = 0x08,
+    GetSetting = 0x09,
+    SetSetting = 0x0A,
+    GetLatency = 0x0B,
+    GetTrackPad = 0x0C,
+    SetTrackPad = 0x0D,
+    RecalibrateTrackPad = 0x0E,
+}
+
+impl CommandId {
+    pub fn from_raw(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(CommandId::Version),
+            0x02 => Some(CommandId::GetKey),
+            0x03 => Some(CommandId::SetKey),
+            0x04 => Some(CommandId::GetLayer),
+            0x06 => Some(CommandId::GetBattery),
+            0x07 => Some(CommandId::GetBonds),
+            0x08 => Some(CommandId::FactoryReset),
+            0x09 => Some(CommandId::GetSetting),
+            0x0A => Some(CommandId::SetSetting),
+            0x0B => Some(CommandId::GetLatency),
+            0x0C => Some(CommandId::GetTrackPad),
+            0x0D => Some(CommandId::SetTrackPad),
+            0x0E => Some(CommandId::RecalibrateTrackPad),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    InvalidArgument = 2,
+    Unsupported = 3,
+}
+
+/// State owned by the board loop that commands can read
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BoardState {
+    pub layer: usize,
+    pub battery: Option<u8>,
+    pub latency: Latency,
+}
+
+/// Trackpad change made by a command. The board that owns the trackpad applies it
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TrackPadRequest {
+    SetConfig(TrackPadConfig),
+    Recalibrate,
+}
+
+/// Processes configuration commands against the keys, the storage and the bonds
+pub struct CommandHandler<'a, F: NorFlash, const S: usize> {
+    storage: &'a Storage<F, u32>,
+    bonder: Option<&'a Bonder<'a, F>>,
+    default: fn(&mut Keys<S>),
+    settings: Settings,
+    // None on boards without a trackpad
+    trackpad: Option<TrackPadConfig>,
+    trackpad_request: Option<TrackPadRequest>,
+}
+
+impl<'a, F: NorFlash, const S: usize> CommandHandler<'a, F, S> {
+    /// Returns a CommandHandler with the settings loaded from storage. The passed
+    /// in default is the keymap loaded on a factory reset. Boards without bonds
+    /// pass None as the bonder
+    pub async fn new(
+        storage: &'a Storage<F, u32>,
+        bonder: Option<&'a Bonder<'a, F>>,
+        default: fn(&mut Keys<S>),
+    ) -> Self {
+        Self {
+            storage,
+            bonder,
+            default,
+            settings: Settings::load(storage).await,
+            trackpad: None,
+            trackpad_request: None,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Enables the trackpad commands on boards with a trackpad. The passed in
+    /// config is the one the trackpad was set up with
+    pub fn set_trackpad(&mut self, config: TrackPadConfig) {
+        self.trackpad = Some(config);
+    }
+
+    /// Returns the trackpad change requested since the last call
+    pub fn take_trackpad_request(&mut self) -> Option<TrackPadRequest> {
+        self.trackpad_request.take()
+    }
+
+    /// Processes the request and writes the response into the passed in buffer.
+    /// Returns the length of the response
+    pub async fn process(
+        &mut self,
+        keys: &mut Keys<S>,
+        state: BoardState,
+        request: &[u8],
+        response: &mut [u8; PACKET_SIZE],
+    ) -> usize {
+        response.fill(0);
+        if request.is_empty() {
+            return 0;
+        }
+        response[0] = request[0];
+        let args = &request[1..];
+        let mut data = Vec::<u8, DATA_SIZE>::new();
+        let status = match CommandId::from_raw(request[0]) {
+            Some(id) => self.run(id, keys, state, args, &mut data).await,
+            None => {
+                warn!("Unknown command {}", request[0]);
+                Status::UnknownCommand
+            }
+        };
+        response[1] = status as u8;
+        response[2..2 + data.len()].copy_from_slice(&data);
+        2 + data.len()
+    }
+
+    async fn run(
+        &mut self,
+        id: CommandId,
+        keys: &mut Keys<S>,
+        state: BoardState,
+        args: &[u8],
+        data: &mut Vec<u8, DATA_SIZE>,
+    ) -> Status {
+        match id {
+            CommandId::Version => {
+                data.extend_from_slice(&[PROTOCOL_VERSION, S as u8, NUM_LAYERS as u8])
+                    .unwrap();
+                Status::Ok
+            }
+            CommandId::GetKey => {
+                if args.len() < 2 || !Self::is_key(args[0], args[1]) {
+                    return Status::InvalidArgument;
+                }
+                let (layer, index) = (args[0] as usize, args[1] as usize);
+                data.extend_from_slice(&args[..2]).unwrap();
+                data.extend_from_slice(&keys.get_action(index, layer).to_bytes())
+                    .unwrap();
+                Status::Ok
+            }
+            CommandId::SetKey => {
+                if args.len() < 2 + ACTION_SIZE || !Self::is_key(args[0], args[1]) {
+                    return Status::InvalidArgument;
+                }
+                let (layer, index) = (args[0] as usize, args[1] as usize);
+                match KeyAction::from_bytes(&args[2..2 + ACTION_SIZE]) {
+                    Some(action) if action.is_valid::<S>() => {
+                        keys.set_action(action, index, layer);
+                        save_layer(self.storage, keys, layer).await;
+                        data.extend_from_slice(&args[..2]).unwrap();
+                        data.extend_from_slice(&action.to_bytes()).unwrap();
+                        Status::Ok
+                    }
+                    _ => Status::InvalidArgument,
+                }
+            }
+            CommandId::GetLayer => {
+                data.push(state.layer as u8).unwrap();
+                Status::Ok
+            }
+            CommandId::GetBattery => match state.battery {
+                Some(percentage) => {
+                    data.push(percentage).unwrap();
+                    Status::Ok
+                }
+                None => Status::Unsupported,
+            },
+            CommandId::GetBonds => {
+                let bonder = match self.bonder {
+                    Some(bonder) => bonder,
+                    None => return Status::Unsupported,
+                };
+                let start = args.first().copied().unwrap_or(0) as usize;
+                let bonds = bonder.bonds();
+                data.push(bonds.len() as u8).unwrap();
+                for (index, addr) in bonds.iter().skip(start).take(BONDS_PER_PACKET) {
+                    let mut entry = [0u8; BOND_ENTRY_SIZE];
+                    entry[0] = *index;
+                    entry[1] = addr.flags;
+                    entry[2..].copy_from_slice(&addr.bytes());
+                    data.extend_from_slice(&entry).unwrap();
+                }
+                Status::Ok
+            }
+            CommandId::FactoryReset => {
+                info!("Factory reset");
+                self.storage.clear().await;
+                if let Some(bonder) = self.bonder {
+                    bonder.clear();
+                }
+                self.settings = Settings::default();
+                // The default keymap resets the keys, so the settings are applied after it
+                (self.default)(keys);
+                self.settings.apply(keys);
+                Status::Ok
+            }
+            CommandId::GetSetting => match args.first().and_then(|id| SettingId::from_raw(*id)) {
+                Some(setting) => {
+                    data.push(setting as u8).unwrap();
+                    data.extend_from_slice(&self.settings.get(setting).to_le_bytes())
+                        .unwrap();
+                    Status::Ok
+                }
+                None => Status::InvalidArgument,
+            },
+            CommandId::SetSetting => {
+                if args.len() < 3 {
+                    return Status::InvalidArgument;
+                }
+                let value = u16::from_le_bytes([args[1], args[2]]);
+                match SettingId::from_raw(args[0]) {
+                    Some(setting) if self.settings.set(setting, value) => {
+                        self.settings.apply(keys);
+                        self.settings.save(self.storage).await;
+                        data.extend_from_slice(&args[..3]).unwrap();
+                        Status::Ok
+                    }
+                    _ => Status::InvalidArgument,
+                }
+            }
+            CommandId::GetLatency => {
+                data.extend_from_slice(&state.latency.to_bytes()).unwrap();
+                Status::Ok
+            }
+            CommandId::GetTrackPad => match self.trackpad {
+                Some(config) => {
+                    let mut buffer = [0u8; TRACKPAD_CONFIG_SIZE];
+                    let len = config.serialize_into(&mut buffer).unwrap();
+                    data.extend_from_slice(&buffer[..len]).unwrap();
+                    Status::Ok
+                }
+                None => Status::Unsupported,
+            },
+            CommandId::SetTrackPad => {
+                if self.trackpad.is_none() {
+                    return Status::Unsupported;
+                }
+                match TrackPadConfig::deserialize_from(args) {
+                    Ok(config) => {
+                        self.trackpad = Some(config);
+                        self.trackpad_request = Some(TrackPadRequest::SetConfig(config));
+                        config.save(self.storage).await;
+                        data.extend_from_slice(&args[..TRACKPAD_CONFIG_SIZE])
+                            .unwrap();
+                        Status::Ok
+                    }
+                    Err(_) => Status::InvalidArgument,
+                }
+            }
+            CommandId::RecalibrateTrackPad => match self.trackpad {
+                // A config change recalibrates too, so it isn't replaced
+                Some(_) => {
+                    if self.trackpad_request.is_none() {
+                        self.trackpad_request = Some(TrackPadRequest::Recalibrate);
+                    }
+                    Status::Ok
+                }
+                None => Status::Unsupported,
+            },
+        }
+    }
+
+    fn is_key(layer: u8, index: u8) -> bool {
+        (layer as usize) < NUM_LAYERS && (index as usize) < S
+    }
+}
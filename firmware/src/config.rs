@@ -1,8 +1,54 @@
 use embassy_time::Duration;
 
-use crate::{codes::KeyCodes, keys::Keys};
-const SCROLL_TIME: u64 = 500;
-const MOUSE_POINTER_TIME: u64 = 5;
+use crate::{
+    cirque::TrackPadMode,
+    codes::{scan_code, KeyCodes},
+    keys::{Keys, ScanCode},
+    pointer::{Curve, GAIN_ONE},
+};
+pub const SCROLL_TIME: u64 = 500;
+pub const MOUSE_POINTER_TIME: u64 = 5;
+
+/// Acceleration of trackpad movement
+pub const TRACKPAD_CURVE: Curve = Curve::Adaptive {
+    gain: GAIN_ONE,
+    threshold: 300,
+    accel: GAIN_ONE,
+    max: 3 * GAIN_ONE,
+};
+
+/// Acceleration of the mouse keys. The mouse keys ramp up their own speed
+/// while held, so the movement isn't accelerated further by default
+pub const MOUSE_KEY_CURVE: Curve = Curve::Linear { gain: GAIN_ONE };
+
+/// How the trackpad is read
+pub const TRACKPAD_MODE: TrackPadMode = TrackPadMode::Relative;
+
+/// Codes tapped by the top left, top right, bottom left and bottom right
+/// trackpad corners in absolute mode
+pub fn trackpad_corners() -> [ScanCode; 4] {
+    [
+        ScanCode::None,
+        ScanCode::None,
+        scan_code(KeyCodes::MouseMiddleClick),
+        scan_code(KeyCodes::MouseRightClick),
+    ]
+}
+
+/// Layer on which trackpad movement scrolls instead of moving the pointer.
+/// None only scrolls while the TrackpadScroll key has latched it
+pub const SCROLL_LAYER: Option<usize> = None;
+
+/// Acceleration used by the mouse and scroll interval keys
+pub fn mouse_acc(x: u64) -> u64 {
+    ((10000 * x.pow(2)) / (x.pow(2) + 50000)) + 1000
+}
+
+/// Layouts that can be loaded from a config key. The position of the
+/// layout in the array is the id the key is stored with in flash
+pub fn layouts<const S: usize>() -> [fn(&mut Keys<S>); 2] {
+    [load_callum, load_colemak]
+}
 
 pub fn load_callum<const S: usize>(keys: &mut Keys<S>) {
     *keys = Keys::<S>::default();
@@ -63,7 +109,7 @@ pub fn load_callum<const S: usize>(keys: &mut Keys<S>) {
     keys.set_code(KeyCodes::KeyboardLeftGUI, 8, 1);
     keys.set_code(KeyCodes::KeyboardVolumeDown, 9, 1);
 
-    let func = |x: u64| -> u64 { ((10000 * x.pow(2)) / (x.pow(2) + 50000)) + 1000 };
+    let func = mouse_acc;
     keys.set_interval(
         KeyCodes::MouseScrollDown,
         Duration::from_millis(SCROLL_TIME),
@@ -377,7 +423,7 @@ pub fn load_colemak<const S: usize>(keys: &mut Keys<S>) {
     keys.set_code(KeyCodes::KeyboardLeftGUI, 8, 1);
     keys.set_code(KeyCodes::KeyboardVolumeDown, 9, 1);
 
-    let func = |x: u64| -> u64 { ((10000 * x.pow(2)) / (x.pow(2) + 50000)) + 1000 };
+    let func = mouse_acc;
     keys.set_interval(
         KeyCodes::MouseScrollDown,
         Duration::from_millis(SCROLL_TIME),
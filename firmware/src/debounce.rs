@@ -0,0 +1,381 @@
+use core::ops::Range;
+
+use embassy_time::{Duration, Instant};
+
+use crate::bitmap::KeyBitmap;
+
+pub const DEBOUNCE_TIME: u64 = 6;
+
+fn elapsed(time: Instant, now: Instant) -> bool {
+    now.saturating_duration_since(time) > Duration::from_millis(DEBOUNCE_TIME)
+}
+
+/// Debounces the raw readings of N keys
+pub trait Debounce {
+    /// Updates the indexed key with a raw reading. Returns the debounced state
+    fn update(&mut self, index: usize, buf: bool, now: Instant) -> bool;
+
+    /// Returns the debounced state of the indexed key
+    fn is_pressed(&self, index: usize) -> bool;
+
+    /// Returns true while the indexed key waits on a timer, during which it
+    /// needs updates even if its reading doesn't change
+    fn is_settling(&self, index: usize) -> bool;
+}
+
+/// Reports a change as soon as it's read, then ignores the key for DEBOUNCE_TIME
+#[derive(Copy, Clone, Debug)]
+pub struct Eager<const N: usize> {
+    state: [bool; N],
+    debounced: [Option<Instant>; N],
+}
+
+impl<const N: usize> Eager<N> {
+    pub const fn new() -> Self {
+        Self {
+            state: [false; N],
+            debounced: [None; N],
+        }
+    }
+}
+
+impl<const N: usize> Debounce for Eager<N> {
+    fn update(&mut self, index: usize, buf: bool, now: Instant) -> bool {
+        match self.debounced[index] {
+            Some(time) if !elapsed(time, now) => {}
+            // The reading that ends the timer counts, since the key may not be
+            // updated again until its reading changes
+            _ => {
+                self.debounced[index] = None;
+                if buf != self.state[index] {
+                    self.debounced[index] = Some(now);
+                    self.state[index] = buf;
+                }
+            }
+        }
+        self.state[index]
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        self.state[index]
+    }
+
+    fn is_settling(&self, index: usize) -> bool {
+        self.debounced[index].is_some()
+    }
+}
+
+/// Reports a change once the key has read the same for DEBOUNCE_TIME
+#[derive(Copy, Clone, Debug)]
+pub struct Defer<const N: usize> {
+    state: [bool; N],
+    raw: [bool; N],
+    changed: [Option<Instant>; N],
+}
+
+impl<const N: usize> Defer<N> {
+    pub const fn new() -> Self {
+        Self {
+            state: [false; N],
+            raw: [false; N],
+            changed: [None; N],
+        }
+    }
+}
+
+impl<const N: usize> Debounce for Defer<N> {
+    fn update(&mut self, index: usize, buf: bool, now: Instant) -> bool {
+        if buf != self.raw[index] {
+            self.raw[index] = buf;
+            self.changed[index] = Some(now);
+        }
+        match self.changed[index] {
+            Some(time) if elapsed(time, now) => {
+                self.state[index] = self.raw[index];
+                self.changed[index] = None;
+            }
+            _ => {}
+        }
+        self.state[index]
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        self.state[index]
+    }
+
+    fn is_settling(&self, index: usize) -> bool {
+        self.changed[index].is_some()
+    }
+}
+
+/// Reports the changes of a row once every key of the row has read the same
+/// for DEBOUNCE_TIME. Rows are set with set_rows
+#[derive(Copy, Clone, Debug)]
+pub struct SymDeferRow<const N: usize> {
+    state: [bool; N],
+    raw: [bool; N],
+    // Index of the first key in the row of each key. Row timers are stored at that index
+    row: [u8; N],
+    changed: [Option<Instant>; N],
+}
+
+impl<const N: usize> SymDeferRow<N> {
+    /// Returns a SymDeferRow where every key is its own row
+    pub const fn new() -> Self {
+        let mut row = [0u8; N];
+        let mut i = 0;
+        while i < N {
+            row[i] = i as u8;
+            i += 1;
+        }
+        Self {
+            state: [false; N],
+            raw: [false; N],
+            row,
+            changed: [None; N],
+        }
+    }
+
+    /// Splits the range into rows of row_len keys, starting at the start of the range
+    pub fn set_rows(&mut self, range: Range<usize>, row_len: usize) {
+        for i in range.clone() {
+            self.row[i] = (range.start + (i - range.start) / row_len * row_len) as u8;
+        }
+    }
+}
+
+impl<const N: usize> Debounce for SymDeferRow<N> {
+    fn update(&mut self, index: usize, buf: bool, now: Instant) -> bool {
+        let row = self.row[index] as usize;
+        if buf != self.raw[index] {
+            self.raw[index] = buf;
+            self.changed[row] = Some(now);
+        }
+        match self.changed[row] {
+            Some(time) if elapsed(time, now) => {
+                for i in 0..N {
+                    if self.row[i] as usize == row {
+                        self.state[i] = self.raw[i];
+                    }
+                }
+                self.changed[row] = None;
+            }
+            _ => {}
+        }
+        self.state[index]
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        self.state[index]
+    }
+
+    fn is_settling(&self, index: usize) -> bool {
+        self.changed[self.row[index] as usize].is_some()
+    }
+}
+
+/// Debounce algorithm of a key
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Off,
+    Eager,
+    Defer,
+    SymDeferRow,
+}
+
+/// Debounces each key with the algorithm selected for its range. Every key
+/// starts out with the eager algorithm
+#[derive(Copy, Clone, Debug)]
+pub struct Debouncer<const N: usize> {
+    algorithms: [Algorithm; N],
+    // Last reading of every key
+    raw: KeyBitmap<N>,
+    off: [bool; N],
+    eager: Eager<N>,
+    defer: Defer<N>,
+    sym_defer_row: SymDeferRow<N>,
+}
+
+impl<const N: usize> Debouncer<N> {
+    pub const fn new() -> Self {
+        Self {
+            algorithms: [Algorithm::Eager; N],
+            raw: KeyBitmap::new(),
+            off: [false; N],
+            eager: Eager::new(),
+            defer: Defer::new(),
+            sym_defer_row: SymDeferRow::new(),
+        }
+    }
+
+    /// Sets the algorithm of the keys in the range. Use set_rows for
+    /// SymDeferRow, which also needs the row length
+    pub fn set_algorithm(&mut self, range: Range<usize>, algorithm: Algorithm) {
+        for i in range {
+            self.algorithms[i] = algorithm;
+        }
+    }
+
+    /// Debounces the range with SymDeferRow using rows of row_len keys
+    pub fn set_rows(&mut self, range: Range<usize>, row_len: usize) {
+        self.set_algorithm(range.clone(), Algorithm::SymDeferRow);
+        self.sym_defer_row.set_rows(range, row_len);
+    }
+
+    pub fn algorithm(&self, index: usize) -> Algorithm {
+        self.algorithms[index]
+    }
+
+    /// Updates the M keys starting at offset from a scan. Only keys whose reading
+    /// changed or that are still settling are updated
+    pub fn update_bitmap<const M: usize>(
+        &mut self,
+        offset: usize,
+        bitmap: KeyBitmap<M>,
+        now: Instant,
+    ) {
+        let changed = bitmap.changed(&self.raw.slice(offset));
+        for i in 0..M {
+            let index = offset + i;
+            if changed.get(i) || self.is_settling(index) {
+                self.update(index, bitmap.get(i), now);
+            }
+        }
+    }
+
+    /// Returns the debounced state of the M keys starting at offset
+    pub fn pressed<const M: usize>(&self, offset: usize) -> KeyBitmap<M> {
+        let mut bitmap = KeyBitmap::new();
+        for i in 0..M {
+            bitmap.set(i, self.is_pressed(offset + i));
+        }
+        bitmap
+    }
+}
+
+impl<const N: usize> Debounce for Debouncer<N> {
+    fn update(&mut self, index: usize, buf: bool, now: Instant) -> bool {
+        self.raw.set(index, buf);
+        match self.algorithms[index] {
+            Algorithm::Off => {
+                self.off[index] = buf;
+                buf
+            }
+            Algorithm::Eager => self.eager.update(index, buf, now),
+            Algorithm::Defer => self.defer.update(index, buf, now),
+            Algorithm::SymDeferRow => self.sym_defer_row.update(index, buf, now),
+        }
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        match self.algorithms[index] {
+            Algorithm::Off => self.off[index],
+            Algorithm::Eager => self.eager.is_pressed(index),
+            Algorithm::Defer => self.defer.is_pressed(index),
+            Algorithm::SymDeferRow => self.sym_defer_row.is_pressed(index),
+        }
+    }
+
+    fn is_settling(&self, index: usize) -> bool {
+        match self.algorithms[index] {
+            Algorithm::Off => false,
+            Algorithm::Eager => self.eager.is_settling(index),
+            Algorithm::Defer => self.defer.is_settling(index),
+            Algorithm::SymDeferRow => self.sym_defer_row.is_settling(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(1000 + ms)
+    }
+
+    #[test]
+    fn eager_reports_changes_right_away() {
+        let mut eager = Eager::<1>::new();
+        assert!(eager.update(0, true, at(0)));
+        assert!(eager.is_settling(0));
+        // The release after the timer is reported as soon as it's read
+        assert!(!eager.update(0, false, at(DEBOUNCE_TIME + 1)));
+    }
+
+    #[test]
+    fn eager_ignores_bounce() {
+        let mut eager = Eager::<1>::new();
+        eager.update(0, true, at(0));
+        assert!(eager.update(0, false, at(1)));
+        assert!(eager.update(0, true, at(2)));
+        // The reading that ends the timer counts
+        assert!(!eager.update(0, false, at(DEBOUNCE_TIME + 1)));
+        assert!(!eager.update(0, true, at(DEBOUNCE_TIME + 2)));
+    }
+
+    #[test]
+    fn defer_reports_changes_once_settled() {
+        let mut defer = Defer::<1>::new();
+        assert!(!defer.update(0, true, at(0)));
+        assert!(!defer.update(0, true, at(DEBOUNCE_TIME)));
+        assert!(defer.update(0, true, at(DEBOUNCE_TIME + 1)));
+        assert!(!defer.is_settling(0));
+        assert!(defer.update(0, false, at(10)));
+        assert!(defer.is_settling(0));
+        assert!(!defer.update(0, false, at(11 + DEBOUNCE_TIME)));
+    }
+
+    #[test]
+    fn defer_restarts_on_bounce() {
+        let mut defer = Defer::<1>::new();
+        defer.update(0, true, at(0));
+        defer.update(0, false, at(2));
+        defer.update(0, true, at(4));
+        assert!(!defer.update(0, true, at(DEBOUNCE_TIME + 1)));
+        assert!(defer.update(0, true, at(DEBOUNCE_TIME + 5)));
+    }
+
+    #[test]
+    fn sym_defer_row_reports_a_row_once_every_key_settled() {
+        let mut sym = SymDeferRow::<4>::new();
+        sym.set_rows(0..4, 2);
+        sym.update(0, true, at(0));
+        sym.update(2, true, at(0));
+        // Key 1 restarts the timer of its row, but not of the other row
+        sym.update(1, true, at(4));
+        assert!(!sym.update(0, true, at(DEBOUNCE_TIME + 1)));
+        assert!(sym.update(2, true, at(DEBOUNCE_TIME + 1)));
+        assert!(sym.update(0, true, at(DEBOUNCE_TIME + 5)));
+        assert!(sym.is_pressed(1));
+        assert!(!sym.is_settling(1));
+    }
+
+    #[test]
+    fn sym_defer_row_releases_like_it_presses() {
+        let mut sym = SymDeferRow::<2>::new();
+        sym.set_rows(0..2, 2);
+        sym.update(0, true, at(0));
+        sym.update(1, true, at(0));
+        sym.update(0, true, at(DEBOUNCE_TIME + 1));
+        sym.update(0, false, at(10));
+        sym.update(1, false, at(11));
+        sym.update(0, true, at(12));
+        assert!(sym.update(0, true, at(12 + DEBOUNCE_TIME)));
+        assert!(!sym.update(1, false, at(13 + DEBOUNCE_TIME)));
+        assert!(sym.is_pressed(0));
+    }
+
+    #[test]
+    fn settling_keys_are_updated_without_changes() {
+        let mut debouncer = Debouncer::<2>::new();
+        debouncer.set_algorithm(0..2, Algorithm::Defer);
+        let mut bitmap = KeyBitmap::<2>::new();
+        bitmap.set(1, true);
+        debouncer.update_bitmap(0, bitmap, at(0));
+        assert!(!debouncer.pressed::<2>(0).get(1));
+        debouncer.update_bitmap(0, bitmap, at(DEBOUNCE_TIME + 1));
+        assert!(debouncer.pressed::<2>(0).get(1));
+        assert!(!debouncer.pressed::<2>(0).get(0));
+    }
+}
@@ -81,6 +81,213 @@ pub struct MouseReport {
     pub pan: i8,   // Scroll left (negative) or right (positive) this many units
 }
 
+/// Mouse report with 16 bit movement, sent with MOUSE_DESCRIPTOR or
+/// COMBINED_DESCRIPTOR. Boot protocol hosts get the 8 bit to_boot report
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub struct MouseReport16 {
+    pub buttons: u8,
+    pub x: i16,
+    pub y: i16,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport16 {
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let [x0, x1] = self.x.to_le_bytes();
+        let [y0, y1] = self.y.to_le_bytes();
+        [
+            self.buttons,
+            x0,
+            x1,
+            y0,
+            y1,
+            self.wheel as u8,
+            self.pan as u8,
+        ]
+    }
+
+    /// Returns the boot protocol report: the buttons and the movement
+    /// saturated to 8 bits
+    pub fn to_boot(&self) -> [u8; 3] {
+        let saturate = |v: i16| v.clamp(i8::MIN as i16, i8::MAX as i16) as i8 as u8;
+        [self.buttons, saturate(self.x), saturate(self.y)]
+    }
+}
+
+impl From<&MouseReport> for MouseReport16 {
+    fn from(report: &MouseReport) -> Self {
+        Self {
+            buttons: report.buttons,
+            x: report.x as i16,
+            y: report.y as i16,
+            wheel: report.wheel,
+            pan: report.pan,
+        }
+    }
+}
+
+/// Wheel or pan units per detent once the host enables the Resolution Multiplier
+pub const RESOLUTION_MULTIPLIER: u8 = 16;
+
+/// Resolution multipliers the host set through the feature report of the
+/// mouse descriptors. Hosts that don't support them leave them at 1
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ResolutionMultiplier {
+    pub wheel: u8,
+    pub pan: u8,
+}
+
+impl ResolutionMultiplier {
+    pub const fn default() -> Self {
+        Self { wheel: 1, pan: 1 }
+    }
+
+    /// Parses the feature report. Bits 0-1 select the wheel multiplier and
+    /// bits 2-3 the pan multiplier, where 1 is RESOLUTION_MULTIPLIER
+    pub fn from_feature(feature: u8) -> Self {
+        let multiplier = |bits: u8| match bits & 0b11 {
+            0 => 1,
+            _ => RESOLUTION_MULTIPLIER,
+        };
+        Self {
+            wheel: multiplier(feature),
+            pan: multiplier(feature >> 2),
+        }
+    }
+
+    pub fn to_feature(&self) -> u8 {
+        (self.wheel > 1) as u8 | ((self.pan > 1) as u8) << 2
+    }
+}
+
+/// Mouse collection with the Resolution Multiplier feature report. The input
+/// report is the same as MouseReport16. Written out by hand since
+/// gen_hid_descriptor can't describe logical collections or physical ranges
+macro_rules! mouse_collection {
+    ($($report_id:expr)?) => {
+        [
+            0x05, 0x01, // Usage Page (Generic Desktop)
+            0x09, 0x02, // Usage (Mouse)
+            0xA1, 0x01, // Collection (Application)
+            0x09, 0x01, //   Usage (Pointer)
+            0xA1, 0x00, //   Collection (Physical)
+            $(0x85, $report_id,)? //     Report ID
+            0x05, 0x09, //     Usage Page (Button)
+            0x19, 0x01, //     Usage Minimum (Button 1)
+            0x29, 0x08, //     Usage Maximum (Button 8)
+            0x15, 0x00, //     Logical Minimum (0)
+            0x25, 0x01, //     Logical Maximum (1)
+            0x75, 0x01, //     Report Size (1)
+            0x95, 0x08, //     Report Count (8)
+            0x81, 0x02, //     Input (Data, Variable, Absolute)
+            0x05, 0x01, //     Usage Page (Generic Desktop)
+            0x09, 0x30, //     Usage (X)
+            0x09, 0x31, //     Usage (Y)
+            0x16, 0x01, 0x80, // Logical Minimum (-32767)
+            0x26, 0xFF, 0x7F, // Logical Maximum (32767)
+            0x75, 0x10, //     Report Size (16)
+            0x95, 0x02, //     Report Count (2)
+            0x81, 0x06, //     Input (Data, Variable, Relative)
+            0xA1, 0x02, //     Collection (Logical)
+            0x09, 0x48, //       Usage (Resolution Multiplier)
+            0x15, 0x00, //       Logical Minimum (0)
+            0x25, 0x01, //       Logical Maximum (1)
+            0x35, 0x01, //       Physical Minimum (1)
+            0x45, RESOLUTION_MULTIPLIER, // Physical Maximum
+            0x75, 0x02, //       Report Size (2)
+            0x95, 0x01, //       Report Count (1)
+            0xB1, 0x02, //       Feature (Data, Variable, Absolute)
+            0x35, 0x00, //       Physical Minimum (0)
+            0x45, 0x00, //       Physical Maximum (0)
+            0x09, 0x38, //       Usage (Wheel)
+            0x15, 0x81, //       Logical Minimum (-127)
+            0x25, 0x7F, //       Logical Maximum (127)
+            0x75, 0x08, //       Report Size (8)
+            0x81, 0x06, //       Input (Data, Variable, Relative)
+            0xC0, //     End Collection
+            0xA1, 0x02, //     Collection (Logical)
+            0x09, 0x48, //       Usage (Resolution Multiplier)
+            0x15, 0x00, //       Logical Minimum (0)
+            0x25, 0x01, //       Logical Maximum (1)
+            0x35, 0x01, //       Physical Minimum (1)
+            0x45, RESOLUTION_MULTIPLIER, // Physical Maximum
+            0x75, 0x02, //       Report Size (2)
+            0xB1, 0x02, //       Feature (Data, Variable, Absolute)
+            0x35, 0x00, //       Physical Minimum (0)
+            0x45, 0x00, //       Physical Maximum (0)
+            0x75, 0x04, //       Report Size (4)
+            0xB1, 0x03, //       Feature (Constant), pads the feature report to a byte
+            0x05, 0x0C, //       Usage Page (Consumer)
+            0x0A, 0x38, 0x02, // Usage (AC Pan)
+            0x15, 0x81, //       Logical Minimum (-127)
+            0x25, 0x7F, //       Logical Maximum (127)
+            0x75, 0x08, //       Report Size (8)
+            0x81, 0x06, //       Input (Data, Variable, Relative)
+            0xC0, //     End Collection
+            0xC0, //   End Collection
+            0xC0, // End Collection
+        ]
+    };
+}
+
+/// MouseReport16 descriptor with high resolution scrolling, used over USB
+pub const MOUSE_DESCRIPTOR: [u8; 117] = mouse_collection!();
+
+const KEYBOARD_COLLECTION: [u8; 66] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x85, 0x01, //   Report ID (1)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute), modifier
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x01, //   Input (Constant), reserved
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute), leds
+    0x75, 0x03, //   Report Size (3)
+    0x95, 0x01, //   Report Count (1)
+    0x91, 0x01, //   Output (Constant), pads the leds to a byte
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xDD, //   Usage Maximum (0xDD)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xDD, 0x00, // Logical Maximum (0xDD)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x06, //   Report Count (6)
+    0x81, 0x00, //   Input (Data, Array, Absolute), keycodes
+    0xC0, // End Collection
+];
+
+/// Keyboard report 1 and mouse report 2, with 16 bit movement and high
+/// resolution scrolling on the mouse. Used as the BLE report map
+pub const COMBINED_DESCRIPTOR: [u8; 185] = {
+    let keyboard = KEYBOARD_COLLECTION;
+    let mouse: [u8; 119] = mouse_collection!(0x02);
+    let mut descriptor = [0u8; 185];
+    let mut i = 0;
+    while i < keyboard.len() {
+        descriptor[i] = keyboard[i];
+        i += 1;
+    }
+    while i < descriptor.len() {
+        descriptor[i] = mouse[i - keyboard.len()];
+        i += 1;
+    }
+    descriptor
+};
+
 #[gen_hid_descriptor(
     (collection = APPLICATION, usage_page = 0xFF69, usage = 0x01) = {
         input=input;
@@ -95,58 +302,52 @@ pub struct BufferReport {
     pub output: [u8; 32],
 }
 
+// Usage page and usage VIA looks for when searching for the raw HID interface
 #[gen_hid_descriptor(
-    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
-        (report_id = 0x01,) = {
-            (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
-                #[packed_bits 8] #[item_settings data,variable,absolute] modifier=input;
-            };
-            (usage_min = 0x00, usage_max = 0xFF) = {
-                #[item_settings constant,variable,absolute] reserved=input;
-            };
-            (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
-                #[packed_bits 5] #[item_settings data,variable,absolute] leds=output;
-            };
-            (usage_page = KEYBOARD, usage_min = 0x00, usage_max = 0xDD) = {
-                #[item_settings data,array,absolute] keycodes=input;
-            };
+    (collection = APPLICATION, usage_page = 0xFF60, usage = 0x61) = {
+        input=input;
+        output=output;
+    }
+)]
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct ViaReport {
+    pub input: [u8; 32],
+    pub output: [u8; 32],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_mouse_report_is_little_endian() {
+        let report = MouseReport16 {
+            buttons: 0b01,
+            x: 300,
+            y: -2,
+            wheel: -1,
+            pan: 3,
         };
-    },
-    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
-        (collection = PHYSICAL, usage = POINTER) = {
-            (report_id = 0x02,) = {
-                (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = BUTTON_8) = {
-                    #[packed_bits 8] #[item_settings data,variable,absolute] buttons=input;
-                };
-                (usage_page = GENERIC_DESKTOP,) = {
-                    (usage = X,) = {
-                        #[item_settings data,variable,relative] x=input;
-                    };
-                    (usage = Y,) = {
-                        #[item_settings data,variable,relative] y=input;
-                    };
-                    (usage = WHEEL,) = {
-                        #[item_settings data,variable,relative] wheel=input;
-                    };
-                };
-                (usage_page = CONSUMER,) = {
-                    (usage = AC_PAN,) = {
-                        #[item_settings data,variable,relative] pan=input;
-                    };
-                };
-            };
+        assert_eq!(report.to_bytes(), [0b01, 0x2C, 0x01, 0xFE, 0xFF, 0xFF, 3]);
+    }
+
+    #[test]
+    fn boot_report_saturates() {
+        let report = MouseReport16 {
+            buttons: 0b10,
+            x: 300,
+            y: -300,
+            ..MouseReport16::default()
         };
-    },
-)]
-#[derive(PartialEq, Eq, Default)]
-pub struct CombinedReport {
-    pub modifier: u8,
-    pub reserved: u8,
-    pub leds: u8,
-    pub keycodes: [u8; 6],
-    pub buttons: u8,
-    pub x: i8,
-    pub y: i8,
-    pub wheel: i8,
-    pub pan: i8, // Scroll left (negative) or right (positive) this many units
+        assert_eq!(report.to_boot(), [0b10, 127, 0x80]);
+    }
+
+    #[test]
+    fn resolution_multiplier_round_trips() {
+        let resolution = ResolutionMultiplier::from_feature(0b0001);
+        assert_eq!(resolution.wheel, RESOLUTION_MULTIPLIER);
+        assert_eq!(resolution.pan, 1);
+        assert_eq!(resolution.to_feature(), 0b0001);
+    }
 }
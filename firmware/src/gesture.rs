@@ -0,0 +1,413 @@
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    cirque::RelativeDataPacket,
+    descriptor::{MouseReport, ResolutionMultiplier},
+};
+
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+
+/// How long x movement keeps turning into pan after the last wheel count
+const SCROLL_HOLD: Duration = Duration::from_millis(100);
+/// X movement per pan unit while scrolling
+const PAN_DIVISOR: i16 = 8;
+
+/// Gestures turned into mouse reports. Each one can be turned off on its own
+#[derive(Copy, Clone, Debug)]
+pub struct RecognizerConfig {
+    /// A tap clicks the left button
+    pub tap_to_click: bool,
+    /// Touching again right after a tap holds the left button while moving
+    pub tap_drag: bool,
+    /// Time after a tap in which moving starts a drag
+    pub drag_start_time: Duration,
+    /// Time without movement before a drag releases the button. Lifting the
+    /// finger and touching again within it keeps dragging
+    pub drag_lock_timeout: Duration,
+    /// Dragging two fingers scrolls, vertically with wheel and horizontally with pan
+    pub two_finger_scroll: bool,
+    /// A two finger tap clicks the right button
+    pub two_finger_right_click: bool,
+}
+
+impl RecognizerConfig {
+    pub const fn default() -> Self {
+        Self {
+            tap_to_click: true,
+            tap_drag: true,
+            drag_start_time: Duration::from_millis(200),
+            drag_lock_timeout: Duration::from_millis(500),
+            two_finger_scroll: true,
+            two_finger_right_click: true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Drag {
+    None,
+    /// A tap was released at the time
+    Tapped(Instant),
+    /// Dragging, last moved at the time
+    Dragging(Instant),
+}
+
+/// Turns relative trackpad packets into mouse reports with taps, tap and drag
+/// and two finger scrolling and tapping
+pub struct Recognizer {
+    config: RecognizerConfig,
+    drag: Drag,
+    // Button bits of the last packet, kept while no new data arrives
+    tapping: bool,
+    secondary: bool,
+    scrolling_until: Option<Instant>,
+    // X movement while scrolling that hasn't made a full pan unit yet
+    pan_rest: i16,
+    buttons: u8,
+    resolution: ResolutionMultiplier,
+}
+
+impl Recognizer {
+    pub const fn new(config: RecognizerConfig) -> Self {
+        Self {
+            config,
+            drag: Drag::None,
+            tapping: false,
+            secondary: false,
+            scrolling_until: None,
+            pan_rest: 0,
+            buttons: 0,
+            resolution: ResolutionMultiplier::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RecognizerConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: RecognizerConfig) {
+        self.config = config;
+    }
+
+    /// Sets the resolution multipliers of the host. Scrolling is scaled so it
+    /// keeps the same speed with finer steps
+    pub fn set_resolution(&mut self, resolution: ResolutionMultiplier) {
+        self.resolution = resolution;
+    }
+
+    /// Updates the gestures with the packet read at the passed in time, or with
+    /// None if there was no new data, so timeouts still expire. Returns a report
+    /// when there's movement or the buttons changed
+    pub fn update(
+        &mut self,
+        packet: Option<RelativeDataPacket>,
+        now: Instant,
+    ) -> Option<MouseReport> {
+        let mut report = MouseReport::default();
+        let (tapping, secondary) = match &packet {
+            Some(packet) => (packet.primary(), packet.secondary()),
+            None => (self.tapping, self.secondary),
+        };
+        let packet = packet.unwrap_or_default();
+        let moved = packet.x != 0 || packet.y != 0;
+
+        if self.config.two_finger_scroll && packet.wheel != 0 {
+            report.wheel = packet.wheel.saturating_mul(self.resolution.wheel as i8);
+            self.scrolling_until = Some(now + SCROLL_HOLD);
+        }
+        let scrolling = match self.scrolling_until {
+            Some(until) => now < until,
+            None => false,
+        };
+        if scrolling {
+            self.pan_rest += packet.x as i16 * self.resolution.pan as i16;
+            let pan = self.pan_rest / PAN_DIVISOR;
+            self.pan_rest -= pan * PAN_DIVISOR;
+            report.pan = pan.clamp(i8::MIN as i16, i8::MAX as i16) as i8;
+        } else {
+            self.pan_rest = 0;
+            report.x = packet.x;
+            report.y = packet.y;
+        }
+
+        // A released tap can start a drag, which lasts until it stops moving
+        if self.tapping && !tapping && self.config.tap_drag {
+            self.drag = Drag::Tapped(now);
+        }
+        self.tapping = tapping;
+        self.secondary = secondary;
+        self.drag = match self.drag {
+            Drag::Tapped(time) if now - time > self.config.drag_start_time => Drag::None,
+            Drag::Tapped(_) if moved && !scrolling => Drag::Dragging(now),
+            Drag::Dragging(_) if moved => Drag::Dragging(now),
+            Drag::Dragging(time) if now - time > self.config.drag_lock_timeout => Drag::None,
+            drag => drag,
+        };
+
+        if (tapping && self.config.tap_to_click) || matches!(self.drag, Drag::Dragging(_)) {
+            report.buttons |= LEFT_BUTTON;
+        }
+        if secondary && self.config.two_finger_right_click {
+            report.buttons |= RIGHT_BUTTON;
+        }
+
+        let changed = report.buttons != self.buttons;
+        self.buttons = report.buttons;
+        let moving = report.x != 0 || report.y != 0 || report.wheel != 0 || report.pan != 0;
+        if changed || moving {
+            Some(report)
+        } else {
+            None
+        }
+    }
+}
+
+/// How trackpad movement is turned into scrolling in scroll mode
+#[derive(Copy, Clone, Debug)]
+pub struct ScrollConfig {
+    /// Movement counts per wheel or pan unit
+    pub divisor: u8,
+    /// Only scrolls along one axis at a time. The axis changes once the
+    /// movement along the other one is twice as large
+    pub snap: bool,
+}
+
+impl ScrollConfig {
+    pub const fn default() -> Self {
+        Self {
+            divisor: 16,
+            snap: true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Axis {
+    Horizontal,
+    Vertical,
+}
+
+/// Turns the movement of mouse reports into wheel and pan, such as while a
+/// scroll layer is active
+pub struct ScrollMode {
+    config: ScrollConfig,
+    axis: Option<Axis>,
+    // Movement that hasn't made a full wheel or pan unit yet
+    rest_x: i16,
+    rest_y: i16,
+    resolution: ResolutionMultiplier,
+}
+
+impl ScrollMode {
+    pub const fn new(config: ScrollConfig) -> Self {
+        Self {
+            config,
+            axis: None,
+            rest_x: 0,
+            rest_y: 0,
+            resolution: ResolutionMultiplier::default(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: ScrollConfig) {
+        self.config = config;
+        self.reset();
+    }
+
+    /// Sets the resolution multipliers of the host. The divisor is applied
+    /// per detent, so a higher multiplier scrolls in finer steps
+    pub fn set_resolution(&mut self, resolution: ResolutionMultiplier) {
+        self.resolution = resolution;
+    }
+
+    /// Drops the snapped axis and the leftover movement. Called when scroll
+    /// mode ends
+    pub fn reset(&mut self) {
+        self.axis = None;
+        self.rest_x = 0;
+        self.rest_y = 0;
+    }
+
+    /// Moves the x and y of the report into pan and wheel. Moving up scrolls up
+    pub fn update(&mut self, report: &mut MouseReport) {
+        let (mut x, mut y) = (report.x as i16, report.y as i16);
+        report.x = 0;
+        report.y = 0;
+        if self.config.snap && (x != 0 || y != 0) {
+            let axis = match self.axis {
+                Some(Axis::Horizontal) if y.abs() > 2 * x.abs() => Axis::Vertical,
+                Some(Axis::Vertical) if x.abs() > 2 * y.abs() => Axis::Horizontal,
+                Some(axis) => axis,
+                None if x.abs() > y.abs() => Axis::Horizontal,
+                None => Axis::Vertical,
+            };
+            if self.axis != Some(axis) {
+                self.rest_x = 0;
+                self.rest_y = 0;
+            }
+            self.axis = Some(axis);
+            match axis {
+                Axis::Horizontal => y = 0,
+                Axis::Vertical => x = 0,
+            }
+        }
+        let divisor = self.config.divisor.max(1) as i16;
+        let scroll = |delta: i16, multiplier: u8, rest: &mut i16| {
+            *rest += delta * multiplier as i16;
+            let units = *rest / divisor;
+            *rest -= units * divisor;
+            units.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+        };
+        report.pan = report
+            .pan
+            .saturating_add(scroll(x, self.resolution.pan, &mut self.rest_x));
+        report.wheel =
+            report
+                .wheel
+                .saturating_sub(scroll(y, self.resolution.wheel, &mut self.rest_y));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(x: i8, y: i8) -> MouseReport {
+        MouseReport {
+            x,
+            y,
+            ..MouseReport::default()
+        }
+    }
+
+    fn packet(buttons: u8, x: i8, y: i8, wheel: i8) -> Option<RelativeDataPacket> {
+        Some(RelativeDataPacket {
+            buttons,
+            x,
+            y,
+            wheel,
+        })
+    }
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    /// Buttons of the report, or None if there's no report
+    fn buttons(report: Option<MouseReport>) -> Option<u8> {
+        report.map(|report| report.buttons)
+    }
+
+    #[test]
+    fn tap_clicks() {
+        let mut recognizer = Recognizer::new(RecognizerConfig::default());
+        assert_eq!(
+            buttons(recognizer.update(packet(1, 0, 0, 0), at(0))),
+            Some(LEFT_BUTTON)
+        );
+        assert_eq!(
+            buttons(recognizer.update(packet(0, 0, 0, 0), at(10))),
+            Some(0)
+        );
+        assert_eq!(buttons(recognizer.update(None, at(20))), None);
+    }
+
+    #[test]
+    fn disabled_taps_dont_click() {
+        let mut recognizer = Recognizer::new(RecognizerConfig {
+            tap_to_click: false,
+            two_finger_right_click: false,
+            ..RecognizerConfig::default()
+        });
+        assert_eq!(
+            buttons(recognizer.update(packet(0b11, 0, 0, 0), at(0))),
+            None
+        );
+    }
+
+    #[test]
+    fn moving_after_a_tap_drags_until_the_lock_timeout() {
+        let mut recognizer = Recognizer::new(RecognizerConfig::default());
+        recognizer.update(packet(1, 0, 0, 0), at(0));
+        recognizer.update(packet(0, 0, 0, 0), at(10));
+        let report = recognizer.update(packet(0, 5, 0, 0), at(50)).unwrap();
+        assert_eq!((report.buttons, report.x), (LEFT_BUTTON, 5));
+        assert_eq!(
+            buttons(recognizer.update(packet(0, 3, 0, 0), at(100))),
+            Some(LEFT_BUTTON)
+        );
+        // Lifting the finger within the timeout keeps the button held
+        assert_eq!(buttons(recognizer.update(None, at(550))), None);
+        assert_eq!(buttons(recognizer.update(None, at(601))), Some(0));
+    }
+
+    #[test]
+    fn moving_long_after_a_tap_doesnt_drag() {
+        let mut recognizer = Recognizer::new(RecognizerConfig::default());
+        recognizer.update(packet(1, 0, 0, 0), at(0));
+        recognizer.update(packet(0, 0, 0, 0), at(10));
+        let report = recognizer.update(packet(0, 5, 0, 0), at(300)).unwrap();
+        assert_eq!((report.buttons, report.x), (0, 5));
+    }
+
+    #[test]
+    fn wheel_scrolls_and_x_pans_while_scrolling() {
+        let mut recognizer = Recognizer::new(RecognizerConfig::default());
+        let report = recognizer.update(packet(0, 0, 0, -2), at(0)).unwrap();
+        assert_eq!((report.wheel, report.x), (-2, 0));
+        let report = recognizer.update(packet(0, 16, 0, 0), at(50)).unwrap();
+        assert_eq!((report.pan, report.x), (2, 0));
+        // Once the scroll hold is over x moves the pointer again
+        let report = recognizer.update(packet(0, 16, 0, 0), at(200)).unwrap();
+        assert_eq!((report.pan, report.x), (0, 16));
+    }
+
+    #[test]
+    fn slow_movement_adds_up_to_scrolling() {
+        let mut scroll = ScrollMode::new(ScrollConfig::default());
+        let mut report = moved(0, -10);
+        scroll.update(&mut report);
+        assert_eq!((report.x, report.y, report.wheel), (0, 0, 0));
+        let mut report = moved(0, -10);
+        scroll.update(&mut report);
+        assert_eq!(report.wheel, 1);
+    }
+
+    #[test]
+    fn snapping_keeps_the_axis_until_the_other_dominates() {
+        let mut scroll = ScrollMode::new(ScrollConfig {
+            divisor: 1,
+            snap: true,
+        });
+        let mut report = moved(2, 8);
+        scroll.update(&mut report);
+        assert_eq!((report.pan, report.wheel), (0, -8));
+        let mut report = moved(6, 4);
+        scroll.update(&mut report);
+        assert_eq!((report.pan, report.wheel), (0, -4));
+        let mut report = moved(9, 4);
+        scroll.update(&mut report);
+        assert_eq!((report.pan, report.wheel), (9, 0));
+    }
+
+    #[test]
+    fn high_resolution_scrolls_in_finer_steps() {
+        let mut scroll = ScrollMode::new(ScrollConfig::default());
+        scroll.set_resolution(ResolutionMultiplier::from_feature(0b0101));
+        let mut report = moved(0, -10);
+        scroll.update(&mut report);
+        assert_eq!(report.wheel, 10);
+    }
+
+    #[test]
+    fn both_axes_scroll_without_snapping() {
+        let mut scroll = ScrollMode::new(ScrollConfig {
+            divisor: 2,
+            snap: false,
+        });
+        let mut report = moved(4, 6);
+        scroll.update(&mut report);
+        assert_eq!((report.pan, report.wheel), (2, -3));
+    }
+}
@@ -0,0 +1,307 @@
+use defmt::{info, warn};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    codes::scan_code_from_raw,
+    config::layouts,
+    keys::{Keys, ScanCode, NUM_LAYERS},
+    storage::{Storage, StorageItem, ITEM_BUFFER_SIZE},
+};
+
+const KEYMAP_VERSION: u8 = 1;
+const KEYMAP_START: u32 = 0x100;
+const HEADER_SIZE: usize = 3;
+pub const ACTION_SIZE: usize = 4;
+pub const MAX_KEYMAP_KEYS: usize = 40;
+
+/// Returns the storage key of the passed in layer
+pub const fn ki(layer: u8) -> u32 {
+    KEYMAP_START + layer as u32
+}
+
+/// Storable representation of a ScanCodeBehavior. Codes are stored as raw
+/// KeyCodes values
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum KeyAction {
+    Code(u8),
+    // Layer code that stays after the key is released
+    Toggle(u8),
+    Double(u8, u8),
+    Triple(u8, u8, u8),
+    Combined {
+        other_index: u8,
+        normal_code: u8,
+        combined_code: u8,
+    },
+    // Delay is the starting delay in ms. The acceleration is always config::mouse_acc
+    Interval {
+        code: u8,
+        delay: u16,
+    },
+    // Index into config::layouts
+    Config(u8),
+}
+
+impl KeyAction {
+    pub fn to_bytes(self) -> [u8; ACTION_SIZE] {
+        match self {
+            KeyAction::Code(code) => [0, code, 0, 0],
+            KeyAction::Toggle(code) => [1, code, 0, 0],
+            KeyAction::Double(code0, code1) => [2, code0, code1, 0],
+            KeyAction::Triple(code0, code1, code2) => [3, code0, code1, code2],
+            KeyAction::Combined {
+                other_index,
+                normal_code,
+                combined_code,
+            } => [4, other_index, normal_code, combined_code],
+            KeyAction::Interval { code, delay } => {
+                let delay = delay.to_le_bytes();
+                [5, code, delay[0], delay[1]]
+            }
+            KeyAction::Config(id) => [6, id, 0, 0],
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        match buf[0] {
+            0 => Some(KeyAction::Code(buf[1])),
+            1 => Some(KeyAction::Toggle(buf[1])),
+            2 => Some(KeyAction::Double(buf[1], buf[2])),
+            3 => Some(KeyAction::Triple(buf[1], buf[2], buf[3])),
+            4 => Some(KeyAction::Combined {
+                other_index: buf[1],
+                normal_code: buf[2],
+                combined_code: buf[3],
+            }),
+            5 => Some(KeyAction::Interval {
+                code: buf[1],
+                delay: u16::from_le_bytes([buf[2], buf[3]]),
+            }),
+            6 => Some(KeyAction::Config(buf[1])),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the action can be loaded into a Keys struct with S keys
+    pub fn is_valid<const S: usize>(&self) -> bool {
+        match *self {
+            KeyAction::Code(code) | KeyAction::Toggle(code) => is_valid_code(code),
+            KeyAction::Double(code0, code1) => is_valid_code(code0) && is_valid_code(code1),
+            KeyAction::Triple(code0, code1, code2) => {
+                is_valid_code(code0) && is_valid_code(code1) && is_valid_code(code2)
+            }
+            KeyAction::Combined {
+                other_index,
+                normal_code,
+                combined_code,
+            } => {
+                (other_index as usize) < S
+                    && is_valid_code(normal_code)
+                    && is_valid_code(combined_code)
+            }
+            KeyAction::Interval { code, .. } => is_valid_code(code),
+            KeyAction::Config(id) => (id as usize) < layouts::<S>().len(),
+        }
+    }
+}
+
+/// Returns false for layer codes past the last layer, which Keys has no codes for
+fn is_valid_code(code: u8) -> bool {
+    match scan_code_from_raw(code) {
+        ScanCode::Layer(layer) => layer.pos < NUM_LAYERS,
+        _ => true,
+    }
+}
+
+/// All the actions of a single layer. This is the unit the keymap is stored
+/// in flash with
+#[derive(Debug, Clone)]
+pub struct KeymapLayer {
+    pub layer: u8,
+    pub actions: Vec<KeyAction, MAX_KEYMAP_KEYS>,
+}
+
+impl KeymapLayer {
+    /// Returns the passed in layer of keys as a KeymapLayer
+    pub fn from_keys<const S: usize>(keys: &Keys<S>, layer: usize) -> Self {
+        let mut actions = Vec::new();
+        for i in 0..S.min(MAX_KEYMAP_KEYS) {
+            actions.push(keys.get_action(i, layer)).unwrap();
+        }
+        Self {
+            layer: layer as u8,
+            actions,
+        }
+    }
+
+    fn is_valid<const S: usize>(&self, layer: usize) -> bool {
+        self.layer as usize == layer
+            && self.actions.len() == S
+            && self.actions.iter().all(|action| action.is_valid::<S>())
+    }
+}
+
+impl<'a> Value<'a> for KeymapLayer {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let size = HEADER_SIZE + self.actions.len() * ACTION_SIZE;
+        if buffer.len() < size {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = KEYMAP_VERSION;
+        buffer[1] = self.layer;
+        buffer[2] = self.actions.len() as u8;
+        for (i, action) in self.actions.iter().enumerate() {
+            let start = HEADER_SIZE + i * ACTION_SIZE;
+            buffer[start..start + ACTION_SIZE].copy_from_slice(&action.to_bytes());
+        }
+        Ok(size)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < HEADER_SIZE || buffer[0] != KEYMAP_VERSION {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let len = buffer[2] as usize;
+        if len > MAX_KEYMAP_KEYS || buffer.len() < HEADER_SIZE + len * ACTION_SIZE {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let mut actions = Vec::new();
+        for chunk in buffer[HEADER_SIZE..HEADER_SIZE + len * ACTION_SIZE].chunks(ACTION_SIZE) {
+            let action = KeyAction::from_bytes(chunk).ok_or(SerializationError::InvalidData)?;
+            actions.push(action).unwrap();
+        }
+        Ok(Self {
+            layer: buffer[1],
+            actions,
+        })
+    }
+}
+
+/// Loads the keymap stored in flash into keys. The passed in default is loaded
+/// instead if the flash doesn't hold a complete and valid keymap for S keys
+pub async fn load_keymap<F: NorFlash, const S: usize>(
+    storage: &Storage<F, u32>,
+    keys: &mut Keys<S>,
+    default: fn(&mut Keys<S>),
+) {
+    default(keys);
+    let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+    for layer in 0..NUM_LAYERS {
+        let stored = storage
+            .get_item::<KeymapLayer>(ki(layer as u8), &mut buffer)
+            .await;
+        match stored {
+            Some(stored) if stored.is_valid::<S>(layer) => {
+                for (i, action) in stored.actions.iter().enumerate() {
+                    keys.set_action(*action, i, layer);
+                }
+            }
+            Some(_) => {
+                warn!("Invalid keymap stored on layer {}, loading default", layer);
+                default(keys);
+                return;
+            }
+            None => {
+                if layer != 0 {
+                    warn!("Keymap missing layer {}, loading default", layer);
+                    default(keys);
+                } else {
+                    info!("No keymap stored, loading default");
+                }
+                return;
+            }
+        }
+    }
+    info!("Keymap loaded from flash");
+}
+
+/// Sends the passed in layer of keys to the storage channel
+pub async fn save_layer<F: NorFlash, const S: usize>(
+    storage: &Storage<F, u32>,
+    keys: &Keys<S>,
+    layer: usize,
+) {
+    storage
+        .send_item(
+            &ki(layer as u8),
+            &StorageItem::Keymap(KeymapLayer::from_keys(keys, layer)),
+        )
+        .await;
+}
+
+/// Sends every layer of keys to the storage channel
+pub async fn save_keymap<F: NorFlash, const S: usize>(storage: &Storage<F, u32>, keys: &Keys<S>) {
+    for layer in 0..NUM_LAYERS {
+        save_layer(storage, keys, layer).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codes::KeyCodes;
+
+    const ACTIONS: [KeyAction; 7] = [
+        KeyAction::Code(0x04),
+        KeyAction::Toggle(0xEA),
+        KeyAction::Double(0xE1, 0x1E),
+        KeyAction::Triple(0xE0, 0xE2, 0x06),
+        KeyAction::Combined {
+            other_index: 3,
+            normal_code: 0x05,
+            combined_code: 0x29,
+        },
+        KeyAction::Interval {
+            code: 0xFB,
+            delay: 300,
+        },
+        KeyAction::Config(1),
+    ];
+
+    #[test]
+    fn actions_round_trip() {
+        for action in ACTIONS {
+            assert_eq!(KeyAction::from_bytes(&action.to_bytes()), Some(action));
+        }
+        assert_eq!(KeyAction::from_bytes(&[7, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn layers_round_trip() {
+        let layer = KeymapLayer {
+            layer: 2,
+            actions: Vec::from_slice(&ACTIONS).unwrap(),
+        };
+        let mut buffer = [0u8; HEADER_SIZE + MAX_KEYMAP_KEYS * ACTION_SIZE];
+        let size = layer.serialize_into(&mut buffer).unwrap();
+        assert_eq!(size, HEADER_SIZE + ACTIONS.len() * ACTION_SIZE);
+        let stored = KeymapLayer::deserialize_from(&buffer[..size]).unwrap();
+        assert_eq!(stored.layer, 2);
+        assert_eq!(stored.actions, layer.actions);
+        // A layer from an older keymap version isn't loaded
+        buffer[0] = KEYMAP_VERSION + 1;
+        assert!(KeymapLayer::deserialize_from(&buffer[..size]).is_err());
+    }
+
+    #[test]
+    fn layers_past_the_last_are_invalid() {
+        let last = KeyCodes::Layer0 as u8 + NUM_LAYERS as u8 - 1;
+        assert!(KeyAction::Code(last).is_valid::<39>());
+        for action in [
+            KeyAction::Code(last + 1),
+            KeyAction::Toggle(last + 1),
+            KeyAction::Double(0xE1, last + 1),
+            KeyAction::Interval {
+                code: last + 1,
+                delay: 300,
+            },
+        ] {
+            assert!(!action.is_valid::<39>());
+        }
+    }
+}
@@ -3,55 +3,15 @@ use core::{borrow::BorrowMut, ops::Range};
 use embassy_time::{Duration, Instant};
 use heapless::Vec;
 
-use crate::codes::KeyCodes;
+use crate::{
+    bitmap::KeyBitmap,
+    codes::{scan_code, scan_code_from_raw, KeyCodes},
+    config::{layouts, mouse_acc},
+    debounce::{Algorithm, Debounce, Debouncer},
+    keymap::KeyAction,
+};
 pub const NUM_LAYERS: usize = 10;
 
-pub const DEBOUNCE_TIME: u64 = 6;
-
-const CENTRAL_NUM_KEYS: usize = 18;
-const PERP_NUM_KEYS: usize = 18;
-
-#[derive(Copy, Clone, Debug)]
-struct Position {
-    state: bool,
-    debounced: Option<Instant>,
-}
-
-impl Position {
-    const fn default() -> Position {
-        Self {
-            state: false,
-            debounced: None,
-        }
-    }
-    /// Returns the pressed status of the position
-    fn is_pressed(&self) -> bool {
-        self.state
-    }
-
-    /// Updates the buf of the key. Updating the buf will also update
-    /// the value returned from the is_pressed function
-    fn update_buf(&mut self, buf: bool) {
-        match self.debounced {
-            Some(time) => {
-                if time.elapsed() > Duration::from_millis(DEBOUNCE_TIME) {
-                    self.debounced = None;
-                }
-            }
-            None => {
-                if buf != self.state {
-                    self.debounced = Some(Instant::now());
-                    self.state = buf;
-                }
-            }
-        }
-    }
-
-    fn update_buf_direct(&mut self, buf: bool) {
-        self.state = buf;
-    }
-}
-
 /// Represents a layer scancode. Pos represents the layer
 /// the scancode will switch to and toggle will repsent if
 /// the layer stored in the code stays after the key is released
@@ -83,6 +43,16 @@ impl IntervalPresses {
             acc_eq,
         }
     }
+    /// Returns the scan code sent on every interval
+    pub fn code(&self) -> ScanCode {
+        self.code
+    }
+
+    /// Returns the starting delay between presses
+    pub fn delay(&self) -> Duration {
+        self.org_delay
+    }
+
     fn get_code(&mut self) -> ScanCode {
         if let Some(time) = self.starting_time {
             if self.last_pressed_time.elapsed() > self.current_delay {
@@ -112,9 +82,36 @@ pub enum ScanCode {
     MouseY(i8),
     Layer(Layer),
     Scroll(i8),
+    /// Changes the speed of the mouse keys, -1 for precision and 1 for turbo
+    MouseSpeed(i8),
+    /// Toggles turning trackpad movement into scrolling
+    ScrollMode,
     None,
 }
 
+impl ScanCode {
+    /// Converts the ScanCode back to the raw keycode value it was created from.
+    /// Codes that don't have a keycode return 0
+    pub fn raw_code(&self) -> u8 {
+        match *self {
+            ScanCode::Letter(code) => code,
+            ScanCode::Modifier(code) => KeyCodes::KeyboardLeftControl as u8 + code,
+            ScanCode::Layer(layer) => KeyCodes::Layer0 as u8 + layer.pos as u8,
+            ScanCode::MouseButton(code) => KeyCodes::MouseLeftClick as u8 + code,
+            ScanCode::MouseX(1) => KeyCodes::MousePositiveX as u8,
+            ScanCode::MouseX(-1) => KeyCodes::MouseNegativeX as u8,
+            ScanCode::MouseY(1) => KeyCodes::MousePositiveY as u8,
+            ScanCode::MouseY(-1) => KeyCodes::MouseNegativeY as u8,
+            ScanCode::Scroll(1) => KeyCodes::MouseScrollUp as u8,
+            ScanCode::Scroll(-1) => KeyCodes::MouseScrollDown as u8,
+            ScanCode::MouseSpeed(-1) => KeyCodes::MousePrecision as u8,
+            ScanCode::MouseSpeed(1) => KeyCodes::MouseTurbo as u8,
+            ScanCode::ScrollMode => KeyCodes::TrackpadScroll as u8,
+            _ => 0,
+        }
+    }
+}
+
 /// Wrapper around ScanCode to allow different fuctionalites when pressed
 /// such as sending multiple keys
 #[derive(Copy, Clone, Debug)]
@@ -135,24 +132,20 @@ pub enum ScanCodeBehavior<const S: usize> {
 
 #[derive(Copy, Clone, Debug)]
 struct Key<const S: usize> {
-    pos: Position,
     codes: [ScanCodeBehavior<S>; NUM_LAYERS],
     pub current_layer: Option<usize>,
-    debounce: bool,
 }
 
 impl<const S: usize> Key<S> {
     const fn default() -> Self {
         Self {
-            pos: Position::default(),
             codes: [ScanCodeBehavior::Single(ScanCode::Letter(0)); NUM_LAYERS],
             current_layer: None,
-            debounce: true,
         }
     }
 
     fn set_code(&mut self, code: KeyCodes, toggle: bool, layer: usize) {
-        self.codes[layer] = match code.get_scan_code() {
+        self.codes[layer] = match scan_code(code) {
             ScanCode::Layer(mut l) => {
                 l.toggle = toggle;
                 ScanCodeBehavior::Single(ScanCode::Layer(l))
@@ -160,23 +153,13 @@ impl<const S: usize> Key<S> {
             rest => ScanCodeBehavior::Single(rest),
         }
     }
-
-    fn update_buf(&mut self, buf: bool) {
-        if self.debounce {
-            self.pos.update_buf(buf);
-        } else {
-            self.pos.update_buf_direct(buf);
-        }
-    }
-
-    pub fn is_pressed(&self) -> bool {
-        self.pos.is_pressed()
-    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct Keys<const S: usize> {
     keys: [Key<S>; S],
+    debouncer: Debouncer<S>,
+    changed: bool,
 }
 
 enum PressResult {
@@ -189,11 +172,13 @@ impl<const S: usize> Keys<S> {
     pub const fn default() -> Self {
         Self {
             keys: [Key::default(); S],
+            debouncer: Debouncer::new(),
+            changed: false,
         }
     }
 
     pub fn get_pressed(&self, index: usize) -> bool {
-        self.keys[index].pos.is_pressed()
+        self.debouncer.is_pressed(index)
     }
 
     /// Sets the code on the passed in layer on the indexed key. Returns
@@ -205,7 +190,7 @@ impl<const S: usize> Keys<S> {
     /// Sets the indexed key to be a double key. A double key sends two keycodes rather than one
     pub fn set_double(&mut self, code0: KeyCodes, code1: KeyCodes, index: usize, layer: usize) {
         self.keys[index].codes[layer] =
-            ScanCodeBehavior::Double(code0.get_scan_code(), code1.get_scan_code());
+            ScanCodeBehavior::Double(scan_code(code0), scan_code(code1));
     }
 
     /// Sets the indexed key to be a combined key. other_index is the other indexed key that needs
@@ -220,8 +205,8 @@ impl<const S: usize> Keys<S> {
     ) {
         self.keys[index].codes[layer] = ScanCodeBehavior::CombinedKey {
             other_index,
-            normal_code: norm_code.get_scan_code(),
-            combined_code: comb_code.get_scan_code(),
+            normal_code: scan_code(norm_code),
+            combined_code: scan_code(comb_code),
         }
     }
 
@@ -236,13 +221,13 @@ impl<const S: usize> Keys<S> {
         layer: usize,
     ) {
         self.keys[index].codes[layer] =
-            ScanCodeBehavior::IntervalPresses(IntervalPresses::new(code.get_scan_code(), dur, f))
+            ScanCodeBehavior::IntervalPresses(IntervalPresses::new(scan_code(code), dur, f))
     }
 
     /// Sets the following indexed to be a toggle layer key for the passed in layer. Any none layer
     /// keys passed in will be set like in set_code
     pub fn set_toggle_layer(&mut self, layer_code: KeyCodes, index: usize, layer: usize) {
-        match layer_code.get_scan_code() {
+        match scan_code(layer_code) {
             ScanCode::Layer(_) => {}
             _ => {
                 panic!("bruh")
@@ -259,33 +244,135 @@ impl<const S: usize> Keys<S> {
         self.keys[index].codes[layer] = ScanCodeBehavior::Function(f);
     }
 
+    /// Returns the storable representation of the indexed key on the passed in layer.
+    /// Function keys can't be stored and are returned as an empty code
+    pub fn get_action(&self, index: usize, layer: usize) -> KeyAction {
+        match self.keys[index].codes[layer] {
+            ScanCodeBehavior::Single(ScanCode::Layer(l)) if l.toggle => {
+                KeyAction::Toggle(ScanCode::Layer(l).raw_code())
+            }
+            ScanCodeBehavior::Single(code) => KeyAction::Code(code.raw_code()),
+            ScanCodeBehavior::Double(code0, code1) => {
+                KeyAction::Double(code0.raw_code(), code1.raw_code())
+            }
+            ScanCodeBehavior::Triple(code0, code1, code2) => {
+                KeyAction::Triple(code0.raw_code(), code1.raw_code(), code2.raw_code())
+            }
+            ScanCodeBehavior::CombinedKey {
+                other_index,
+                normal_code,
+                combined_code,
+            } => KeyAction::Combined {
+                other_index: other_index as u8,
+                normal_code: normal_code.raw_code(),
+                combined_code: combined_code.raw_code(),
+            },
+            ScanCodeBehavior::IntervalPresses(val) => KeyAction::Interval {
+                code: val.code().raw_code(),
+                delay: val.delay().as_millis() as u16,
+            },
+            ScanCodeBehavior::Config(f) => {
+                match layouts::<S>()
+                    .iter()
+                    .position(|l| *l as usize == f as usize)
+                {
+                    Some(id) => KeyAction::Config(id as u8),
+                    None => KeyAction::Code(0),
+                }
+            }
+            ScanCodeBehavior::Function(_) => KeyAction::Code(0),
+        }
+    }
+
+    /// Sets the indexed key on the passed in layer from its storable representation
+    pub fn set_action(&mut self, action: KeyAction, index: usize, layer: usize) {
+        let code = scan_code_from_raw;
+        self.keys[index].codes[layer] = match action {
+            KeyAction::Code(val) => ScanCodeBehavior::Single(code(val)),
+            KeyAction::Toggle(val) => match code(val) {
+                ScanCode::Layer(mut l) => {
+                    l.toggle = true;
+                    ScanCodeBehavior::Single(ScanCode::Layer(l))
+                }
+                rest => ScanCodeBehavior::Single(rest),
+            },
+            KeyAction::Double(val0, val1) => ScanCodeBehavior::Double(code(val0), code(val1)),
+            KeyAction::Triple(val0, val1, val2) => {
+                ScanCodeBehavior::Triple(code(val0), code(val1), code(val2))
+            }
+            KeyAction::Combined {
+                other_index,
+                normal_code,
+                combined_code,
+            } => ScanCodeBehavior::CombinedKey {
+                other_index: other_index as usize,
+                normal_code: code(normal_code),
+                combined_code: code(combined_code),
+            },
+            KeyAction::Interval { code: val, delay } => ScanCodeBehavior::IntervalPresses(
+                IntervalPresses::new(code(val), Duration::from_millis(delay as u64), mouse_acc),
+            ),
+            KeyAction::Config(id) => match layouts::<S>().get(id as usize) {
+                Some(f) => ScanCodeBehavior::Config(*f),
+                None => ScanCodeBehavior::Single(ScanCode::Letter(0)),
+            },
+        }
+    }
+
+    /// Returns true if a config key changed the layout since the last call
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+
     /// Updates the indexed key with the provided reading
     pub fn update_buf(&mut self, index: usize, buf: bool) {
-        self.keys[index].update_buf(buf);
+        self.debouncer.update(index, buf, Instant::now());
     }
 
-    /// Updates the indexed key with the provided reading
-    pub fn update_buf_central(&mut self, index: usize, buf: bool) {
-        if index < CENTRAL_NUM_KEYS {
-            self.keys[index].update_buf(buf);
-        }
+    /// Updates the M keys starting at offset from a scanned bitmap. Only the keys
+    /// that changed or are still debouncing are processed
+    pub fn update_bitmap<const M: usize>(&mut self, offset: usize, bitmap: KeyBitmap<M>) {
+        self.debouncer.update_bitmap(offset, bitmap, Instant::now());
     }
 
     /// Returns the indexes of all the keys that are pressed to the vec
     pub fn is_pressed(&self, vec: &mut Vec<usize, S>) {
         for i in 0..S {
-            if self.keys[i].pos.is_pressed() {
+            if self.debouncer.is_pressed(i) {
                 vec.push(i).unwrap();
             }
         }
     }
 
+    /// Turns debouncing of the range on with the eager algorithm, or off
     pub fn set_debounce(&mut self, range: Range<u8>, state: bool) {
-        for i in range {
-            self.keys[i as usize].debounce = state;
+        let algorithm = if state {
+            Algorithm::Eager
+        } else {
+            Algorithm::Off
+        };
+        self.debouncer
+            .set_algorithm(range.start as usize..range.end as usize, algorithm);
+    }
+
+    /// Sets the debounce algorithm of the range. SymDeferRow treats the
+    /// whole range as one row, use set_debounce_rows to split it
+    pub fn set_debounce_algorithm(&mut self, range: Range<u8>, algorithm: Algorithm) {
+        let range = range.start as usize..range.end as usize;
+        match algorithm {
+            Algorithm::SymDeferRow => self.debouncer.set_rows(range.clone(), range.len()),
+            _ => self.debouncer.set_algorithm(range, algorithm),
         }
     }
 
+    /// Debounces the range with SymDeferRow in rows of row_len keys
+    pub fn set_debounce_rows(&mut self, range: Range<u8>, row_len: usize) {
+        self.debouncer
+            .set_rows(range.start as usize..range.end as usize, row_len);
+    }
+
     /// Pushes the resulting ScanResult onto the provided vec depending on the indexed key's
     /// position. Returns true if a key was pushed into the provided index set
     fn get_pressed_code(
@@ -294,7 +381,7 @@ impl<const S: usize> Keys<S> {
         layer: usize,
         set: &mut Vec<ScanCode, 64>,
     ) -> PressResult {
-        let pressed = self.keys[index].pos.is_pressed();
+        let pressed = self.debouncer.is_pressed(index);
         match self.keys[index].codes[layer].borrow_mut() {
             ScanCodeBehavior::Single(code) => {
                 if pressed {
@@ -329,7 +416,7 @@ impl<const S: usize> Keys<S> {
                 combined_code: other_key_code,
             } => {
                 if pressed {
-                    if self.keys[*other_index].pos.is_pressed() {
+                    if self.debouncer.is_pressed(*other_index) {
                         set.push(*other_key_code).unwrap();
                         PressResult::Pressed
                     } else {
@@ -342,7 +429,13 @@ impl<const S: usize> Keys<S> {
             }
             ScanCodeBehavior::IntervalPresses(val) => {
                 if pressed {
-                    set.push(val.get_code()).unwrap();
+                    // Pointer movement is timed by the mouse keys in Report,
+                    // so those codes are sent for as long as the key is held
+                    let code = match val.code() {
+                        ScanCode::MouseX(_) | ScanCode::MouseY(_) => val.code(),
+                        _ => val.get_code(),
+                    };
+                    set.push(code).unwrap();
                     PressResult::Pressed
                 } else {
                     val.starting_time = None;
@@ -352,6 +445,7 @@ impl<const S: usize> Keys<S> {
             ScanCodeBehavior::Config(f) => {
                 if pressed {
                     f(self);
+                    self.changed = true;
                     PressResult::Function
                 } else {
                     PressResult::None
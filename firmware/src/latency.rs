@@ -0,0 +1,61 @@
+use defmt::info;
+use embassy_time::Instant;
+
+// Reports between the latency logs
+const LOG_INTERVAL: u32 = 1000;
+pub const LATENCY_SIZE: usize = 16;
+
+/// Counts the time from a scan returning to its report being sent, in microseconds
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Latency {
+    pub count: u32,
+    pub last: u32,
+    pub max: u32,
+    total: u64,
+}
+
+impl Latency {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            last: 0,
+            max: 0,
+            total: 0,
+        }
+    }
+
+    /// Records a sent report for the scan that returned at the passed in time.
+    /// Logs the counters every LOG_INTERVAL reports
+    pub fn record(&mut self, scanned: Instant) {
+        let latency = scanned.elapsed().as_micros().min(u32::MAX as u64) as u32;
+        self.count = self.count.wrapping_add(1);
+        self.last = latency;
+        self.max = self.max.max(latency);
+        self.total += latency as u64;
+        if self.count % LOG_INTERVAL == 0 {
+            info!(
+                "Latency over {} reports: avg {} us, max {} us",
+                self.count,
+                self.average(),
+                self.max
+            );
+        }
+    }
+
+    pub fn average(&self) -> u32 {
+        match self.count {
+            0 => 0,
+            count => (self.total / count as u64) as u32,
+        }
+    }
+
+    /// Returns the count, average, max and last latency as little endian u32s
+    pub fn to_bytes(&self) -> [u8; LATENCY_SIZE] {
+        let mut bytes = [0u8; LATENCY_SIZE];
+        bytes[0..4].copy_from_slice(&self.count.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.average().to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.max.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.last.to_le_bytes());
+        bytes
+    }
+}
@@ -1,13 +1,32 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(feature = "nrf")]
 pub mod battery;
+pub mod bitmap;
+#[cfg(feature = "nrf")]
 pub mod bond;
 pub mod cirque;
 pub mod codes;
+#[cfg(feature = "nrf")]
+pub mod command;
 pub mod config;
+pub mod debounce;
 pub mod descriptor;
+pub mod gesture;
+pub mod keymap;
 pub mod keys;
+pub mod latency;
+#[cfg(feature = "nrf")]
 pub mod matrix;
+pub mod pointer;
+#[cfg(feature = "nrf")]
+pub mod power;
 pub mod report;
+#[cfg(feature = "nrf")]
+pub mod scanner;
+pub mod settings;
+#[cfg(feature = "nrf")]
 pub mod split;
 pub mod storage;
+pub mod transform;
+pub mod via;
@@ -1,120 +1,259 @@
-use embassy_futures::select::{select4, select_array, select_slice};
-use embassy_nrf::{
-    gpio::{AnyPin, Input, Output},
-    gpiote::{AnyChannel, InputChannel},
-};
-use embassy_time::{Duration, Instant};
+use embassy_futures::select::select_array;
+use embassy_nrf::gpio::{AnyPin, Flex, Level, OutputDrive, Pin, Pull};
+use embassy_time::{block_for, Duration};
 use embedded_hal_async::digital::Wait;
 use heapless::Vec;
-use rand::seq::IteratorRandom;
 
-const DEBOUNCE_TIME: u64 = 5;
+pub use crate::transform::{suppress_ghosts, Transform, LEFT_TRANSFORM, RIGHT_TRANSFORM};
+use crate::{
+    bitmap::KeyBitmap,
+    power::set_sense,
+    scanner::{Idle, KeyScanner},
+};
+
+/// Direction of the diodes, which decides which side of the matrix is driven
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiodeDirection {
+    /// Columns are driven and rows are read
+    Col2Row,
+    /// Rows are driven and columns are read
+    Row2Col,
+}
+
+/// How a scanner waits for a press once nothing has been pressed for a while
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IdleStrategy {
+    /// Drives every output and waits until a read line reaches the active
+    /// level. This is the wait the matrix already used: embassy's pin waits arm
+    /// each pin's SENSE and wake on the one GPIOTE PORT event, so any number of
+    /// pins can wake the scanner. Against the GPIOTE IN channels tried in
+    /// bin/ble.rs it saves the IN channel run current, 22 uA against 0.1 uA for
+    /// the PORT event in the nRF52840 datasheet. Against Poll it saves the scans
+    /// and the wakeups between them. Neither has been measured on the board
+    Sense,
+    /// Keeps scanning at the scan interval, for pins that can't wake the chip
+    Poll,
+}
+
 #[derive(Copy, Clone, Debug)]
-struct Debouncer {
-    state: bool,
-    debounced: Option<Instant>,
+pub struct MatrixConfig {
+    pub diode_direction: DiodeDirection,
+    /// Level a driven line is set to and a pressed key reads as. The read lines
+    /// are pulled to the opposite level
+    pub active_level: Level,
+    /// Suppresses ghost keys for matrices without diodes. See suppress_ghosts
+    pub ghost_detection: bool,
+    /// Time between driving an output and reading the inputs, for lines that
+    /// need to charge through long wires or high pull resistors
+    pub settle_time: Duration,
+    pub idle_strategy: IdleStrategy,
 }
 
-impl Debouncer {
-    const fn default() -> Debouncer {
+impl MatrixConfig {
+    /// Column to row diodes with active high outputs and pull-down inputs, as on the choc PCB
+    pub const fn default() -> Self {
         Self {
-            state: false,
-            debounced: None,
+            diode_direction: DiodeDirection::Col2Row,
+            active_level: Level::High,
+            ghost_detection: false,
+            settle_time: Duration::from_micros(0),
+            idle_strategy: IdleStrategy::Sense,
         }
     }
-    /// Returns the pressed status of the position
-    fn is_pressed(&self) -> bool {
-        self.state
+
+    fn inactive_level(&self) -> Level {
+        match self.active_level {
+            Level::High => Level::Low,
+            Level::Low => Level::High,
+        }
     }
+}
 
-    /// Updates the buf of the key. Updating the buf will also update
-    /// the value returned from the is_pressed function
-    fn update_buf(&mut self, buf: bool) {
-        match self.debounced {
-            Some(time) => {
-                if time.elapsed() > Duration::from_millis(DEBOUNCE_TIME) {
-                    self.debounced = None;
-                }
+pub(crate) fn is_active(pin: &Flex<'_, AnyPin>, level: Level) -> bool {
+    match level {
+        Level::High => pin.is_high(),
+        Level::Low => pin.is_low(),
+    }
+}
+
+/// Waits until one of the pins reads the level. Each pin's SENSE is set to
+/// the level and all of them share the GPIOTE PORT event. SENSE is cleared
+/// again when the futures are dropped
+pub(crate) async fn wait_for_any<const N: usize>(pins: &mut [Flex<'_, AnyPin>; N], level: Level) {
+    match level {
+        Level::High => {
+            let futures: Vec<_, N> = pins.iter_mut().map(|pin| pin.wait_for_high()).collect();
+            unsafe {
+                select_array(futures.into_array::<N>().unwrap_unchecked()).await;
             }
-            None => {
-                if buf != self.state {
-                    self.debounced = Some(Instant::now());
-                    self.state = buf;
-                }
+        }
+        Level::Low => {
+            let futures: Vec<_, N> = pins.iter_mut().map(|pin| pin.wait_for_low()).collect();
+            unsafe {
+                select_array(futures.into_array::<N>().unwrap_unchecked()).await;
             }
         }
     }
 }
-pub struct Matrix<'a, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> {
-    out: [Output<'a, AnyPin>; OUTPUT_SIZE],
-    input: [Input<'a, AnyPin>; INPUT_SIZE],
-    debouncers: [[Debouncer; OUTPUT_SIZE]; INPUT_SIZE],
-    pressed: Option<Instant>,
+
+pub struct Matrix<'a, const ROWS: usize, const COLS: usize> {
+    columns: [Flex<'a, AnyPin>; COLS],
+    rows: [Flex<'a, AnyPin>; ROWS],
+    // PSEL numbers of the pins, used to set their SENSE before System OFF
+    column_psels: [u8; COLS],
+    row_psels: [u8; ROWS],
+    config: MatrixConfig,
+    transform: Transform<ROWS, COLS>,
+    // States reported by the last scan, used by ghost detection
+    previous: [[bool; COLS]; ROWS],
+    idle: Idle,
 }
 
-impl<'a, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> Matrix<'a, INPUT_SIZE, OUTPUT_SIZE> {
-    pub fn new(
-        out: [Output<'a, AnyPin>; OUTPUT_SIZE],
-        input: [Input<'a, AnyPin>; INPUT_SIZE],
+impl<'a, const ROWS: usize, const COLS: usize> Matrix<'a, ROWS, COLS> {
+    /// Returns a matrix with the default config
+    pub fn new(columns: [AnyPin; COLS], rows: [AnyPin; ROWS]) -> Self {
+        Self::with_config(columns, rows, MatrixConfig::default())
+    }
+
+    /// Returns a matrix that drives and reads the pins as set in the config
+    pub fn with_config(
+        columns: [AnyPin; COLS],
+        rows: [AnyPin; ROWS],
+        config: MatrixConfig,
     ) -> Self {
+        let column_psels = columns.each_ref().map(|pin| pin.psel_bits() as u8);
+        let row_psels = rows.each_ref().map(|pin| pin.psel_bits() as u8);
+        let mut columns = columns.map(Flex::new);
+        let mut rows = rows.map(Flex::new);
+        let (outputs, inputs) = match config.diode_direction {
+            DiodeDirection::Col2Row => (&mut columns[..], &mut rows[..]),
+            DiodeDirection::Row2Col => (&mut rows[..], &mut columns[..]),
+        };
+        let pull = match config.active_level {
+            Level::High => Pull::Down,
+            Level::Low => Pull::Up,
+        };
+        for pin in outputs {
+            pin.set_level(config.inactive_level());
+            pin.set_as_output(OutputDrive::Standard);
+        }
+        for pin in inputs {
+            pin.set_as_input(pull);
+        }
         Self {
-            out,
-            input,
-            debouncers: [[Debouncer::default(); OUTPUT_SIZE]; INPUT_SIZE],
-            pressed: None,
+            columns,
+            rows,
+            column_psels,
+            row_psels,
+            config,
+            transform: Transform::identity(),
+            previous: [[false; COLS]; ROWS],
+            idle: Idle::new(),
         }
     }
 
-    // Only returns if a key is high or a previous scan had a key that was high. Otherwise,
-    // awaits for a high key
-    pub async fn scan(&mut self, states: &mut [[bool; OUTPUT_SIZE]; INPUT_SIZE]) {
-        // If no keys were pressed in the previous scan,
-        // we'll set all the output pins high and await
-        // for one of the channels to go high to save battery
-        if let Some(time) = self.pressed {
-            if time.elapsed() >= Duration::from_millis(DEBOUNCE_TIME) {
-                for power in &mut self.out {
-                    power.set_high();
-                }
+    pub fn set_settle_time(&mut self, settle_time: Duration) {
+        self.config.settle_time = settle_time;
+    }
 
-                // let mut high = false;
-                // for row in &mut self.input {
-                //     high = high || row.is_high()
-                // }
-
-                let futures: Vec<_, INPUT_SIZE> = self
-                    .input
-                    .iter_mut()
-                    .map(|pin| pin.wait_for_high())
-                    .collect();
-                unsafe {
-                    select_array(futures.into_array::<INPUT_SIZE>().unwrap_unchecked()).await;
-                }
+    pub fn set_idle_strategy(&mut self, idle_strategy: IdleStrategy) {
+        self.config.idle_strategy = idle_strategy;
+    }
 
-                for power in &mut self.out {
-                    power.set_low();
-                }
+    /// Waits for the driven output to settle
+    fn settle(&self) {
+        if self.config.settle_time.as_ticks() > 0 {
+            block_for(self.config.settle_time);
+        }
+    }
+
+    /// Sets the transform used to number the keys in scan_keys
+    pub fn set_transform(&mut self, transform: Transform<ROWS, COLS>) {
+        self.transform = transform;
+    }
+
+    /// Drives every output to the level
+    fn set_outputs(&mut self, level: Level) {
+        let outputs = match self.config.diode_direction {
+            DiodeDirection::Col2Row => &mut self.columns[..],
+            DiodeDirection::Row2Col => &mut self.rows[..],
+        };
+        for pin in outputs {
+            pin.set_level(level);
+        }
+    }
+
+    /// Drives every output and sets the SENSE of the inputs, so a key press
+    /// wakes the chip from System OFF. Pin configs are kept in System OFF
+    pub fn prepare_wakeup(&mut self) {
+        let active = self.config.active_level;
+        self.set_outputs(active);
+        let inputs = match self.config.diode_direction {
+            DiodeDirection::Col2Row => &self.row_psels[..],
+            DiodeDirection::Row2Col => &self.column_psels[..],
+        };
+        for psel in inputs {
+            set_sense(*psel, active);
+        }
+    }
+
+    // Only returns if a key is pressed or a previous scan had a key that was pressed.
+    // Otherwise, awaits for a pressed key unless the idle strategy is Poll
+    pub async fn scan(&mut self, states: &mut [[bool; COLS]; ROWS]) {
+        let active = self.config.active_level;
+        let inactive = self.config.inactive_level();
+        // If no keys were pressed in the previous scan,
+        // we'll drive all the outputs and await
+        // for one of the inputs to become active to save battery
+        if self.config.idle_strategy == IdleStrategy::Sense && self.idle.is_idle() {
+            self.set_outputs(active);
+            match self.config.diode_direction {
+                DiodeDirection::Col2Row => wait_for_any(&mut self.rows, active).await,
+                DiodeDirection::Row2Col => wait_for_any(&mut self.columns, active).await,
             }
+            self.set_outputs(inactive);
         }
 
         let mut pressed = false;
-        for i in 0..OUTPUT_SIZE {
-            self.out[i].set_high();
-            for j in 0..INPUT_SIZE {
-                states[j][i] = self.input[j].is_high();
-                pressed = pressed || states[j][i];
+        match self.config.diode_direction {
+            DiodeDirection::Col2Row => {
+                for i in 0..COLS {
+                    self.columns[i].set_level(active);
+                    self.settle();
+                    for j in 0..ROWS {
+                        states[j][i] = is_active(&self.rows[j], active);
+                        pressed = pressed || states[j][i];
+                    }
+                    self.columns[i].set_level(inactive);
+                }
             }
-            self.out[i].set_low();
-        }
-        if pressed {
-            self.pressed = None;
-        } else {
-            match self.pressed {
-                Some(_) => {}
-                None => {
-                    self.pressed = Some(Instant::now());
+            DiodeDirection::Row2Col => {
+                for j in 0..ROWS {
+                    self.rows[j].set_level(active);
+                    self.settle();
+                    for i in 0..COLS {
+                        states[j][i] = is_active(&self.columns[i], active);
+                        pressed = pressed || states[j][i];
+                    }
+                    self.rows[j].set_level(inactive);
                 }
             }
         }
+        if self.config.ghost_detection {
+            suppress_ghosts(states, &self.previous);
+            self.previous = *states;
+        }
+        // Ghosts still count as activity so the idle wait doesn't return right away
+        self.idle.update(pressed);
+    }
+}
+
+impl<'a, const ROWS: usize, const COLS: usize, const N: usize> KeyScanner<N>
+    for Matrix<'a, ROWS, COLS>
+{
+    async fn scan_keys(&mut self) -> KeyBitmap<N> {
+        let mut states = [[false; COLS]; ROWS];
+        self.scan(&mut states).await;
+        self.transform.to_bitmap(&states)
     }
 }
@@ -0,0 +1,224 @@
+use embassy_time::{Duration, Instant};
+
+/// Fixed point gain of 1. Gains are in 1/256ths
+pub const GAIN_ONE: u16 = 256;
+
+/// Movement after a longer pause starts over from the slowest speed
+const MAX_INTERVAL: Duration = Duration::from_millis(100);
+/// Shortest interval speeds are measured over
+const MIN_INTERVAL_US: u64 = 1000;
+
+/// How the gain of the pointer changes with its speed. Speeds are in
+/// counts per second, before the gain is applied
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Curve {
+    /// The same gain at every speed
+    Linear { gain: u16 },
+    /// gain + factor * (speed / reference)^exponent, capped at max
+    Power {
+        gain: u16,
+        factor: u16,
+        reference: u16,
+        exponent: u8,
+        max: u16,
+    },
+    /// Like libinput's adaptive profile: the gain stays at gain up to the
+    /// threshold, then rises by accel per 1000 counts/s until it reaches max.
+    /// The speed is smoothed over the last two movements
+    Adaptive {
+        gain: u16,
+        threshold: u16,
+        accel: u16,
+        max: u16,
+    },
+}
+
+impl Curve {
+    /// Returns the gain at the speed
+    pub fn gain(&self, speed: u32) -> u32 {
+        match *self {
+            Curve::Linear { gain } => gain as u32,
+            Curve::Power {
+                gain,
+                factor,
+                reference,
+                exponent,
+                max,
+            } => {
+                let ratio = speed as u64 * GAIN_ONE as u64 / reference.max(1) as u64;
+                let mut power = GAIN_ONE as u64;
+                for _ in 0..exponent {
+                    power = (power * ratio / GAIN_ONE as u64).min(u32::MAX as u64);
+                }
+                (gain as u64 + factor as u64 * power / GAIN_ONE as u64).min(max as u64) as u32
+            }
+            Curve::Adaptive {
+                gain,
+                threshold,
+                accel,
+                max,
+            } => {
+                let over = speed.saturating_sub(threshold as u32).min(u16::MAX as u32);
+                (gain as u32 + over * accel as u32 / 1000).min(max.max(gain) as u32)
+            }
+        }
+    }
+}
+
+/// Applies a Curve to pointer movement. The fractions of a count left over
+/// after the gain are carried to the next movement, so slow movement with a
+/// gain below 1 still moves the pointer
+pub struct Accelerator {
+    curve: Curve,
+    last: Option<Instant>,
+    speed: u32,
+    // Remainders in 1/256ths of a count
+    rest_x: i32,
+    rest_y: i32,
+}
+
+impl Accelerator {
+    pub const fn new(curve: Curve) -> Self {
+        Self {
+            curve,
+            last: None,
+            speed: 0,
+            rest_x: 0,
+            rest_y: 0,
+        }
+    }
+
+    pub fn curve(&self) -> &Curve {
+        &self.curve
+    }
+
+    pub fn set_curve(&mut self, curve: Curve) {
+        self.curve = curve;
+        self.reset();
+    }
+
+    /// Drops the speed and the remainders, such as when the finger lifts
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.speed = 0;
+        self.rest_x = 0;
+        self.rest_y = 0;
+    }
+
+    /// Applies the gain to movement made at the passed in time
+    pub fn apply(&mut self, x: i16, y: i16, now: Instant) -> (i16, i16) {
+        if x == 0 && y == 0 {
+            return (0, 0);
+        }
+        let interval = match self.last {
+            Some(last) if now - last <= MAX_INTERVAL => (now - last).as_micros(),
+            _ => {
+                self.reset();
+                MAX_INTERVAL.as_micros()
+            }
+        };
+        self.last = Some(now);
+        let distance = isqrt((x as i32).pow(2) as u32 + (y as i32).pow(2) as u32) as u64;
+        let speed = (distance * 1_000_000 / interval.max(MIN_INTERVAL_US)).min(u32::MAX as u64);
+        let speed = match self.curve {
+            Curve::Adaptive { .. } if self.speed != 0 => (self.speed + speed as u32) / 2,
+            _ => speed as u32,
+        };
+        self.speed = speed;
+
+        let gain = self.curve.gain(speed) as i32;
+        let scale = |delta: i16, rest: &mut i32| {
+            let total = delta as i32 * gain + *rest;
+            let count = total / GAIN_ONE as i32;
+            *rest = total - count * GAIN_ONE as i32;
+            count.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        };
+        (scale(x, &mut self.rest_x), scale(y, &mut self.rest_y))
+    }
+}
+
+/// Integer square root, rounded down
+fn isqrt(value: u32) -> u32 {
+    let mut root = 0u32;
+    let mut bit = 1u32 << 30;
+    let mut value = value;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= root + bit {
+            value -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isqrt_rounds_down() {
+        assert_eq!(isqrt(0), 0);
+        assert_eq!(isqrt(24), 4);
+        assert_eq!(isqrt(25), 5);
+        assert_eq!(isqrt(u32::MAX), 65535);
+    }
+
+    #[test]
+    fn slow_movement_accumulates() {
+        let mut pointer = Accelerator::new(Curve::Linear { gain: GAIN_ONE / 4 });
+        let start = Instant::from_millis(1000);
+        let moved: i32 = (0..8)
+            .map(|i| pointer.apply(1, 0, start + Duration::from_millis(10 * i)).0 as i32)
+            .sum();
+        assert_eq!(moved, 2);
+    }
+
+    #[test]
+    fn power_curve_is_capped() {
+        let curve = Curve::Power {
+            gain: GAIN_ONE,
+            factor: GAIN_ONE,
+            reference: 1000,
+            exponent: 2,
+            max: 4 * GAIN_ONE,
+        };
+        assert_eq!(curve.gain(0), GAIN_ONE as u32);
+        assert_eq!(curve.gain(1000), 2 * GAIN_ONE as u32);
+        assert_eq!(curve.gain(100_000), 4 * GAIN_ONE as u32);
+    }
+
+    #[test]
+    fn adaptive_curve_starts_at_the_threshold() {
+        let curve = Curve::Adaptive {
+            gain: GAIN_ONE,
+            threshold: 500,
+            accel: 256,
+            max: 3 * GAIN_ONE,
+        };
+        assert_eq!(curve.gain(400), GAIN_ONE as u32);
+        assert_eq!(curve.gain(1500), GAIN_ONE as u32 + 256);
+        assert_eq!(curve.gain(60_000), 3 * GAIN_ONE as u32);
+    }
+
+    #[test]
+    fn fast_movement_is_accelerated() {
+        let curve = Curve::Adaptive {
+            gain: GAIN_ONE,
+            threshold: 500,
+            accel: 256,
+            max: 3 * GAIN_ONE,
+        };
+        let mut pointer = Accelerator::new(curve);
+        let start = Instant::from_millis(1000);
+        // The first movement after a pause isn't accelerated
+        assert_eq!(pointer.apply(20, 0, start), (20, 0));
+        let (x, _) = pointer.apply(20, 0, start + Duration::from_millis(10));
+        assert!(x > 20);
+    }
+}
@@ -0,0 +1,160 @@
+use core::cell::Cell;
+
+use defmt::info;
+use embassy_futures::select::select;
+use embassy_nrf::{gpio::Level, pac};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use nrf_softdevice::raw;
+
+/// Power states, from fully awake to powered down. Each state is entered once
+/// nothing has touched the PowerManager for its timeout
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, defmt::Format)]
+pub enum PowerState {
+    Active,
+    /// Higher BLE slave latency and a longer scan interval
+    Idle,
+    /// The trackpad stops being polled
+    Sleep,
+    /// The board should enter System OFF
+    Off,
+}
+
+/// Idle time before entering each PowerState
+#[derive(Copy, Clone, Debug)]
+pub struct PowerTimeouts {
+    pub idle: Duration,
+    pub sleep: Duration,
+    pub off: Duration,
+}
+
+impl PowerTimeouts {
+    pub const fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(30),
+            sleep: Duration::from_secs(5 * 60),
+            off: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+/// Scan interval used once the board isn't Active, if the configured one is shorter
+pub const IDLE_SCAN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Tracks the last activity, such as a scan with a pressed key or a sent
+/// report, and derives the PowerState from it. Shared between the loops of a
+/// board, so it only needs &self
+pub struct PowerManager {
+    last: Cell<Instant>,
+    timeouts: PowerTimeouts,
+    // Wakes the changed waiter when activity ends a lower power state
+    woken: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl PowerManager {
+    pub fn new(timeouts: PowerTimeouts) -> Self {
+        Self {
+            last: Cell::new(Instant::now()),
+            timeouts,
+            woken: Signal::new(),
+        }
+    }
+
+    /// Records activity, which puts the board back in the Active state
+    pub fn touch(&self) {
+        let state = self.state();
+        self.last.set(Instant::now());
+        if state != PowerState::Active {
+            self.woken.signal(());
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        let idle = self.last.get().elapsed();
+        if idle >= self.timeouts.off {
+            PowerState::Off
+        } else if idle >= self.timeouts.sleep {
+            PowerState::Sleep
+        } else if idle >= self.timeouts.idle {
+            PowerState::Idle
+        } else {
+            PowerState::Active
+        }
+    }
+
+    /// Returns the time the next lower power state is entered, if there is one
+    fn next_deadline(&self) -> Option<Instant> {
+        let timeout = match self.state() {
+            PowerState::Active => self.timeouts.idle,
+            PowerState::Idle => self.timeouts.sleep,
+            PowerState::Sleep => self.timeouts.off,
+            PowerState::Off => return None,
+        };
+        Some(self.last.get() + timeout)
+    }
+
+    /// Waits until the state differs from the passed in one and returns the
+    /// new state. Only one task may wait for changes at a time
+    pub async fn changed(&self, from: PowerState) -> PowerState {
+        self.woken.reset();
+        loop {
+            let state = self.state();
+            if state != from {
+                return state;
+            }
+            match self.next_deadline() {
+                Some(deadline) => {
+                    select(Timer::at(deadline), self.woken.wait()).await;
+                }
+                None => self.woken.wait().await,
+            }
+        }
+    }
+
+    /// Waits until the board should power down. Only one task may wait at a time
+    pub async fn wait_for_off(&self) {
+        let mut state = self.state();
+        while state != PowerState::Off {
+            state = self.changed(state).await;
+        }
+    }
+
+    /// Returns the scan interval to use in the current state
+    pub fn scan_interval(&self, interval: Duration) -> Duration {
+        match self.state() {
+            PowerState::Active => interval,
+            _ => interval.max(IDLE_SCAN_INTERVAL),
+        }
+    }
+}
+
+/// Sets the SENSE of a pin, by its PSEL number, so it wakes the chip from
+/// System OFF when it reaches the level. The rest of the pin config is kept
+pub fn set_sense(psel: u8, level: Level) {
+    let port = match psel >= 32 {
+        true => unsafe { &*pac::P1::ptr() },
+        false => unsafe { &*pac::P0::ptr() },
+    };
+    port.pin_cnf[(psel % 32) as usize].modify(|_, w| match level {
+        Level::High => w.sense().high(),
+        Level::Low => w.sense().low(),
+    });
+}
+
+/// Enters System OFF. Waking up from a SENSE pin resets the chip, so this
+/// never returns and the board starts again from main, reconnecting to its
+/// bonded host
+pub fn system_off() -> ! {
+    info!("Entering System OFF");
+    unsafe {
+        // Only returns if the SoftDevice isn't enabled, in which case the
+        // POWER peripheral can be used directly
+        raw::sd_power_system_off();
+        (*pac::POWER::ptr())
+            .systemoff
+            .write(|w| w.systemoff().enter());
+    }
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
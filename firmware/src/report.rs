@@ -1,12 +1,134 @@
+use defmt::warn;
 use embassy_time::{Duration, Instant};
 use heapless::{FnvIndexSet, Vec};
 use usbd_hid::descriptor::KeyboardReport;
 
 use crate::{
-    descriptor::{KeyboardReportNKRO, MouseReport},
-    keys::{Keys, ScanCode},
+    config::{MOUSE_KEY_CURVE, SCROLL_LAYER},
+    descriptor::{KeyboardReportNKRO, MouseReport16, ResolutionMultiplier},
+    keys::{Keys, ScanCode, NUM_LAYERS},
+    pointer::{Accelerator, Curve},
 };
 
+/// Reports a scroll key detent is spread over with high resolution scrolling
+const SCROLL_STEPS: i16 = 4;
+/// Taps that can wait for the next report
+const MAX_TAPS: usize = 4;
+
+/// Speeds of the mouse keys, in counts per second
+#[derive(Copy, Clone, Debug)]
+pub struct MouseKeysConfig {
+    /// Time between reports while a mouse key is held
+    pub interval: Duration,
+    pub start_speed: u16,
+    pub max_speed: u16,
+    /// Time a direction has to be held to reach max_speed
+    pub time_to_max: Duration,
+    /// Speed in percent while a precision key is held
+    pub precision: u16,
+    /// Speed in percent while a turbo key is held
+    pub turbo: u16,
+}
+
+impl MouseKeysConfig {
+    pub const fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(10),
+            start_speed: 250,
+            max_speed: 1500,
+            time_to_max: Duration::from_millis(1000),
+            precision: 25,
+            turbo: 200,
+        }
+    }
+}
+
+/// Turns the held mouse direction keys into a velocity that ramps up from
+/// start_speed to max_speed, and the velocity into movement. Diagonals move
+/// at the same speed as straight lines
+pub struct MouseKeys {
+    config: MouseKeysConfig,
+    // Time the keys started moving the pointer
+    start: Option<Instant>,
+    last: Instant,
+    // Movement that didn't make a full count yet, in millionths of a count
+    rest_x: i64,
+    rest_y: i64,
+}
+
+impl MouseKeys {
+    pub fn new(config: MouseKeysConfig) -> Self {
+        Self {
+            config,
+            start: None,
+            last: Instant::now(),
+            rest_x: 0,
+            rest_y: 0,
+        }
+    }
+
+    pub fn config(&self) -> &MouseKeysConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: MouseKeysConfig) {
+        self.config = config;
+    }
+
+    /// Returns the speed after the direction was held for the passed in time
+    fn speed(&self, held: Duration, modifier: i8) -> i64 {
+        let start = self.config.start_speed as i64;
+        let max = (self.config.max_speed as i64).max(start);
+        let ramp = self.config.time_to_max.as_micros() as i64;
+        let speed = match ramp {
+            0 => max,
+            _ => start + (max - start) * (held.as_micros() as i64).min(ramp) / ramp,
+        };
+        match modifier.signum() {
+            -1 => speed * self.config.precision as i64 / 100,
+            1 => speed * self.config.turbo as i64 / 100,
+            _ => speed,
+        }
+    }
+
+    /// Moves the pointer in the direction of x and y, each -1, 0 or 1, with
+    /// the speed modifier of the held precision or turbo keys. Returns the
+    /// movement since the last update
+    pub fn update(&mut self, x: i8, y: i8, modifier: i8, now: Instant) -> (i16, i16) {
+        let (x, y) = (x.signum() as i64, y.signum() as i64);
+        if x == 0 && y == 0 {
+            self.start = None;
+            self.rest_x = 0;
+            self.rest_y = 0;
+            self.last = now;
+            return (0, 0);
+        }
+        let interval = self.config.interval.as_micros() as i64;
+        let elapsed = match self.start {
+            Some(_) => ((now - self.last).as_micros() as i64).min(2 * interval),
+            // The first step is made right away
+            None => {
+                self.start = Some(now);
+                interval
+            }
+        };
+        self.last = now;
+        let held = now - self.start.unwrap_or(now);
+        let mut speed = self.speed(held, modifier);
+        if x != 0 && y != 0 {
+            // 1 / sqrt(2) in 1/256ths
+            speed = speed * 181 / 256;
+        }
+        let step = |direction: i64, rest: &mut i64| {
+            let total = direction * speed * elapsed + *rest;
+            let count = total / 1_000_000;
+            *rest = total - count * 1_000_000;
+            count.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+        };
+        (step(x, &mut self.rest_x), step(y, &mut self.rest_y))
+    }
+}
+
 fn set_bit(num: &mut u8, bit: u8, pos: u8) {
     let mask = 1 << pos;
     if bit == 1 {
@@ -18,34 +140,106 @@ fn set_bit(num: &mut u8, bit: u8, pos: u8) {
 
 pub struct Report {
     key_report: KeyboardReport,
-    mouse_report: MouseReport,
+    mouse_report: MouseReport16,
+    mouse_keys: MouseKeys,
+    pointer: Accelerator,
     last_report_time: Instant,
     current_layer: usize,
     reset_layer: usize,
+    resolution: ResolutionMultiplier,
+    // Wheel units from the scroll keys that weren't sent yet
+    pending_wheel: i16,
+    // Direction of the scroll keys in the last call, to find their presses
+    scroll_held: i8,
+    scroll_layer: Option<usize>,
+    // Trackpad scrolling latched by the scroll toggle key
+    scroll_latched: bool,
+    scroll_key_held: bool,
+    // Codes pressed without a key, such as by the trackpad corners
+    taps: Vec<ScanCode, MAX_TAPS>,
 }
 impl Report {
     pub fn default() -> Self {
         Self {
             key_report: KeyboardReport::default(),
-            mouse_report: MouseReport::default(),
+            mouse_report: MouseReport16::default(),
+            mouse_keys: MouseKeys::new(MouseKeysConfig::default()),
+            pointer: Accelerator::new(MOUSE_KEY_CURVE),
             last_report_time: Instant::now(),
             current_layer: 0,
             reset_layer: 0,
+            resolution: ResolutionMultiplier::default(),
+            pending_wheel: 0,
+            scroll_held: 0,
+            scroll_layer: SCROLL_LAYER,
+            scroll_latched: false,
+            scroll_key_held: false,
+            taps: Vec::new(),
+        }
+    }
+
+    /// Returns the layer the last report was generated on
+    pub fn layer(&self) -> usize {
+        self.current_layer
+    }
+
+    /// Sets the resolution multipliers of the host, which the scroll keys
+    /// are scaled by
+    pub fn set_resolution(&mut self, resolution: ResolutionMultiplier) {
+        if resolution != self.resolution {
+            self.resolution = resolution;
+            self.pending_wheel = 0;
+        }
+    }
+
+    /// Sets the layer on which trackpad movement scrolls, or None to only
+    /// scroll with the toggle key
+    pub fn set_scroll_layer(&mut self, layer: Option<usize>) {
+        self.scroll_layer = layer;
+    }
+
+    /// Returns true if trackpad movement should scroll, either because the
+    /// scroll layer is active or the toggle key latched it
+    pub fn scroll_active(&self) -> bool {
+        self.scroll_latched || self.scroll_layer == Some(self.current_layer)
+    }
+
+    /// Presses the code without a key, such as for a trackpad corner tap. The
+    /// code is held until a mouse report is due and released after it
+    pub fn tap(&mut self, code: ScanCode) {
+        if self.taps.push(code).is_err() {
+            warn!("Too many taps, dropping one");
         }
     }
 
+    /// Sets the speeds of the mouse keys
+    pub fn set_mouse_keys(&mut self, config: MouseKeysConfig) {
+        self.mouse_keys.set_config(config);
+    }
+
+    /// Sets the acceleration of the mouse keys
+    pub fn set_pointer_curve(&mut self, curve: Curve) {
+        self.pointer.set_curve(curve);
+    }
+
     /// Generates a report with the provided keys. Returns a option tuple
     /// where it returns a Some when a report need to be sent
     pub fn generate_report<const S: usize>(
         &mut self,
         keys: &mut Keys<S>,
-    ) -> (Option<&KeyboardReport>, Option<&MouseReport>) {
+    ) -> (Option<&KeyboardReport>, Option<&MouseReport16>) {
         let mut new_layer = None;
         let mut pressed_keys = Vec::<ScanCode, 64>::new();
         let mut new_key_report = KeyboardReport::default();
-        let mut new_mouse_report = MouseReport::default();
+        let mut new_mouse_report = MouseReport16::default();
+        let (mut mouse_x, mut mouse_y, mut mouse_speed) = (0i8, 0i8, 0i8);
+        let mut scroll_key = false;
+        let mut scroll = 0i8;
 
         keys.get_keys(self.current_layer, &mut pressed_keys);
+        for code in &self.taps {
+            let _ = pressed_keys.push(*code);
+        }
         let mut index = 0;
         for key in &pressed_keys {
             match key {
@@ -67,14 +261,18 @@ impl Report {
                     set_bit(&mut new_mouse_report.buttons, 1, b_idx);
                 }
                 ScanCode::MouseX(code) => {
-                    new_mouse_report.x += code * 10;
+                    mouse_x = mouse_x.saturating_add(*code);
                 }
                 ScanCode::MouseY(code) => {
-                    new_mouse_report.y += code * 10;
+                    mouse_y = mouse_y.saturating_add(*code);
                 }
                 ScanCode::Scroll(code) => {
-                    new_mouse_report.wheel += code;
+                    scroll = scroll.saturating_add(*code);
                 }
+                ScanCode::MouseSpeed(code) => {
+                    mouse_speed = mouse_speed.saturating_add(*code);
+                }
+                ScanCode::ScrollMode => scroll_key = true,
                 ScanCode::Layer(layer) => match new_layer {
                     Some(_) => {
                         if layer.toggle {
@@ -90,15 +288,29 @@ impl Report {
         }
         match new_layer {
             Some(layer) => {
+                // Keys has no codes past the last layer
+                let pos = layer.pos.min(NUM_LAYERS - 1);
                 if layer.toggle {
-                    self.reset_layer = layer.pos;
+                    self.reset_layer = pos;
                 }
-                self.current_layer = layer.pos;
+                self.current_layer = pos;
             }
             None => {
                 self.current_layer = self.reset_layer;
             }
         }
+        if scroll_key && !self.scroll_key_held {
+            self.scroll_latched = !self.scroll_latched;
+        }
+        self.scroll_key_held = scroll_key;
+        // A scroll key turns one detent per press. Interval keys repeat by
+        // pressing again, so holding one doesn't pile up detents
+        if scroll != 0 && scroll != self.scroll_held {
+            let detent = self.resolution.wheel as i16;
+            self.pending_wheel =
+                (self.pending_wheel + scroll as i16 * detent).clamp(-detent, detent);
+        }
+        self.scroll_held = scroll;
         let mut key_report = None;
         let mut mouse_report = None;
         if self.key_report.keycodes != new_key_report.keycodes
@@ -107,11 +319,23 @@ impl Report {
             self.key_report = new_key_report;
             key_report = Some(&self.key_report)
         }
+        let ready = self.last_report_time.elapsed() >= self.mouse_keys.config().interval;
+        if ready {
+            let now = Instant::now();
+            let (x, y) = self.mouse_keys.update(mouse_x, mouse_y, mouse_speed, now);
+            (new_mouse_report.x, new_mouse_report.y) = self.pointer.apply(x, y, now);
+            // With high resolution scrolling a detent is spread over a few reports
+            let step = (self.resolution.wheel as i16 / SCROLL_STEPS).max(1);
+            let wheel = self.pending_wheel.clamp(-step, step);
+            self.pending_wheel -= wheel;
+            new_mouse_report.wheel = wheel as i8;
+            self.taps.clear();
+        }
         if (self.mouse_report.buttons != new_mouse_report.buttons
             || new_mouse_report.x != 0
             || new_mouse_report.y != 0
             || new_mouse_report.wheel != 0)
-            && self.last_report_time.elapsed() >= Duration::from_millis(20)
+            && ready
         {
             self.last_report_time = Instant::now();
             self.mouse_report = new_mouse_report;
@@ -120,3 +344,134 @@ impl Report {
         (key_report, mouse_report)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codes::KeyCodes;
+    use crate::keymap::KeyAction;
+
+    fn mouse_keys() -> MouseKeys {
+        MouseKeys {
+            config: MouseKeysConfig::default(),
+            start: None,
+            last: Instant::from_millis(0),
+            rest_x: 0,
+            rest_y: 0,
+        }
+    }
+
+    #[test]
+    fn speed_ramps_up_to_the_max() {
+        let mut keys = mouse_keys();
+        let start = Instant::from_millis(1000);
+        // 250 counts/s for 10ms
+        assert_eq!(keys.update(1, 0, 0, start), (2, 0));
+        let mut now = start;
+        for _ in 0..200 {
+            now += Duration::from_millis(10);
+            keys.update(1, 0, 0, now);
+        }
+        // 1500 counts/s for 10ms
+        assert_eq!(
+            keys.update(1, 0, 0, now + Duration::from_millis(10)),
+            (15, 0)
+        );
+    }
+
+    #[test]
+    fn diagonals_move_at_the_same_speed() {
+        let mut keys = mouse_keys();
+        let now = Instant::from_millis(1000);
+        keys.update(1, -1, 1, now);
+        let (x, y) = keys.update(1, -1, 1, now + Duration::from_millis(20));
+        assert_eq!((x, y), (8, -8));
+    }
+
+    #[test]
+    fn precision_movement_accumulates() {
+        let mut keys = mouse_keys();
+        let mut now = Instant::from_millis(1000);
+        let mut moved = keys.update(0, 1, -1, now).1;
+        for _ in 0..9 {
+            now += Duration::from_millis(10);
+            moved += keys.update(0, 1, -1, now).1;
+        }
+        // About 76 counts/s over 100ms
+        assert_eq!(moved, 7);
+    }
+
+    #[test]
+    fn taps_are_released_after_one_report() {
+        let mut keys = Keys::<1>::default();
+        let mut report = Report::default();
+        report.set_mouse_keys(MouseKeysConfig {
+            interval: Duration::from_ticks(0),
+            ..MouseKeysConfig::default()
+        });
+        report.tap(ScanCode::Letter(0x04));
+        report.tap(ScanCode::MouseButton(1));
+        let (key, mouse) = report.generate_report(&mut keys);
+        assert_eq!(key.unwrap().keycodes[0], 0x04);
+        assert_eq!(mouse.unwrap().buttons, 0b10);
+        let (key, mouse) = report.generate_report(&mut keys);
+        assert_eq!(key.unwrap().keycodes[0], 0);
+        assert_eq!(mouse.unwrap().buttons, 0);
+    }
+
+    #[test]
+    fn held_scroll_key_stops_after_release() {
+        let mut keys = Keys::<1>::default();
+        keys.set_code(KeyCodes::MouseScrollUp, 0, 0);
+        let mut report = Report::default();
+        report.set_resolution(ResolutionMultiplier { wheel: 4, pan: 1 });
+        report.set_mouse_keys(MouseKeysConfig {
+            interval: Duration::from_ticks(0),
+            ..MouseKeysConfig::default()
+        });
+        keys.update_buf(0, true);
+        let mut wheel = 0;
+        for _ in 0..100 {
+            if let (_, Some(mouse)) = report.generate_report(&mut keys) {
+                wheel += mouse.wheel as i32;
+            }
+        }
+        // One detent for the press, spread over the high resolution steps
+        assert_eq!(wheel, 4);
+        keys.update_buf(0, false);
+        for _ in 0..100 {
+            if let (_, Some(mouse)) = report.generate_report(&mut keys) {
+                assert_eq!(mouse.wheel, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn layers_past_the_last_are_clamped() {
+        let mut keys = Keys::<2>::default();
+        keys.set_action(
+            KeyAction::Code(KeyCodes::Layer0 as u8 + NUM_LAYERS as u8),
+            0,
+            0,
+        );
+        let mut report = Report::default();
+        keys.update_buf(0, true);
+        report.generate_report(&mut keys);
+        assert_eq!(report.layer(), NUM_LAYERS - 1);
+        // The next report looks the other keys up on the clamped layer
+        report.generate_report(&mut keys);
+        assert_eq!(report.layer(), NUM_LAYERS - 1);
+    }
+
+    #[test]
+    fn releasing_stops_the_pointer() {
+        let mut keys = mouse_keys();
+        let now = Instant::from_millis(1000);
+        keys.update(-1, 0, 0, now);
+        assert_eq!(
+            keys.update(0, 0, 0, now + Duration::from_millis(10)),
+            (0, 0)
+        );
+        assert_eq!(keys.start, None);
+    }
+}
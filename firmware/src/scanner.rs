@@ -0,0 +1,217 @@
+use defmt::error;
+use embassy_nrf::{
+    gpio::{AnyPin, Flex, Level, Output, Pull},
+    spim::{Instance, Spim},
+};
+use embassy_time::{block_for, Duration, Instant};
+
+use crate::{
+    bitmap::KeyBitmap,
+    debounce::DEBOUNCE_TIME,
+    matrix::{is_active, wait_for_any, Transform},
+};
+
+/// Reads the raw state of N keys, however they're wired
+#[allow(async_fn_in_trait)]
+pub trait KeyScanner<const N: usize> {
+    /// Scans every key. Once nothing has been pressed for a while, waits for a
+    /// press to save battery
+    async fn scan_keys(&mut self) -> KeyBitmap<N>;
+}
+
+/// Time nothing has to be pressed for before the scanners wait for a press. It's
+/// longer than DEBOUNCE_TIME, so the deferred debouncers get a scan late enough
+/// to let a release through before the wait
+const IDLE_TIME: u64 = 2 * DEBOUNCE_TIME;
+
+/// Tracks how long nothing has been pressed, so scanners can wait for a
+/// press instead of scanning once the keys have settled
+#[derive(Copy, Clone, Debug)]
+pub struct Idle {
+    since: Option<Instant>,
+    last_scan: Instant,
+}
+
+impl Idle {
+    pub const fn new() -> Self {
+        Self {
+            since: None,
+            last_scan: Instant::from_ticks(0),
+        }
+    }
+
+    /// Returns true once the last scan read nothing pressed for more than IDLE_TIME.
+    /// Counting to the last scan instead of now makes sure the keys were scanned
+    /// past the debounce time, even if scans are further apart than it
+    pub fn is_idle(&self) -> bool {
+        match self.since {
+            Some(time) => {
+                self.last_scan.saturating_duration_since(time) > Duration::from_millis(IDLE_TIME)
+            }
+            None => false,
+        }
+    }
+
+    /// Updates the timer with whether anything was pressed in the last scan
+    pub fn update(&mut self, pressed: bool) {
+        self.last_scan = Instant::now();
+        if pressed {
+            self.since = None;
+        } else {
+            match self.since {
+                Some(_) => {}
+                None => {
+                    self.since = Some(self.last_scan);
+                }
+            }
+        }
+    }
+}
+
+fn pull(active_level: Level) -> Pull {
+    match active_level {
+        Level::High => Pull::Down,
+        Level::Low => Pull::Up,
+    }
+}
+
+/// Scans boards with one GPIO per key. Pin i is key i
+pub struct DirectPins<'a, const N: usize> {
+    pins: [Flex<'a, AnyPin>; N],
+    active_level: Level,
+    idle: Idle,
+}
+
+impl<'a, const N: usize> DirectPins<'a, N> {
+    /// Returns a scanner for switches that connect their pin to the active level.
+    /// The pins are pulled to the opposite level
+    pub fn new(pins: [AnyPin; N], active_level: Level) -> Self {
+        let mut pins = pins.map(Flex::new);
+        for pin in &mut pins {
+            pin.set_as_input(pull(active_level));
+        }
+        Self {
+            pins,
+            active_level,
+            idle: Idle::new(),
+        }
+    }
+}
+
+impl<'a, const N: usize> KeyScanner<N> for DirectPins<'a, N> {
+    async fn scan_keys(&mut self) -> KeyBitmap<N> {
+        if self.idle.is_idle() {
+            wait_for_any(&mut self.pins, self.active_level).await;
+        }
+        let mut bitmap = KeyBitmap::new();
+        for (i, pin) in self.pins.iter().enumerate() {
+            bitmap.set(i, is_active(pin, self.active_level));
+        }
+        self.idle.update(!bitmap.is_empty());
+        bitmap
+    }
+}
+
+/// Scans a matrix whose columns are driven by a chain of 74HC595 shift registers
+/// over SPI, with the rows read by GPIOs. Column i is output Qi%8 of register
+/// i / 8, counting from QA of the register connected to the SPI output. 1 to
+/// 32 columns are supported
+pub struct ShiftRegisterMatrix<'a, T: Instance, const ROWS: usize, const COLS: usize> {
+    spi: Spim<'a, T>,
+    latch: Output<'a, AnyPin>,
+    rows: [Flex<'a, AnyPin>; ROWS],
+    active_level: Level,
+    transform: Transform<ROWS, COLS>,
+    settle_time: Duration,
+    idle: Idle,
+}
+
+impl<'a, T: Instance, const ROWS: usize, const COLS: usize> ShiftRegisterMatrix<'a, T, ROWS, COLS> {
+    // The columns are shifted out as a u32
+    const COLS_FIT: () = assert!(COLS > 0 && COLS <= 32, "1 to 32 columns are supported");
+
+    /// Returns a scanner that drives one column at a time to the active level.
+    /// The latch pin is connected to RCLK of every register
+    pub fn new(
+        spi: Spim<'a, T>,
+        latch: Output<'a, AnyPin>,
+        rows: [AnyPin; ROWS],
+        active_level: Level,
+    ) -> Self {
+        let () = Self::COLS_FIT;
+        let mut rows = rows.map(Flex::new);
+        for pin in &mut rows {
+            pin.set_as_input(pull(active_level));
+        }
+        Self {
+            spi,
+            latch,
+            rows,
+            active_level,
+            transform: Transform::identity(),
+            settle_time: Duration::from_micros(0),
+            idle: Idle::new(),
+        }
+    }
+
+    /// Sets the time between latching the columns and reading the rows
+    pub fn set_settle_time(&mut self, settle_time: Duration) {
+        self.settle_time = settle_time;
+    }
+
+    /// Waits for the latched columns to settle, like Matrix::settle
+    fn settle(&self) {
+        if self.settle_time.as_ticks() > 0 {
+            block_for(self.settle_time);
+        }
+    }
+
+    /// Sets the transform used to number the keys
+    pub fn set_transform(&mut self, transform: Transform<ROWS, COLS>) {
+        self.transform = transform;
+    }
+
+    /// Shifts out the columns, setting the columns in the mask to the active level
+    /// and the rest to the opposite level
+    async fn write_columns(&mut self, mask: u32) {
+        let bits = match self.active_level {
+            Level::High => mask,
+            Level::Low => !mask,
+        };
+        // The first byte shifted out ends up in the last register of the chain
+        let len = (COLS + 7) / 8;
+        let bytes = bits.to_be_bytes();
+        self.latch.set_low();
+        match self.spi.write(&bytes[4 - len..]).await {
+            Ok(_) => {}
+            Err(_) => error!("shift register write failed"),
+        }
+        self.latch.set_high();
+    }
+}
+
+impl<'a, T: Instance, const ROWS: usize, const COLS: usize, const N: usize> KeyScanner<N>
+    for ShiftRegisterMatrix<'a, T, ROWS, COLS>
+{
+    async fn scan_keys(&mut self) -> KeyBitmap<N> {
+        let all = (u64::MAX >> (64 - COLS)) as u32;
+        if self.idle.is_idle() {
+            self.write_columns(all).await;
+            wait_for_any(&mut self.rows, self.active_level).await;
+        }
+
+        let mut states = [[false; COLS]; ROWS];
+        let mut pressed = false;
+        for col in 0..COLS {
+            self.write_columns(1 << col).await;
+            self.settle();
+            for row in 0..ROWS {
+                states[row][col] = is_active(&self.rows[row], self.active_level);
+                pressed = pressed || states[row][col];
+            }
+        }
+        self.write_columns(0).await;
+        self.idle.update(pressed);
+        self.transform.to_bitmap(&states)
+    }
+}
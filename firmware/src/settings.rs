@@ -0,0 +1,155 @@
+use defmt::info;
+use embassy_time::Duration;
+use embedded_storage_async::nor_flash::NorFlash;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    keys::Keys,
+    storage::{Storage, StorageItem, ITEM_BUFFER_SIZE},
+};
+
+const SETTINGS_VERSION: u8 = 2;
+const SETTINGS_SIZE: usize = 6;
+// Size of the version 1 settings, which only had debounce
+const SETTINGS_V1_SIZE: usize = 2;
+pub const SETTINGS_KEY: u32 = 0x300;
+
+// Largest accepted values, in microseconds
+const MAX_SCAN_INTERVAL: u16 = 10_000;
+const MAX_SETTLE_TIME: u16 = 100;
+
+/// Ids used to get and set a single setting from the host
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SettingId {
+    Debounce = 0,
+    /// Delay between matrix scans in microseconds
+    ScanInterval = 1,
+    /// Delay between driving a matrix output and reading the inputs in microseconds
+    SettleTime = 2,
+}
+
+impl SettingId {
+    pub fn from_raw(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(SettingId::Debounce),
+            1 => Some(SettingId::ScanInterval),
+            2 => Some(SettingId::SettleTime),
+            _ => None,
+        }
+    }
+}
+
+/// Board settings that can be changed without reflashing
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    pub debounce: bool,
+    pub scan_interval_us: u16,
+    pub settle_time_us: u16,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            debounce: true,
+            scan_interval_us: 5,
+            settle_time_us: 0,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings stored in flash. Returns the default settings if none are stored
+    pub async fn load<F: NorFlash>(storage: &Storage<F, u32>) -> Self {
+        let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+        match storage
+            .get_item::<Settings>(SETTINGS_KEY, &mut buffer)
+            .await
+        {
+            Some(settings) => settings,
+            None => {
+                info!("No settings stored, loading default");
+                Self::default()
+            }
+        }
+    }
+
+    /// Sends the settings to the storage channel
+    pub async fn save<F: NorFlash>(&self, storage: &Storage<F, u32>) {
+        storage
+            .send_item(&SETTINGS_KEY, &StorageItem::Settings(*self))
+            .await;
+    }
+
+    /// Applies the settings to the keys. The scan timings are read by the board loop
+    pub fn apply<const S: usize>(&self, keys: &mut Keys<S>) {
+        keys.set_debounce(0..S as u8, self.debounce);
+    }
+
+    pub fn scan_interval(&self) -> Duration {
+        Duration::from_micros(self.scan_interval_us as u64)
+    }
+
+    pub fn settle_time(&self) -> Duration {
+        Duration::from_micros(self.settle_time_us as u64)
+    }
+
+    pub fn get(&self, id: SettingId) -> u16 {
+        match id {
+            SettingId::Debounce => self.debounce as u16,
+            SettingId::ScanInterval => self.scan_interval_us,
+            SettingId::SettleTime => self.settle_time_us,
+        }
+    }
+
+    /// Sets the setting. Returns false if the value is out of range for the setting
+    pub fn set(&mut self, id: SettingId, value: u16) -> bool {
+        match id {
+            SettingId::Debounce if value <= 1 => {
+                self.debounce = value == 1;
+                true
+            }
+            SettingId::ScanInterval if value <= MAX_SCAN_INTERVAL => {
+                self.scan_interval_us = value;
+                true
+            }
+            SettingId::SettleTime if value <= MAX_SETTLE_TIME => {
+                self.settle_time_us = value;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Value<'a> for Settings {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < SETTINGS_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = SETTINGS_VERSION;
+        buffer[1] = self.debounce as u8;
+        buffer[2..4].copy_from_slice(&self.scan_interval_us.to_le_bytes());
+        buffer[4..6].copy_from_slice(&self.settle_time_us.to_le_bytes());
+        Ok(SETTINGS_SIZE)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        match buffer.first() {
+            // Settings stored before the scan timings keep the default timings
+            Some(1) if buffer.len() >= SETTINGS_V1_SIZE => Ok(Self {
+                debounce: buffer[1] != 0,
+                ..Self::default()
+            }),
+            Some(&SETTINGS_VERSION) if buffer.len() >= SETTINGS_SIZE => Ok(Self {
+                debounce: buffer[1] != 0,
+                scan_interval_us: u16::from_le_bytes([buffer[2], buffer[3]]),
+                settle_time_us: u16::from_le_bytes([buffer[4], buffer[5]]),
+            }),
+            _ => Err(SerializationError::InvalidFormat),
+        }
+    }
+}
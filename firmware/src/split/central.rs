@@ -1,6 +1,9 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use defmt::{error, info};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
 use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
 use nrf_softdevice::{
     ble::{
         advertisement_builder::{
@@ -20,11 +23,13 @@ use nrf_softdevice::{
     raw, Softdevice,
 };
 use static_cell::StaticCell;
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+use usbd_hid::descriptor::KeyboardReport;
 
 use crate::{
     bond::Bonder,
-    descriptor::{CombinedReport, MouseReport},
+    command::{CommandId, Status, PACKET_SIZE},
+    descriptor::{MouseReport16, ResolutionMultiplier, COMBINED_DESCRIPTOR},
+    power::PowerState,
     storage::Storage,
 };
 
@@ -45,8 +50,53 @@ const REPORT_MAP: Uuid = Uuid::new_16(0x2a4b);
 const HID_CONTROL_POINT: Uuid = Uuid::new_16(0x2a4c);
 const HID_REPORT: Uuid = Uuid::new_16(0x2a4d);
 const PROTOCOL_MODE: Uuid = Uuid::new_16(0x2a4e);
+const BOOT_MOUSE_INPUT: Uuid = Uuid::new_16(0x2a33);
 
 const KEYBOARD_ID: u8 = 0x01;
+const MOUSE_ID: u8 = 0x02;
+// Report types of the report reference descriptor
+const FEATURE_REPORT: u8 = 0x03;
+// Protocol mode values, the host switches to boot to get 8 bit mouse reports
+const BOOT_PROTOCOL: u8 = 0x00;
+const REPORT_PROTOCOL: u8 = 0x01;
+
+// Connection parameters requested from the host. Intervals are in 1.25ms units and
+// the supervision timeout in 10ms units, which has to stay above
+// (1 + slave_latency) * max_conn_interval * 2
+const ACTIVE_CONN_PARAMS: raw::ble_gap_conn_params_t = raw::ble_gap_conn_params_t {
+    min_conn_interval: 6,
+    max_conn_interval: 6,
+    slave_latency: 99,
+    conn_sup_timeout: 400,
+};
+const IDLE_CONN_PARAMS: raw::ble_gap_conn_params_t = raw::ble_gap_conn_params_t {
+    min_conn_interval: 6,
+    max_conn_interval: 6,
+    slave_latency: 299,
+    conn_sup_timeout: 600,
+};
+
+// Vendor config service. UUIDs are little endian
+const CONFIG_SERVICE: Uuid = Uuid::new_128(&[
+    0x38, 0xcf, 0x64, 0x0a, 0xc3, 0xfb, 0x10, 0x9f, 0xeb, 0x11, 0x54, 0x23, 0xe0, 0x12, 0x73, 0x9e,
+]);
+const CONFIG_KEYMAP: Uuid = Uuid::new_128(&[
+    0x39, 0xcf, 0x64, 0x0a, 0xc3, 0xfb, 0x10, 0x9f, 0xeb, 0x11, 0x54, 0x23, 0xe0, 0x12, 0x73, 0x9e,
+]);
+const CONFIG_LAYER: Uuid = Uuid::new_128(&[
+    0x3a, 0xcf, 0x64, 0x0a, 0xc3, 0xfb, 0x10, 0x9f, 0xeb, 0x11, 0x54, 0x23, 0xe0, 0x12, 0x73, 0x9e,
+]);
+const CONFIG_SETTINGS: Uuid = Uuid::new_128(&[
+    0x3b, 0xcf, 0x64, 0x0a, 0xc3, 0xfb, 0x10, 0x9f, 0xeb, 0x11, 0x54, 0x23, 0xe0, 0x12, 0x73, 0x9e,
+]);
+const CONFIG_COMMAND: Uuid = Uuid::new_128(&[
+    0x3c, 0xcf, 0x64, 0x0a, 0xc3, 0xfb, 0x10, 0x9f, 0xeb, 0x11, 0x54, 0x23, 0xe0, 0x12, 0x73, 0x9e,
+]);
+// Layer, index and the KeyAction bytes
+const KEYMAP_VALUE_SIZE: usize = 6;
+// Setting id and the u16 value
+const SETTINGS_VALUE_SIZE: usize = 3;
+const CONFIG_CHANNEL_SIZE: usize = 4;
 
 #[repr(u8)]
 #[derive(Clone, Copy)]
@@ -201,6 +251,11 @@ pub struct HidService {
     input_mouse: u16,
     input_mouse_cccd: u16,
     intput_mouse_descriptor: u16,
+    feature_mouse: u16,
+    boot_mouse: u16,
+    // Resolution Multiplier feature report last written by the host
+    resolution: AtomicU8,
+    protocol: AtomicU8,
 }
 
 impl HidService {
@@ -216,7 +271,7 @@ impl HidService {
 
         let report_map = service_builder.add_characteristic(
             REPORT_MAP,
-            Attribute::new(CombinedReport::desc()).security(SecurityMode::JustWorks),
+            Attribute::new(COMBINED_DESCRIPTOR).security(SecurityMode::JustWorks),
             Metadata::new(Properties::new().read()),
         )?;
         let report_map_handle = report_map.build();
@@ -230,7 +285,7 @@ impl HidService {
 
         let protocol_mode = service_builder.add_characteristic(
             PROTOCOL_MODE,
-            Attribute::new([1u8]).security(SecurityMode::JustWorks),
+            Attribute::new([REPORT_PROTOCOL]).security(SecurityMode::JustWorks),
             Metadata::new(Properties::new().read().write_without_response()),
         )?;
         let protocol_mode_handle = protocol_mode.build();
@@ -261,7 +316,7 @@ impl HidService {
 
         let mut input_mouse = service_builder.add_characteristic(
             HID_REPORT,
-            Attribute::new([0u8; 5]).security(SecurityMode::JustWorks),
+            Attribute::new([0u8; 7]).security(SecurityMode::JustWorks),
             Metadata::new(Properties::new().read().notify()),
         )?;
 
@@ -272,6 +327,24 @@ impl HidService {
 
         let input_mouse_handle = input_mouse.build();
 
+        let mut feature_mouse = service_builder.add_characteristic(
+            HID_REPORT,
+            Attribute::new([0u8]).security(SecurityMode::JustWorks),
+            Metadata::new(Properties::new().read().write()),
+        )?;
+        feature_mouse.add_descriptor(
+            Uuid::new_16(0x2908),
+            Attribute::new([MOUSE_ID, FEATURE_REPORT]).security(SecurityMode::JustWorks),
+        )?;
+        let feature_mouse_handle = feature_mouse.build();
+
+        let boot_mouse = service_builder.add_characteristic(
+            BOOT_MOUSE_INPUT,
+            Attribute::new([0u8; 3]).security(SecurityMode::JustWorks),
+            Metadata::new(Properties::new().read().notify()),
+        )?;
+        let boot_mouse_handle = boot_mouse.build();
+
         let _service_handle = service_builder.build();
 
         Ok(HidService {
@@ -287,6 +360,10 @@ impl HidService {
             input_mouse: input_mouse_handle.value_handle,
             input_mouse_cccd: input_mouse_handle.cccd_handle,
             intput_mouse_descriptor: input_mouse_desc.handle(),
+            feature_mouse: feature_mouse_handle.value_handle,
+            boot_mouse: boot_mouse_handle.value_handle,
+            resolution: AtomicU8::new(0),
+            protocol: AtomicU8::new(REPORT_PROTOCOL),
         })
     }
 
@@ -296,10 +373,51 @@ impl HidService {
         if handle == self.input_keyboard_cccd {
             info!("HID input keyboard notify: {:?}", data);
         }
+        if handle == self.protocol_mode {
+            match data.first() {
+                Some(protocol) => {
+                    info!("Protocol mode set: {:?}", protocol);
+                    self.protocol.store(*protocol, Ordering::Relaxed);
+                }
+                None => {}
+            }
+        }
+        if handle == self.feature_mouse {
+            match data.first() {
+                Some(feature) => {
+                    info!("Resolution multiplier set: {:?}", feature);
+                    self.resolution.store(*feature, Ordering::Relaxed);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Returns the resolution multipliers the host enabled
+    pub fn resolution(&self) -> ResolutionMultiplier {
+        ResolutionMultiplier::from_feature(self.resolution.load(Ordering::Relaxed))
+    }
+
+    /// Turns high resolution scrolling off and goes back to the report
+    /// protocol until the host changes them again, such as on a new connection
+    pub fn reset(&self, sd: &Softdevice) {
+        self.resolution.store(0, Ordering::Relaxed);
+        self.protocol.store(REPORT_PROTOCOL, Ordering::Relaxed);
+        if let Err(e) = gatt_server::set_value(sd, self.feature_mouse, &[0]) {
+            error!("Failed to reset the resolution multiplier: {:?}", e);
+        }
+        if let Err(e) = gatt_server::set_value(sd, self.protocol_mode, &[REPORT_PROTOCOL]) {
+            error!("Failed to reset the protocol mode: {:?}", e);
+        }
     }
 
-    pub fn mouse_notify(&self, conn: &Connection, data: &[u8]) {
-        match gatt_server::notify_value(&conn, self.input_mouse, data) {
+    /// Sends the report, as an 8 bit boot report if the host is in boot protocol
+    pub fn mouse_notify(&self, conn: &Connection, rep: &MouseReport16) {
+        let result = match self.protocol.load(Ordering::Relaxed) {
+            BOOT_PROTOCOL => gatt_server::notify_value(&conn, self.boot_mouse, &rep.to_boot()),
+            _ => gatt_server::notify_value(&conn, self.input_mouse, &rep.to_bytes()),
+        };
+        match result {
             Ok(_) => {
                 info!("Report Sent!");
             }
@@ -320,10 +438,161 @@ impl HidService {
     }
 }
 
+/// Characteristic of the config service a request was written to
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConfigTarget {
+    Keymap,
+    Settings,
+    Command,
+}
+
+/// Request written to the config service as a command packet. Writes to the
+/// keymap and settings characteristics are translated into commands
+#[derive(Clone, Debug)]
+pub struct ConfigEvent {
+    pub target: ConfigTarget,
+    pub request: Vec<u8, PACKET_SIZE>,
+}
+
+/// Vendor service used to configure the keyboard over BLE. Every attribute needs an
+/// encrypted link and writes from peers that aren't bonded are dropped by BleCentral.
+/// Writing [layer, index] to the keymap selects a key and writing [layer, index, action]
+/// sets it. The settings work the same with [id] and [id, value]. The value of both is
+/// the last selected entry. The command characteristic takes any command packet and
+/// notifies the response
+pub struct ConfigService {
+    keymap: u16,
+    layer: u16,
+    layer_cccd: u16,
+    settings: u16,
+    command: u16,
+    command_cccd: u16,
+}
+
+impl ConfigService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service_builder = ServiceBuilder::new(sd, CONFIG_SERVICE)?;
+
+        let keymap = service_builder.add_characteristic(
+            CONFIG_KEYMAP,
+            Attribute::new([0u8; KEYMAP_VALUE_SIZE])
+                .security(SecurityMode::JustWorks)
+                .variable_len(KEYMAP_VALUE_SIZE as u16),
+            Metadata::new(Properties::new().read().write()),
+        )?;
+        let keymap_handle = keymap.build();
+
+        let layer = service_builder.add_characteristic(
+            CONFIG_LAYER,
+            Attribute::new([0u8]).security(SecurityMode::JustWorks),
+            Metadata::new(Properties::new().read().notify()),
+        )?;
+        let layer_handle = layer.build();
+
+        let settings = service_builder.add_characteristic(
+            CONFIG_SETTINGS,
+            Attribute::new([0u8; SETTINGS_VALUE_SIZE])
+                .security(SecurityMode::JustWorks)
+                .variable_len(SETTINGS_VALUE_SIZE as u16),
+            Metadata::new(Properties::new().read().write()),
+        )?;
+        let settings_handle = settings.build();
+
+        let command = service_builder.add_characteristic(
+            CONFIG_COMMAND,
+            Attribute::new([0u8; PACKET_SIZE])
+                .security(SecurityMode::JustWorks)
+                .variable_len(PACKET_SIZE as u16),
+            Metadata::new(Properties::new().read().write().notify()),
+        )?;
+        let command_handle = command.build();
+
+        let _service_handle = service_builder.build();
+
+        Ok(ConfigService {
+            keymap: keymap_handle.value_handle,
+            layer: layer_handle.value_handle,
+            layer_cccd: layer_handle.cccd_handle,
+            settings: settings_handle.value_handle,
+            command: command_handle.value_handle,
+            command_cccd: command_handle.cccd_handle,
+        })
+    }
+
+    pub fn on_write(&self, handle: u16, data: &[u8]) -> Option<ConfigEvent> {
+        if (handle == self.layer_cccd || handle == self.command_cccd) && !data.is_empty() {
+            info!("config notifications: {}", (data[0] & 0x01) != 0);
+            return None;
+        }
+        let (target, id) = if handle == self.keymap {
+            match data.len() {
+                2 => (ConfigTarget::Keymap, CommandId::GetKey),
+                KEYMAP_VALUE_SIZE => (ConfigTarget::Keymap, CommandId::SetKey),
+                _ => return None,
+            }
+        } else if handle == self.settings {
+            match data.len() {
+                1 => (ConfigTarget::Settings, CommandId::GetSetting),
+                SETTINGS_VALUE_SIZE => (ConfigTarget::Settings, CommandId::SetSetting),
+                _ => return None,
+            }
+        } else if handle == self.command {
+            let request = Vec::from_slice(data).ok()?;
+            return Some(ConfigEvent {
+                target: ConfigTarget::Command,
+                request,
+            });
+        } else {
+            return None;
+        };
+        let mut request = Vec::new();
+        request.push(id as u8).unwrap();
+        request.extend_from_slice(data).unwrap();
+        Some(ConfigEvent { target, request })
+    }
+
+    /// Stores the response to a request. Keymap and settings responses become the value
+    /// of their characteristic and command responses are notified
+    pub fn respond(
+        &self,
+        sd: &Softdevice,
+        conn: &Connection,
+        target: ConfigTarget,
+        response: &[u8],
+    ) {
+        let ok = response.len() >= 2 && response[1] == Status::Ok as u8;
+        if target != ConfigTarget::Command && !ok {
+            error!("Config request failed: {:?}", response);
+            return;
+        }
+        let res = match target {
+            ConfigTarget::Keymap => gatt_server::set_value(sd, self.keymap, &response[2..]),
+            ConfigTarget::Settings => gatt_server::set_value(sd, self.settings, &response[2..]),
+            ConfigTarget::Command => {
+                // Responses stay readable when notifications are off
+                let res = gatt_server::set_value(sd, self.command, response);
+                let _ = gatt_server::notify_value(conn, self.command, response);
+                res
+            }
+        };
+        if let Err(e) = res {
+            error!("{:?}", e);
+        }
+    }
+
+    pub fn layer_notify(&self, sd: &Softdevice, conn: &Connection, layer: u8) {
+        if let Err(e) = gatt_server::set_value(sd, self.layer, &[layer]) {
+            error!("{:?}", e);
+        }
+        let _ = gatt_server::notify_value(conn, self.layer, &[layer]);
+    }
+}
+
 pub struct Server {
     _dis: DeviceInformationService,
     bas: BatteryService,
     hid: HidService,
+    config: ConfigService,
 }
 
 impl Server {
@@ -348,16 +617,19 @@ impl Server {
 
         let hid = HidService::new(sd)?;
 
+        let config = ConfigService::new(sd)?;
+
         Ok(Self {
             _dis: dis,
             bas,
             hid,
+            config,
         })
     }
 }
 
 impl gatt_server::Server for Server {
-    type Event = ();
+    type Event = ConfigEvent;
 
     fn on_write(
         &self,
@@ -369,7 +641,7 @@ impl gatt_server::Server for Server {
     ) -> Option<Self::Event> {
         self.hid.on_write(conn, handle, data);
         self.bas.on_write(handle, data);
-        None
+        self.config.on_write(handle, data)
     }
 }
 
@@ -378,6 +650,7 @@ pub struct BleCentral<'a> {
     conn: Option<Connection>,
     sd: &'a Mutex<CriticalSectionRawMutex, &'static Softdevice>,
     status: Mutex<CriticalSectionRawMutex, bool>,
+    config: Channel<CriticalSectionRawMutex, ConfigEvent, CONFIG_CHANNEL_SIZE>,
 }
 
 impl<'a> BleCentral<'a> {
@@ -390,6 +663,7 @@ impl<'a> BleCentral<'a> {
             conn: None,
             sd,
             status: Mutex::new(false),
+            config: Channel::new(),
         }
     }
     async fn active(&self) -> bool {
@@ -432,31 +706,38 @@ impl<'a> BleCentral<'a> {
         };
 
         let sd_ref = *(self.sd.lock().await);
-        let conn = peripheral::advertise_connectable(sd_ref, adv, &config)
+        let conn = peripheral::advertise_pairable(sd_ref, adv, &config, bonder)
             .await
             .unwrap();
         self.conn = Some(conn);
         self.conn
             .as_mut()
             .unwrap()
-            .set_conn_params(raw::ble_gap_conn_params_t {
-                min_conn_interval: 6,
-                max_conn_interval: 6,
-                slave_latency: 99,
-                conn_sup_timeout: 400,
-            })
+            .set_conn_params(ACTIVE_CONN_PARAMS)
             .unwrap();
     }
 
     /// Runs GATT server. This Funnction must be running conccurrently
-    /// to make other functions work
-    pub async fn connect(&self) {
+    /// to make other functions work. Config requests from peers that
+    /// aren't bonded are dropped
+    pub async fn connect<S: NorFlash>(&self, bonder: &Bonder<'_, S>) {
         if let Some(conn) = &self.conn {
             {
+                let sd_ref = *(self.sd.lock().await);
+                self.server.hid.reset(sd_ref);
                 let mut status = self.status.lock().await;
                 *status = true;
             }
-            gatt_server::run(self.conn.as_ref().unwrap(), self.server, |_| {}).await;
+            gatt_server::run(conn, self.server, |event| {
+                if !bonder.is_bonded(conn) {
+                    error!("Config request from a peer that isn't bonded");
+                    return;
+                }
+                if self.config.try_send(event).is_err() {
+                    error!("Config channel is full");
+                }
+            })
+            .await;
             {
                 let mut status = self.status.lock().await;
                 *status = false;
@@ -464,6 +745,35 @@ impl<'a> BleCentral<'a> {
         }
     }
 
+    /// Requests the connection parameters of the power state from the host.
+    /// Idle and lower states use a higher slave latency
+    pub fn set_power_state(&self, state: PowerState) {
+        let params = match state {
+            PowerState::Active => ACTIVE_CONN_PARAMS,
+            _ => IDLE_CONN_PARAMS,
+        };
+        match &self.conn {
+            Some(conn) => match conn.set_conn_params(params) {
+                Ok(_) => info!("Connection params set for {:?}", state),
+                Err(e) => error!("Failed to set connection params: {:?}", e),
+            },
+            None => {}
+        }
+    }
+
+    /// Disconnects from the host, if connected
+    pub async fn disconnect(&mut self) {
+        match self.conn.take() {
+            Some(conn) => match conn.disconnect() {
+                Ok(_) => info!("Disconnected from host"),
+                Err(_) => error!("Host was already disconnected"),
+            },
+            None => {}
+        }
+        let mut status = self.status.lock().await;
+        *status = false;
+    }
+
     pub async fn keyboard_notify(&self, rep: &KeyboardReport) {
         if self.active().await {
             if let Some(conn) = &self.conn {
@@ -482,21 +792,19 @@ impl<'a> BleCentral<'a> {
         }
     }
 
-    pub async fn mouse_notify(&self, rep: &MouseReport) {
+    pub async fn mouse_notify(&self, rep: &MouseReport16) {
         if self.active().await {
             if let Some(conn) = &self.conn {
-                let buf = [
-                    rep.buttons,
-                    rep.x as u8,
-                    rep.y as u8,
-                    rep.wheel as u8,
-                    rep.pan as u8,
-                ];
-                self.server.hid.mouse_notify(&conn, &buf);
+                self.server.hid.mouse_notify(&conn, rep);
             }
         }
     }
 
+    /// Returns the resolution multipliers the host enabled for scrolling
+    pub fn resolution(&self) -> ResolutionMultiplier {
+        self.server.hid.resolution()
+    }
+
     pub async fn battery_notify(&self, percentage: u8) {
         if self.active().await {
             if let Some(conn) = &self.conn {
@@ -504,4 +812,28 @@ impl<'a> BleCentral<'a> {
             }
         }
     }
+
+    /// Waits for the next request written to the config service
+    pub async fn config_receive(&self) -> ConfigEvent {
+        self.config.receive().await
+    }
+
+    /// Sends the response of a config request back to the peer
+    pub async fn config_respond(&self, target: ConfigTarget, response: &[u8]) {
+        if self.active().await {
+            if let Some(conn) = &self.conn {
+                let sd_ref = *(self.sd.lock().await);
+                self.server.config.respond(sd_ref, conn, target, response);
+            }
+        }
+    }
+
+    pub async fn layer_notify(&self, layer: u8) {
+        if self.active().await {
+            if let Some(conn) = &self.conn {
+                let sd_ref = *(self.sd.lock().await);
+                self.server.config.layer_notify(sd_ref, conn, layer);
+            }
+        }
+    }
 }
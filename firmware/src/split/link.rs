@@ -14,6 +14,8 @@ use nrf_softdevice::{
     Softdevice,
 };
 
+use crate::{bitmap::KeyBitmap, split::HALF_KEYS};
+
 #[nrf_softdevice::gatt_client(uuid = "9e7312e0-2354-11eb-9f10-fbc30a62cf38")]
 struct KeyClient {
     #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf38", read, write, notify)]
@@ -23,13 +25,13 @@ struct KeyClient {
 }
 
 pub struct Link<'a, M: RawMutex, const N: usize> {
-    tx: Sender<'a, M, u32, N>,
+    tx: Sender<'a, M, KeyBitmap<HALF_KEYS>, N>,
     sd: &'a Mutex<CriticalSectionRawMutex, &'static Softdevice>,
 }
 
 impl<'a, M: RawMutex, const N: usize> Link<'a, M, N> {
     pub fn new(
-        tx: Sender<'a, M, u32, N>,
+        tx: Sender<'a, M, KeyBitmap<HALF_KEYS>, N>,
         sd: &'a Mutex<CriticalSectionRawMutex, &'static Softdevice>,
     ) -> Self {
         Self { tx, sd }
@@ -49,10 +51,12 @@ impl<'a, M: RawMutex, const N: usize> Link<'a, M, N> {
         key_client.state_cccd_write(true).await.unwrap();
         key_client.mouse_state_cccd_write(true).await.unwrap();
         let e2 = gatt_client::run(&peer_conn, &key_client, |event| match event {
-            KeyClientEvent::StateNotification(val) => match self.tx.try_send(val) {
-                Ok(_) => {}
-                Err(_) => {}
-            },
+            KeyClientEvent::StateNotification(val) => {
+                match self.tx.try_send(KeyBitmap::from_bits(val as u64)) {
+                    Ok(_) => {}
+                    Err(_) => {}
+                }
+            }
             KeyClientEvent::MouseStateNotification(val) => {
                 let x = ((val & 0xFF00) >> 8) as u8;
                 let y = (val & 0xFF) as u8;
@@ -1,2 +1,6 @@
 pub mod central;
 pub mod link;
+
+/// Keys on each half. The peripheral half sends its keys to the central as a
+/// KeyBitmap of this size, packed into a u32 characteristic
+pub const HALF_KEYS: usize = 18;
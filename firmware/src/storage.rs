@@ -1,11 +1,19 @@
-use core::{marker::PhantomData, ops::Range};
+use core::{cell::RefCell, marker::PhantomData, ops::Range};
 
 use defmt::{error, info};
-use embassy_nrf::pac::ficr::info;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    channel::Channel,
+    mutex::Mutex,
+    signal::Signal,
+};
 use embassy_time::Timer;
-use embedded_storage_async::nor_flash::{NorFlash, NorFlashError};
-use nrf_softdevice::{Flash, FlashError, Softdevice};
+use embedded_storage::nor_flash as blocking;
+use embedded_storage_async::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, ReadNorFlash,
+};
+use heapless::Vec;
 use sequential_storage::{
     cache::NoCache,
     erase_all,
@@ -13,70 +21,107 @@ use sequential_storage::{
 };
 use static_cell::StaticCell;
 
+#[cfg(feature = "nrf")]
 use crate::bond::Peer;
+use crate::{cirque::TrackPadConfig, keymap::KeymapLayer, settings::Settings, via::MacroBuffer};
 
 pub const NRF_FLASH_RANGE: Range<u32> = (160 * 4096)..(163 * 4096);
+// Needs to fit the largest StorageItem. Fetching reads every item before the
+// one searched for into the buffer, so every read of the storage has to use it
+pub const ITEM_BUFFER_SIZE: usize = 256;
+const CHANNEL_SIZE: usize = 16;
+// Keys that can wait in the priority queue at once, one for every bond
+const PRIORITY_SIZE: usize = 8;
 
 pub struct Storage<S: NorFlash, K: Key> {
     flash_range: Range<u32>,
     flash: Mutex<CriticalSectionRawMutex, S>,
-    chan: Channel<CriticalSectionRawMutex, (K, StorageItem), 5>,
+    chan: Channel<CriticalSectionRawMutex, (K, StorageItem), CHANNEL_SIZE>,
+    // Items that can't be dropped, such as bonds. Stored before the channel
+    priority: blocking_mutex::Mutex<
+        CriticalSectionRawMutex,
+        RefCell<Vec<(K, StorageItem), PRIORITY_SIZE>>,
+    >,
+    priority_sent: Signal<CriticalSectionRawMutex, ()>,
     _marker: PhantomData<K>,
 }
 
 #[derive(Debug, Clone)]
 pub enum StorageItem {
+    #[cfg(feature = "nrf")]
     Peer(Peer),
+    Keymap(KeymapLayer),
+    Macros(MacroBuffer),
+    Settings(Settings),
+    TrackPad(TrackPadConfig),
+}
+
+/// Wraps a blocking flash driver so it can be used by Storage. Used by
+/// the builds that don't enable the softdevice, such as with embassy_nrf's Nvmc
+pub struct BlockingFlash<F>(pub F);
+
+impl<F: blocking::ErrorType> ErrorType for BlockingFlash<F> {
+    type Error = F::Error;
+}
+
+impl<F: blocking::ReadNorFlash> ReadNorFlash for BlockingFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl<F: blocking::NorFlash> NorFlash for BlockingFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes)
+    }
 }
 
-impl<S: NorFlash, K: Key> Storage<S, K> {
+impl<F: blocking::MultiwriteNorFlash> MultiwriteNorFlash for BlockingFlash<F> {}
+
+impl<S: NorFlash, K: Key + From<u8>> Storage<S, K> {
     /// Returns Storage Struct. This method will clear
     /// the flash range if not intialized.
     pub async fn init(mut flash: S, flash_range: Range<u32>) -> Self {
         info!("Init Stage");
-        let mut data_buffer = [0; 128];
+        let mut data_buffer = [0; ITEM_BUFFER_SIZE];
         // Check if the key value pair (0x0, 0x69) is in the map
         // If the pair is not in the map, it indicates that the
-        // storage isn't initialized
+        // storage isn't initialized. The key has the type of every other
+        // key, as a shorter key would match the start of theirs
         Timer::after_millis(10).await;
-        match fetch_item::<u8, u32, _>(
+        match fetch_item::<K, u32, _>(
             &mut flash,
             flash_range.clone(),
             &mut NoCache::new(),
             &mut data_buffer,
-            &0x0u8,
+            &K::from(0),
         )
         .await
         {
             Ok(res) => match res {
                 Some(val) => {
                     if val != 0x69 {
-                        erase_all(&mut flash, flash_range.clone()).await;
-                        store_item(
-                            &mut flash,
-                            flash_range.clone(),
-                            &mut NoCache::new(),
-                            &mut data_buffer,
-                            &0x0u8,
-                            &0x69u32,
-                        )
-                        .await;
+                        Self::reset_flash(&mut flash, flash_range.clone()).await;
                         info!("Key Exists, invalid value");
                     } else {
                         info!("Valid Storage");
                     }
                 }
                 None => {
-                    erase_all(&mut flash, flash_range.clone()).await;
-                    store_item(
-                        &mut flash,
-                        flash_range.clone(),
-                        &mut NoCache::new(),
-                        &mut data_buffer,
-                        &0x0u8,
-                        &0x69u32,
-                    )
-                    .await;
+                    Self::reset_flash(&mut flash, flash_range.clone()).await;
                     info!("Key Doesn't exist");
                 }
             },
@@ -88,12 +133,14 @@ impl<S: NorFlash, K: Key> Storage<S, K> {
             flash: Mutex::new(flash),
             flash_range,
             chan: Channel::new(),
+            priority: blocking_mutex::Mutex::new(RefCell::new(Vec::new())),
+            priority_sent: Signal::new(),
             _marker: PhantomData,
         }
     }
 
     pub async fn store_item<'a, V: Value<'a>>(&self, key: K, value: &V) {
-        let mut buffer = [0; 128];
+        let mut buffer = [0; ITEM_BUFFER_SIZE];
         let flash = &mut *(self.flash.lock().await);
         match store_item(
             flash,
@@ -110,30 +157,64 @@ impl<S: NorFlash, K: Key> Storage<S, K> {
         }
     }
 
-    /// Sends item to channel to be stored later. Blocks until message is sent
-    pub fn send_item(&self, key: &K, value: &StorageItem) {
-        loop {
-            info!("Trying to send to channel!");
-            match self.chan.try_send((key.clone(), value.clone())) {
-                Ok(_) => {
-                    info!("Message sent to channel succesfully!");
-                    break;
-                }
-                Err(_) => {
-                    info!("Channel is full!");
+    /// Sends item to channel to be stored later. Waits while the channel is full
+    pub async fn send_item(&self, key: &K, value: &StorageItem) {
+        self.chan.send((key.clone(), value.clone())).await;
+    }
+
+    /// Queues an item that can't be dropped from code that can't await, such as
+    /// the bonds from the SoftDevice security callbacks. The item replaces the
+    /// one queued with the same key, so the queue only fills up with more than
+    /// PRIORITY_SIZE keys. Priority items are stored before the channel
+    pub fn send_priority_item(&self, key: &K, value: &StorageItem) {
+        let queued = self.priority.lock(|priority| {
+            let mut priority = priority.borrow_mut();
+            match priority.iter_mut().find(|(k, _)| k == key) {
+                Some(item) => {
+                    item.1 = value.clone();
+                    Ok(())
                 }
+                None => priority.push((key.clone(), value.clone())),
             }
+        });
+        match queued {
+            Ok(_) => self.priority_sent.signal(()),
+            Err(_) => error!("Storage priority queue is full, item dropped"),
         }
     }
 
+    fn take_priority_item(&self) -> Option<(K, StorageItem)> {
+        self.priority.lock(|priority| {
+            let mut priority = priority.borrow_mut();
+            match priority.is_empty() {
+                true => None,
+                false => Some(priority.remove(0)),
+            }
+        })
+    }
+
+    async fn store(&self, key: K, value: StorageItem) {
+        match value {
+            #[cfg(feature = "nrf")]
+            StorageItem::Peer(peer) => self.store_item(key, &peer).await,
+            StorageItem::Keymap(layer) => self.store_item(key, &layer).await,
+            StorageItem::Macros(macros) => self.store_item(key, &macros).await,
+            StorageItem::Settings(settings) => self.store_item(key, &settings).await,
+            StorageItem::TrackPad(config) => self.store_item(key, &config).await,
+        };
+    }
+
     /// This method allows non-async methods to write to the storage in a async matter with
     /// channels. Method is not needed if all your functions can be run in async
     pub async fn run_storage(&self) {
         loop {
-            let (key, value) = self.chan.receive().await;
-            match value {
-                StorageItem::Peer(peer) => self.store_item(key, &peer).await,
-            };
+            while let Some((key, value)) = self.take_priority_item() {
+                self.store(key, value).await;
+            }
+            match select(self.priority_sent.wait(), self.chan.receive()).await {
+                Either::First(_) => {}
+                Either::Second((key, value)) => self.store(key, value).await,
+            }
         }
     }
 
@@ -153,8 +234,150 @@ impl<S: NorFlash, K: Key> Storage<S, K> {
         }
     }
 
+    /// Erases every stored item. The storage stays initialized, so the next
+    /// boot doesn't erase the items stored after the clear
     pub async fn clear(&self) {
         let flash = &mut *(self.flash.lock().await);
-        erase_all(flash, self.flash_range.clone()).await;
+        Self::reset_flash(flash, self.flash_range.clone()).await;
+    }
+
+    /// Erases the flash range and stores the (0x0, 0x69) pair that marks it as initialized
+    async fn reset_flash(flash: &mut S, flash_range: Range<u32>) {
+        let mut data_buffer = [0; ITEM_BUFFER_SIZE];
+        match erase_all(flash, flash_range.clone()).await {
+            Ok(_) => {}
+            Err(_) => error!("Failed to erase storage"),
+        }
+        match store_item(
+            flash,
+            flash_range,
+            &mut NoCache::new(),
+            &mut data_buffer,
+            &K::from(0),
+            &0x69u32,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(_) => error!("Failed to mark storage as initialized"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use core::convert::Infallible;
+
+    use embassy_futures::{block_on, yield_now};
+
+    use super::*;
+    use crate::keymap::{ki, KeyAction, KeymapLayer, MAX_KEYMAP_KEYS};
+
+    const FLASH_SIZE: usize = 4 * 4096;
+
+    pub(crate) const RAM_FLASH_RANGE: Range<u32> = 0..FLASH_SIZE as u32;
+
+    /// NOR flash in RAM
+    pub(crate) struct RamFlash([u8; FLASH_SIZE]);
+
+    impl RamFlash {
+        pub(crate) fn new() -> Self {
+            Self([0xFF; FLASH_SIZE])
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = Infallible;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 4;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Infallible> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            FLASH_SIZE
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 4096;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Infallible> {
+            self.0[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Infallible> {
+            let offset = offset as usize;
+            for (cell, byte) in self.0[offset..offset + bytes.len()].iter_mut().zip(bytes) {
+                *cell &= *byte;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn items_stored_after_a_clear_survive_a_reboot() {
+        let mut flash = RamFlash::new();
+        block_on(async {
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            storage.clear().await;
+            storage.store_item(1, &Settings::default()).await;
+        });
+        block_on(async {
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+            assert!(storage.get_item::<Settings>(1, &mut buffer).await.is_some());
+        });
+    }
+
+    #[test]
+    fn items_are_found_next_to_a_full_layer() {
+        let mut flash = RamFlash::new();
+        let mut layer = KeymapLayer {
+            layer: 0,
+            actions: heapless::Vec::new(),
+        };
+        for i in 0..MAX_KEYMAP_KEYS {
+            layer.actions.push(KeyAction::Code(i as u8)).unwrap();
+        }
+        block_on(async {
+            // Peers need the softdevice, but any item stored with the layer
+            // is fetched through the same buffer
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            storage.store_item(ki(0), &layer).await;
+            storage.store_item(1, &Settings::default()).await;
+        });
+        block_on(async {
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+            let stored = storage.get_item::<KeymapLayer>(ki(0), &mut buffer).await;
+            assert_eq!(stored.unwrap().actions, layer.actions);
+            assert!(storage.get_item::<Settings>(1, &mut buffer).await.is_some());
+        });
+    }
+
+    #[test]
+    fn priority_items_are_kept_while_the_channel_is_full() {
+        let mut flash = RamFlash::new();
+        block_on(async {
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            let item = StorageItem::Settings(Settings::default());
+            while storage.chan.try_send((2, item.clone())).is_ok() {}
+            storage.send_priority_item(&1, &item);
+            let stored = async {
+                let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+                while storage.get_item::<Settings>(1, &mut buffer).await.is_none() {
+                    yield_now().await;
+                }
+            };
+            select(storage.run_storage(), stored).await;
+        });
     }
 }
@@ -0,0 +1,179 @@
+//! Processing of raw matrix scans that doesn't touch the pins, so it can be
+//! tested on the host
+
+use crate::bitmap::KeyBitmap;
+
+/// Maps each matrix position, indexed by [row][col], to a key index.
+/// None is used for positions without a switch
+#[derive(Copy, Clone, Debug)]
+pub struct Transform<const ROWS: usize, const COLS: usize> {
+    indexes: [[Option<u8>; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Transform<ROWS, COLS> {
+    pub const fn new(indexes: [[Option<u8>; COLS]; ROWS]) -> Self {
+        Self { indexes }
+    }
+
+    /// Returns a transform that numbers the positions row by row
+    pub const fn identity() -> Self {
+        let mut indexes = [[None; COLS]; ROWS];
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                indexes[row][col] = Some((row * COLS + col) as u8);
+                col += 1;
+            }
+            row += 1;
+        }
+        Self { indexes }
+    }
+
+    /// Returns the key index of the matrix position
+    pub fn index(&self, row: usize, col: usize) -> Option<usize> {
+        self.indexes[row][col].map(|index| index as usize)
+    }
+
+    /// Calls f with the key index and state of every mapped position of a scan
+    pub fn for_each_key(&self, states: &[[bool; COLS]; ROWS], mut f: impl FnMut(usize, bool)) {
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                match self.index(row, col) {
+                    Some(index) => f(index, states[row][col]),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    /// Returns the pressed keys of a scan. Indexes past N are dropped
+    pub fn to_bitmap<const N: usize>(&self, states: &[[bool; COLS]; ROWS]) -> KeyBitmap<N> {
+        let mut bitmap = KeyBitmap::new();
+        self.for_each_key(states, |index, state| bitmap.set(index, state));
+        bitmap
+    }
+}
+
+/// Left half. The thumb keys are wired to the last three columns of the
+/// bottom row and map to keys 15 to 17
+pub const LEFT_TRANSFORM: Transform<4, 5> = Transform::new([
+    [Some(0), Some(1), Some(2), Some(3), Some(4)],
+    [Some(5), Some(6), Some(7), Some(8), Some(9)],
+    [Some(10), Some(11), Some(12), Some(13), Some(14)],
+    [None, None, Some(15), Some(16), Some(17)],
+]);
+
+/// Right half, indexed from 0 as sent over the split link. The thumb keys are
+/// wired to the first three columns of the bottom row
+pub const RIGHT_TRANSFORM: Transform<4, 5> = Transform::new([
+    [Some(0), Some(1), Some(2), Some(3), Some(4)],
+    [Some(5), Some(6), Some(7), Some(8), Some(9)],
+    [Some(10), Some(11), Some(12), Some(13), Some(14)],
+    [Some(15), Some(16), Some(17), None, None],
+]);
+
+/// Returns true if the pressed key is a corner of a rectangle of pressed keys.
+/// Without diodes, any three corners of a rectangle make the fourth read as
+/// pressed, so none of them can be trusted
+fn is_ambiguous<const ROWS: usize, const COLS: usize>(
+    states: &[[bool; COLS]; ROWS],
+    row: usize,
+    col: usize,
+) -> bool {
+    if !states[row][col] {
+        return false;
+    }
+    for other_row in 0..ROWS {
+        if other_row == row || !states[other_row][col] {
+            continue;
+        }
+        for other_col in 0..COLS {
+            if other_col != col && states[row][other_col] && states[other_row][other_col] {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Suppresses ghost keys in a scan of a matrix without diodes. Ambiguous keys
+/// keep their state from the previous scan, so keys held before the rectangle
+/// formed stay pressed and the phantom key is never reported
+pub fn suppress_ghosts<const ROWS: usize, const COLS: usize>(
+    states: &mut [[bool; COLS]; ROWS],
+    previous: &[[bool; COLS]; ROWS],
+) {
+    let mut ambiguous = [[false; COLS]; ROWS];
+    for row in 0..ROWS {
+        for col in 0..COLS {
+            ambiguous[row][col] = is_ambiguous(states, row, col);
+        }
+    }
+    for row in 0..ROWS {
+        for col in 0..COLS {
+            if ambiguous[row][col] {
+                states[row][col] = previous[row][col];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::suppress_ghosts;
+
+    const EMPTY: [[bool; 3]; 3] = [[false; 3]; 3];
+
+    #[test]
+    fn keys_without_a_rectangle_are_reported() {
+        let mut states = [[true, true, false], [false, false, true], [false; 3]];
+        let expected = states;
+        suppress_ghosts(&mut states, &EMPTY);
+        assert_eq!(states, expected);
+    }
+
+    #[test]
+    fn phantom_corner_is_suppressed() {
+        let previous = [[true, true, false], [true, false, false], [false; 3]];
+        // Holding the three keys makes [1][1] read as pressed
+        let mut states = [[true, true, false], [true, true, false], [false; 3]];
+        suppress_ghosts(&mut states, &previous);
+        assert_eq!(states, previous);
+    }
+
+    #[test]
+    fn rectangle_from_nothing_is_suppressed() {
+        let mut states = [[false, true, true], [false, true, true], [false; 3]];
+        suppress_ghosts(&mut states, &EMPTY);
+        assert_eq!(states, EMPTY);
+    }
+
+    #[test]
+    fn other_keys_are_kept_while_ghosting() {
+        let previous = [[true, true, false], [true, false, false], [false; 3]];
+        let mut states = [
+            [true, true, false],
+            [true, true, false],
+            [false, false, true],
+        ];
+        suppress_ghosts(&mut states, &previous);
+        assert_eq!(
+            states,
+            [
+                [true, true, false],
+                [true, false, false],
+                [false, false, true]
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_are_reported_once_the_rectangle_breaks() {
+        let previous = [[true, true, false], [true, false, false], [false; 3]];
+        let mut states = [[true, false, false], [true, false, false], [false; 3]];
+        let expected = states;
+        suppress_ghosts(&mut states, &previous);
+        assert_eq!(states, expected);
+    }
+}
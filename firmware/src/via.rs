@@ -0,0 +1,462 @@
+//! VIA raw HID protocol. Keys are addressed by VIA as (row, col) on a
+//! VIA_ROWS x VIA_COLS matrix where the key index is row * VIA_COLS + col.
+//! Macro buffers are stored in flash so they can be edited from VIA, but
+//! aren't played back by the keys yet.
+
+use defmt::info;
+use embassy_time::Instant;
+use embedded_storage_async::nor_flash::NorFlash;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    codes::KeyCodes,
+    config::{MOUSE_POINTER_TIME, SCROLL_TIME},
+    keymap::{save_keymap, save_layer, KeyAction},
+    keys::{Keys, NUM_LAYERS},
+    storage::{Storage, StorageItem, ITEM_BUFFER_SIZE},
+};
+
+pub const VIA_ROWS: usize = 8;
+pub const VIA_COLS: usize = 5;
+pub const MACRO_COUNT: u8 = 4;
+pub const MACRO_BUFFER_SIZE: usize = 128;
+const MACRO_KEY: u32 = 0x200;
+
+const VIA_PROTOCOL_VERSION: u16 = 0x000C;
+const FIRMWARE_VERSION: u32 = 1;
+
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_EEPROM_RESET: u8 = 0x0A;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_UNHANDLED: u8 = 0xFF;
+
+const ID_UPTIME: u8 = 0x01;
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+const ID_SWITCH_MATRIX_STATE: u8 = 0x03;
+const ID_FIRMWARE_VERSION: u8 = 0x04;
+
+// QMK keycodes used by VIA
+const KC_NO: u16 = 0x0000;
+const KC_MS_UP: u16 = 0x00CD;
+const KC_MS_DOWN: u16 = 0x00CE;
+const KC_MS_LEFT: u16 = 0x00CF;
+const KC_MS_RIGHT: u16 = 0x00D0;
+const KC_MS_BTN1: u16 = 0x00D1;
+const KC_MS_BTN2: u16 = 0x00D2;
+const KC_MS_BTN3: u16 = 0x00D3;
+const KC_MS_WH_UP: u16 = 0x00D9;
+const KC_MS_WH_DOWN: u16 = 0x00DA;
+const KC_MS_ACCEL0: u16 = 0x00DD;
+const KC_MS_ACCEL2: u16 = 0x00DF;
+const QK_MODS: u16 = 0x0100;
+const QK_MODS_MAX: u16 = 0x1FFF;
+const QK_TO: u16 = 0x5200;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_DEF_LAYER: u16 = 0x5240;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_KB: u16 = 0x7E00;
+// Keyboard keycodes after the ones used by the layouts of Config keys
+const KB_TRACKPAD_SCROLL: u16 = QK_KB + 0x20;
+
+/// Converts a raw keycode to the matching VIA keycode. Returns KC_NO for
+/// codes VIA doesn't have
+fn to_via_code(code: u8) -> u16 {
+    match code {
+        0x00..=0xA4 | 0xE0..=0xE7 => code as u16,
+        0xE9..=0xF2 => QK_MOMENTARY | (code - KeyCodes::Layer0 as u8) as u16,
+        0xF4 => KC_MS_BTN1,
+        0xF5 => KC_MS_BTN2,
+        0xF6 => KC_MS_BTN3,
+        0xF7 => KC_MS_RIGHT,
+        0xF8 => KC_MS_LEFT,
+        0xF9 => KC_MS_DOWN,
+        0xFA => KC_MS_UP,
+        0xFB => KC_MS_WH_UP,
+        0xFC => KC_MS_WH_DOWN,
+        0xFD => KC_MS_ACCEL0,
+        0xFE => KC_MS_ACCEL2,
+        0xFF => KB_TRACKPAD_SCROLL,
+        _ => KC_NO,
+    }
+}
+
+/// Returns the QMK modifier bit of a raw modifier keycode
+fn mod_bit(code: u8) -> Option<u16> {
+    match code {
+        0xE0..=0xE3 => Some(1 << (code - 0xE0)),
+        0xE4..=0xE7 => Some(0x10 | (1 << (code - 0xE4))),
+        _ => None,
+    }
+}
+
+/// Converts a KeyAction to a VIA keycode. Actions VIA can't show, such as combined keys,
+/// are shown as the code they send by default
+pub fn action_to_via(action: KeyAction) -> u16 {
+    match action {
+        KeyAction::Code(code) => to_via_code(code),
+        KeyAction::Toggle(code) => match code {
+            0xE9..=0xF2 => QK_TO | (code - KeyCodes::Layer0 as u8) as u16,
+            _ => to_via_code(code),
+        },
+        KeyAction::Double(code, modifier) => match (to_via_code(code), mod_bit(modifier)) {
+            (via @ 0x04..=0xA4, Some(bit)) => (bit << 8) | via,
+            (via, _) => via,
+        },
+        KeyAction::Triple(code, mod0, mod1) => {
+            match (to_via_code(code), mod_bit(mod0), mod_bit(mod1)) {
+                (via @ 0x04..=0xA4, Some(bit0), Some(bit1)) => ((bit0 | bit1) << 8) | via,
+                (via, _, _) => via,
+            }
+        }
+        KeyAction::Combined { normal_code, .. } => to_via_code(normal_code),
+        KeyAction::Interval { code, .. } => to_via_code(code),
+        KeyAction::Config(id) => QK_KB + id as u16,
+    }
+}
+
+/// Converts a VIA keycode to a KeyAction. Returns None for keycodes the keys
+/// don't support
+pub fn via_to_action(code: u16) -> Option<KeyAction> {
+    let layer = |base: u16| (code - base) as usize;
+    match code {
+        0x0000..=0x0001 => Some(KeyAction::Code(0)),
+        0x0004..=0x00A4 | 0x00E0..=0x00E7 => Some(KeyAction::Code(code as u8)),
+        KC_MS_BTN1 => Some(KeyAction::Code(KeyCodes::MouseLeftClick as u8)),
+        KC_MS_BTN2 => Some(KeyAction::Code(KeyCodes::MouseRightClick as u8)),
+        KC_MS_BTN3 => Some(KeyAction::Code(KeyCodes::MouseMiddleClick as u8)),
+        KC_MS_ACCEL0 => Some(KeyAction::Code(KeyCodes::MousePrecision as u8)),
+        KC_MS_ACCEL2 => Some(KeyAction::Code(KeyCodes::MouseTurbo as u8)),
+        KB_TRACKPAD_SCROLL => Some(KeyAction::Code(KeyCodes::TrackpadScroll as u8)),
+        KC_MS_UP | KC_MS_DOWN | KC_MS_LEFT | KC_MS_RIGHT => Some(KeyAction::Interval {
+            code: match code {
+                KC_MS_UP => KeyCodes::MouseNegativeY,
+                KC_MS_DOWN => KeyCodes::MousePositiveY,
+                KC_MS_LEFT => KeyCodes::MouseNegativeX,
+                _ => KeyCodes::MousePositiveX,
+            } as u8,
+            delay: MOUSE_POINTER_TIME as u16,
+        }),
+        KC_MS_WH_UP | KC_MS_WH_DOWN => Some(KeyAction::Interval {
+            code: match code {
+                KC_MS_WH_UP => KeyCodes::MouseScrollUp,
+                _ => KeyCodes::MouseScrollDown,
+            } as u8,
+            delay: SCROLL_TIME as u16,
+        }),
+        QK_MODS..=QK_MODS_MAX => {
+            let base = (code & 0xFF) as u8;
+            let mods = (code >> 8) & 0x1F;
+            // Bit 4 selects the right hand modifiers
+            let offset = if mods & 0x10 != 0 { 0xE4 } else { 0xE0 };
+            let mut codes = (0..4u8)
+                .filter(|i| mods & (1 << *i) != 0)
+                .map(|i| offset + i);
+            match (codes.next(), codes.next()) {
+                (Some(mod0), Some(mod1)) => Some(KeyAction::Triple(base, mod0, mod1)),
+                (Some(mod0), None) => Some(KeyAction::Double(base, mod0)),
+                _ => Some(KeyAction::Code(base)),
+            }
+        }
+        _ if (QK_MOMENTARY..QK_MOMENTARY + 0x20).contains(&code)
+            && layer(QK_MOMENTARY) < NUM_LAYERS =>
+        {
+            Some(KeyAction::Code(
+                KeyCodes::Layer0 as u8 + layer(QK_MOMENTARY) as u8,
+            ))
+        }
+        // Toggle layer keys stay on their layer until another toggle key is pressed,
+        // which is closest to TO, DF and TG
+        _ if [QK_TO, QK_DEF_LAYER, QK_TOGGLE_LAYER]
+            .iter()
+            .any(|base| (*base..*base + 0x20).contains(&code) && layer(*base) < NUM_LAYERS) =>
+        {
+            Some(KeyAction::Toggle(
+                KeyCodes::Layer0 as u8 + (code & 0x1F) as u8,
+            ))
+        }
+        _ if (QK_KB..QK_KB + 0x20).contains(&code) => Some(KeyAction::Config((code - QK_KB) as u8)),
+        _ => None,
+    }
+}
+
+/// Macro buffer edited through VIA. Macros are stored back to back,
+/// seperated by a 0 byte
+#[derive(Debug, Clone)]
+pub struct MacroBuffer(pub [u8; MACRO_BUFFER_SIZE]);
+
+impl<'a> Value<'a> for MacroBuffer {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < MACRO_BUFFER_SIZE {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[..MACRO_BUFFER_SIZE].copy_from_slice(&self.0);
+        Ok(MACRO_BUFFER_SIZE)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < MACRO_BUFFER_SIZE {
+            return Err(SerializationError::InvalidFormat);
+        }
+        Ok(Self(buffer[..MACRO_BUFFER_SIZE].try_into().unwrap()))
+    }
+}
+
+pub struct Via<'a, F: NorFlash, const S: usize> {
+    storage: &'a Storage<F, u32>,
+    default: fn(&mut Keys<S>),
+    macros: MacroBuffer,
+    start: Instant,
+}
+
+impl<'a, F: NorFlash, const S: usize> Via<'a, F, S> {
+    /// Returns a Via struct. Loads the macro buffer from storage. The passed
+    /// in default is the keymap loaded when VIA resets the keymap
+    pub async fn new(storage: &'a Storage<F, u32>, default: fn(&mut Keys<S>)) -> Self {
+        let mut buffer = [0u8; ITEM_BUFFER_SIZE];
+        let macros = storage
+            .get_item::<MacroBuffer>(MACRO_KEY, &mut buffer)
+            .await
+            .unwrap_or(MacroBuffer([0u8; MACRO_BUFFER_SIZE]));
+        Self {
+            storage,
+            default,
+            macros,
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the key index of the VIA matrix position if it has a key
+    fn index(row: u8, col: u8) -> Option<usize> {
+        let index = row as usize * VIA_COLS + col as usize;
+        if (col as usize) < VIA_COLS && index < S {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the VIA keycode stored at the offset of the dynamic keymap buffer.
+    /// The buffer is ordered by layer, row and column
+    fn buffer_code(keys: &Keys<S>, pos: usize) -> u16 {
+        let layer = pos / (VIA_ROWS * VIA_COLS);
+        let index = pos % (VIA_ROWS * VIA_COLS);
+        if layer < NUM_LAYERS && index < S {
+            action_to_via(keys.get_action(index, layer))
+        } else {
+            KC_NO
+        }
+    }
+
+    async fn reset_keymap(&self, keys: &mut Keys<S>) {
+        (self.default)(keys);
+        save_keymap(self.storage, keys).await;
+    }
+
+    async fn reset_macros(&mut self) {
+        self.macros = MacroBuffer([0u8; MACRO_BUFFER_SIZE]);
+        self.storage
+            .send_item(&MACRO_KEY, &StorageItem::Macros(self.macros.clone()))
+            .await;
+    }
+
+    /// Handles a VIA command. The response is written back into data and
+    /// should be sent back to the host
+    pub async fn process(&mut self, keys: &mut Keys<S>, data: &mut [u8; 32]) {
+        match data[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                data[1..3].copy_from_slice(&VIA_PROTOCOL_VERSION.to_be_bytes());
+            }
+            ID_GET_KEYBOARD_VALUE => match data[1] {
+                ID_UPTIME => {
+                    let uptime = self.start.elapsed().as_millis() as u32;
+                    data[2..6].copy_from_slice(&uptime.to_be_bytes());
+                }
+                ID_LAYOUT_OPTIONS => {
+                    data[2..6].copy_from_slice(&[0u8; 4]);
+                }
+                ID_SWITCH_MATRIX_STATE => {
+                    // One byte per row as every row fits in 8 columns
+                    for row in 0..VIA_ROWS {
+                        let mut bits = 0u8;
+                        for col in 0..VIA_COLS {
+                            if let Some(index) = Self::index(row as u8, col as u8) {
+                                if keys.get_pressed(index) {
+                                    bits |= 1 << col;
+                                }
+                            }
+                        }
+                        data[2 + row] = bits;
+                    }
+                }
+                ID_FIRMWARE_VERSION => {
+                    data[2..6].copy_from_slice(&FIRMWARE_VERSION.to_be_bytes());
+                }
+                _ => data[0] = ID_UNHANDLED,
+            },
+            ID_SET_KEYBOARD_VALUE => match data[1] {
+                ID_LAYOUT_OPTIONS => {}
+                _ => data[0] = ID_UNHANDLED,
+            },
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+                let code = match (data[1] as usize, Self::index(data[2], data[3])) {
+                    (layer, Some(index)) if layer < NUM_LAYERS => {
+                        action_to_via(keys.get_action(index, layer))
+                    }
+                    _ => KC_NO,
+                };
+                data[4..6].copy_from_slice(&code.to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let code = u16::from_be_bytes([data[4], data[5]]);
+                match (data[1] as usize, Self::index(data[2], data[3])) {
+                    (layer, Some(index)) if layer < NUM_LAYERS => {
+                        match via_to_action(code).filter(|a| a.is_valid::<S>()) {
+                            Some(action) => {
+                                keys.set_action(action, index, layer);
+                                save_layer(self.storage, keys, layer).await;
+                            }
+                            None => info!("Unsupported VIA keycode {:x}", code),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            ID_DYNAMIC_KEYMAP_RESET => {
+                self.reset_keymap(keys).await;
+            }
+            ID_EEPROM_RESET => {
+                self.reset_keymap(keys).await;
+                self.reset_macros().await;
+            }
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => {
+                data[1] = NUM_LAYERS as u8;
+            }
+            ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+                let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+                let size = (data[3] as usize).min(28) & !1;
+                for i in (0..size).step_by(2) {
+                    let code = Self::buffer_code(keys, (offset + i) / 2);
+                    data[4 + i..6 + i].copy_from_slice(&code.to_be_bytes());
+                }
+            }
+            ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+                let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+                let size = (data[3] as usize).min(28) & !1;
+                let mut changed = [false; NUM_LAYERS];
+                for i in (0..size).step_by(2) {
+                    let pos = (offset + i) / 2;
+                    let layer = pos / (VIA_ROWS * VIA_COLS);
+                    let index = pos % (VIA_ROWS * VIA_COLS);
+                    let code = u16::from_be_bytes([data[4 + i], data[5 + i]]);
+                    if layer < NUM_LAYERS && index < S {
+                        if let Some(action) = via_to_action(code).filter(|a| a.is_valid::<S>()) {
+                            keys.set_action(action, index, layer);
+                            changed[layer] = true;
+                        }
+                    }
+                }
+                for (layer, changed) in changed.iter().enumerate() {
+                    if *changed {
+                        save_layer(self.storage, keys, layer).await;
+                    }
+                }
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => {
+                data[1] = MACRO_COUNT;
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                data[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+                let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+                let size = (data[3] as usize).min(28);
+                for i in 0..size {
+                    data[4 + i] = *self.macros.0.get(offset + i).unwrap_or(&0);
+                }
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+                let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+                let size = (data[3] as usize).min(28);
+                for i in 0..size {
+                    if let Some(byte) = self.macros.0.get_mut(offset + i) {
+                        *byte = data[4 + i];
+                    }
+                }
+                self.storage
+                    .send_item(&MACRO_KEY, &StorageItem::Macros(self.macros.clone()))
+                    .await;
+            }
+            ID_DYNAMIC_KEYMAP_MACRO_RESET => {
+                self.reset_macros().await;
+            }
+            _ => data[0] = ID_UNHANDLED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, select::select, yield_now};
+
+    use super::*;
+    use crate::storage::tests::{RamFlash, RAM_FLASH_RANGE};
+
+    #[test]
+    fn trackpad_scroll_round_trips() {
+        let action = KeyAction::Code(KeyCodes::TrackpadScroll as u8);
+        assert_eq!(action_to_via(action), KB_TRACKPAD_SCROLL);
+        assert_eq!(via_to_action(KB_TRACKPAD_SCROLL), Some(action));
+    }
+
+    #[test]
+    fn macros_are_stored_in_flash() {
+        let mut flash = RamFlash::new();
+        block_on(async {
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            let mut keys = Keys::<39>::default();
+            let mut via = Via::new(&storage, |_: &mut Keys<39>| {}).await;
+            let mut data = [0u8; 32];
+            data[..7].copy_from_slice(&[
+                ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER,
+                0,
+                2,
+                3,
+                0x04,
+                0x05,
+                0,
+            ]);
+            via.process(&mut keys, &mut data).await;
+            select(storage.run_storage(), yield_now()).await;
+        });
+        block_on(async {
+            let storage = Storage::<_, u32>::init(&mut flash, RAM_FLASH_RANGE).await;
+            let mut keys = Keys::<39>::default();
+            let mut via = Via::new(&storage, |_: &mut Keys<39>| {}).await;
+            let mut data = [0u8; 32];
+            data[..4].copy_from_slice(&[ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER, 0, 0, 6]);
+            via.process(&mut keys, &mut data).await;
+            assert_eq!(data[4..10], [0, 0, 0x04, 0x05, 0, 0]);
+
+            data[0] = ID_DYNAMIC_KEYMAP_MACRO_RESET;
+            via.process(&mut keys, &mut data).await;
+            data[..4].copy_from_slice(&[ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER, 0, 2, 2]);
+            via.process(&mut keys, &mut data).await;
+            assert_eq!(data[4..6], [0, 0]);
+        });
+    }
+}